@@ -89,8 +89,135 @@ pub mod core {
         let mut console = ui::console::Console::new(publisher.clone(), console_sub);
         let debug_info = ui::debug_info::DebugInfo::new();
 
+        let config = match support::config::Config::load(assets.join("settings.cfg")) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!(target: "DAT205", "{}, using default settings", e);
+                support::config::Config::new()
+            }
+        };
+
+        // Saved sculpting edits only fit the terrain they were made on, so
+        // they decide the seed.
+        let terrain_edits = config.get_str("terrain.edits").and_then(|path| {
+            match rendering::sculpt::TerrainEdits::load(assets.join(path)) {
+                Ok(edits) => Some(edits),
+                Err(e) => {
+                    error!(target: "DAT205", "{}", e);
+                    None
+                }
+            }
+        });
+
         // Create seed for terrain generation.
-        let rand_seed = rand::thread_rng().gen();
+        let terrain_seed = match terrain_edits.as_ref().and_then(|e| e.seed) {
+            Some(seed) => seed,
+            None => config.get("terrain.seed", rand::thread_rng().gen()),
+        };
+
+        let erosion_settings = rendering::erosion::ErosionSettings::from_config(&config);
+
+        let terrain_layers = {
+            let path = assets.join(config.get_str("terrain.layers").unwrap_or("terrain_layers.cfg"));
+            let layer_config = match support::config::Config::load(&path) {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!(target: "DAT205", "{}, using a single terrain layer", e);
+                    support::config::Config::new()
+                }
+            };
+            rendering::splatting::TerrainLayers::from_config(&layer_config, &assets)
+        };
+
+        let terrain_mode = config.get_str("terrain.mode").unwrap_or("heightfield").to_owned();
+
+        let scatter_rules = if terrain_mode == "planet" {
+            // Scattering places objects on the flat heightfield
+            Vec::new()
+        } else {
+            let path = assets.join(config.get_str("terrain.scatter").unwrap_or("scatter.cfg"));
+            match support::config::Config::load(&path) {
+                Ok(c) => rendering::scatter::load_rules(&c, &assets),
+                Err(e) => {
+                    warn!(target: "DAT205", "{}, nothing is scattered", e);
+                    Vec::new()
+                }
+            }
+        };
+
+        let h_scale = config.get("terrain.horizontal_scale",
+                                 rendering::heightmap::DEFAULT_HORIZONTAL_SCALE);
+        let v_scale = config.get("terrain.vertical_scale",
+                                 rendering::heightmap::DEFAULT_VERTICAL_SCALE);
+
+        // Volume terrain is drawn from its own meshes. Everything else that
+        // needs the height of the ground, like lights, scattering and the water
+        // reflection, uses its topmost surface as a heightfield.
+        let volume = if terrain_mode == "volume" {
+            let settings = rendering::volume::VolumeSettings::from_config(&config, terrain_seed);
+            Some(rendering::volume::Volume::generate(settings, h_scale, v_scale, &terrain_layers))
+        } else {
+            None
+        };
+
+        let mut heightmap = {
+            use rendering::heightmap;
+
+            let resolution = match config.get("terrain.resolution",
+                                              heightmap::DEFAULT_RESOLUTION) {
+                r if r < 2 => {
+                    warn!(target: "DAT205",
+                          "terrain.resolution must be at least 2, using {}",
+                          heightmap::DEFAULT_RESOLUTION);
+                    heightmap::DEFAULT_RESOLUTION
+                }
+                r => r,
+            };
+
+            let from_noise = || {
+                heightmap::Heightmap::from_noise(terrain_seed, resolution, h_scale, v_scale)
+            };
+
+            match (volume.as_ref(), config.get_str("terrain.heightmap")) {
+                (Some(volume), _) => volume.surface_heightmap(),
+                (None, Some(path)) => {
+                    match heightmap::Heightmap::from_image(assets.join(path), h_scale, v_scale) {
+                        Ok(hm) => hm,
+                        Err(e) => {
+                            error!(target: "DAT205", "{}", e);
+                            from_noise()
+                        }
+                    }
+                }
+                (None, None) => from_noise(),
+            }
+        };
+
+        // Biomes reshape generated terrain, heightmaps loaded from images are
+        // only colored by them.
+        let biomes = config.get_str("terrain.biomes").and_then(|file| {
+            match support::config::Config::load(assets.join(file)) {
+                Ok(ref c) if c.get("enabled", true) => {
+                    Some(rendering::biome::BiomeMap::from_config(c, &terrain_layers, terrain_seed))
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    error!(target: "DAT205", "{}", e);
+                    None
+                }
+            }
+        });
+
+        if let Some(ref biomes) = biomes {
+            if heightmap.seed().is_some() {
+                biomes.shape(&mut heightmap);
+            }
+        }
+
+        if config.get("erosion.enabled", false) && volume.is_none() {
+            info!(target: "DAT205", "Eroding terrain...");
+            rendering::erosion::erode(&mut heightmap, &erosion_settings);
+        }
 
         let dpi = window.hidpi_factor();
         let mut text_render = ui::text::TextRenderer::new(DEFAULT_WINDOW_WIDTH as f32,
@@ -112,8 +239,36 @@ pub mod core {
                                                           &mut factory,
                                                           DEFAULT_WINDOW_WIDTH as u16,
                                                           DEFAULT_WINDOW_HEIGHT as u16,
-                                                          &rand_seed,
+                                                          heightmap,
+                                                          erosion_settings,
+                                                          terrain_layers,
+                                                          biomes,
+                                                          scatter_rules,
+                                                          &config,
+                                                          &assets,
                                                           main_color.clone());
+
+        if let Some(volume) = volume {
+            deferred_light_sys.set_terrain_chunks(&mut factory, volume.into_chunks());
+        }
+
+        if terrain_mode == "planet" {
+            use rendering::planet::{Planet, PlanetSettings};
+
+            let planet = Planet::new(PlanetSettings::from_config(&config, terrain_seed));
+            let r = planet.radius();
+
+            cam.set_planet_center(Some(Point3::new(0.0, 0.0, 0.0)));
+            cam.look_at(Point3::new(0.0, 0.0, 2.5 * r), Point3::new(0.0, 0.4 * r, 0.0));
+
+            deferred_light_sys.set_planet(&mut factory, planet);
+        }
+
+        if let Some(edits) = terrain_edits {
+            if let Err(e) = deferred_light_sys.apply_terrain_edits(&edits) {
+                error!(target: "DAT205", "{}", e);
+            }
+        }
         //let mut skybox = rendering::skybox::Skybox::new(&mut factory, main_color.clone());
 
         let mut frame_time = support::frame_clock::FrameClock::new();
@@ -140,6 +295,7 @@ pub mod core {
                     ui.handle_event(event);
                 }
                 cam.process_input(&event);
+                deferred_light_sys.process_input(&event);
 
                 // Close window if the escape key or the exit button is pressed
                 match event {
@@ -163,7 +319,8 @@ pub mod core {
                                   &debug_ids,
                                   frame_time.get_fps(),
                                   frame_time.get_last_frame_duration(),
-                                  cam.get_eye());
+                                  cam.get_eye(),
+                                  deferred_light_sys.terrain_seed());
                 // TODO: Move this to a UIRenderable component and use ECS
                 console.update(ui, &console_ids);
             }
@@ -198,7 +355,6 @@ pub mod core {
             encoder.clear(&main_color, colors::DARK_BLUE.into_with_a());
 
             deferred_light_sys.render((frame_time.elapsed() as f32) / 1000.0,
-                                      &rand_seed,
                                       &mut encoder,
                                       &cam);
             text_render.render(&mut encoder, &mut factory);
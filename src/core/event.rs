@@ -1,5 +1,8 @@
 
 use ui::console::ConsoleLogLevel;
+use rendering::sculpt::BrushTool;
+use rendering::clusters::LightingMode;
+use rendering::tonemap::ToneMapOperator;
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub enum EventID {
@@ -28,8 +31,62 @@ pub enum Event {
     DebugShowDiffuseBuffer,
     // Show depth channel
     DebugShowDepthBuffer,
+    // Show roughness and metallic
+    DebugShowMaterialBuffer,
+    // Color the sun light by shadow cascade
+    DebugShowCascades,
     // Turn all debug settings off
     DebugOff,
+    // Save terrain heights as a 16 bit PNG
+    ExportHeightmap(String),
+    // Save terrain normals as a PNG normal map
+    ExportNormalMap(String),
+    // Run hydraulic and thermal erosion, with parameter overrides
+    ErodeTerrain(Vec<(String, f32)>),
+    // Water surface
+    ToggleWater,
+    SetWaterLevel(f32),
+    // Terrain sculpting, None turns the brush off
+    SetBrushTool(Option<BrushTool>),
+    SetBrushRadius(f32),
+    SetBrushStrength(f32),
+    SetBrushFalloff(f32),
+    UndoSculpt,
+    RedoSculpt,
+    SaveTerrainEdits(String),
+    LoadTerrainEdits(String),
+    // Generate new terrain, with a random seed if None
+    RegenerateTerrain(Option<u32>),
+    // Save the terrain and scattered objects as OBJ or glTF
+    ExportObj(String),
+    ExportGltf(String),
+    // Grow a tree from a preset file and save it as OBJ, with seed 1 if None
+    ExportTree(String, String, Option<u32>),
+    // Lay out a road with the mouse, with the configured width if None
+    BeginRoad(Option<f32>),
+    EndRoad,
+    ClearRoads,
+    // Directional sun light, the direction points towards the sun
+    SetSunDirection([f32; 3]),
+    SetSunColor([f32; 3]),
+    SetSunIntensity(f32),
+    // Point lights, drawn as volumes or clustered
+    SetLightingMode(LightingMode),
+    SetLightCount(usize),
+    // Time both lighting modes at a few light counts and log the results
+    BenchmarkLights,
+    // Time drawing the terrain as a mesh against drawing it displaced
+    BenchmarkTerrain,
+    // Glow around bright light, added before tone mapping
+    ToggleBloom,
+    SetBloomIntensity(f32),
+    SetBloomThreshold(f32),
+    // Tone mapping, the exposure is in stops
+    SetToneMapOperator(ToneMapOperator),
+    SetExposure(f32),
+    ToggleAutoExposure,
+    SetExposureKey(f32),
+    SetAdaptationSpeed(f32),
 
     // * --- WindowEvent
     // Resize the window
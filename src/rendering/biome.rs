@@ -0,0 +1,237 @@
+
+use noise::perlin2;
+use noise;
+
+use rendering::heightmap::Heightmap;
+use rendering::splatting::{MAX_LAYERS, TerrainLayers};
+use support::config::Config;
+
+pub const NUM_BIOMES: usize = 5;
+
+/// A biome covers the part of the world where temperature and moisture are
+/// close to its own. Its height profile rescales the generated heights as
+/// `amplitude * h + (offset + roughness * noise) * vertical_scale`.
+#[derive(Debug, Clone)]
+pub struct Biome {
+    pub name: String,
+    pub temperature: f32,
+    pub moisture: f32,
+    pub amplitude: f32,
+    pub offset: f32,
+    pub roughness: f32,
+    pub vegetation: f32,
+    vegetation_rules: Vec<(String, f32)>,
+    palette: [Option<[f32; 3]>; MAX_LAYERS],
+}
+
+impl Biome {
+    fn new(name: &str,
+           temperature: f32,
+           moisture: f32,
+           amplitude: f32,
+           offset: f32,
+           roughness: f32,
+           vegetation: f32)
+           -> Biome {
+        Biome {
+            name: name.to_owned(),
+            temperature: temperature,
+            moisture: moisture,
+            amplitude: amplitude,
+            offset: offset,
+            roughness: roughness,
+            vegetation: vegetation,
+            vegetation_rules: Vec::new(),
+            palette: [None; MAX_LAYERS],
+        }
+    }
+
+    /// How densely objects of a scatter rule grow in this biome.
+    pub fn vegetation_density(&self, rule: &str) -> f32 {
+        self.vegetation_rules
+            .iter()
+            .find(|&&(ref name, _)| name == rule)
+            .map(|&(_, d)| d)
+            .unwrap_or(self.vegetation)
+    }
+}
+
+/// Temperature and moisture noise fields over the terrain, and the biomes
+/// they select.
+pub struct BiomeMap {
+    biomes: Vec<Biome>,
+    seed: Option<u32>,
+    scale: f32,
+    blend: f32,
+    temperature: noise::PermutationTable,
+    moisture: noise::PermutationTable,
+    detail: noise::PermutationTable,
+}
+
+impl BiomeMap {
+    /// Read the biome settings from a config, falling back to built in
+    /// defaults for anything not set. Every biome key is prefixed with the
+    /// biome name. Palette colors are set per terrain layer, as
+    /// `<biome>.<layer> = r g b`, and replace the color of that layer inside
+    /// the biome.
+    pub fn from_config(config: &Config, layers: &TerrainLayers, default_seed: u32) -> BiomeMap {
+        let defaults = [Biome::new("desert", 0.85, 0.15, 0.35, 0.05, 0.01, 0.1),
+                        Biome::new("grassland", 0.6, 0.45, 0.6, 0.0, 0.02, 0.7),
+                        Biome::new("forest", 0.45, 0.8, 0.9, 0.05, 0.04, 1.0),
+                        Biome::new("tundra", 0.25, 0.35, 0.7, 0.1, 0.03, 0.3),
+                        Biome::new("snow", 0.1, 0.6, 1.3, 0.2, 0.05, 0.05)];
+
+        let biomes = defaults.iter()
+            .map(|d| {
+                let key = |k: &str| format!("{}.{}", d.name, k);
+
+                let mut palette = [None; MAX_LAYERS];
+                for (i, layer) in layers.layers().iter().enumerate() {
+                    palette[i] = match config.get_floats(&key(&layer.name)) {
+                        Some(ref c) if c.len() == 3 => Some([c[0], c[1], c[2]]),
+                        Some(_) => {
+                            warn!(target: "DAT205", "Expected a color for {}", key(&layer.name));
+                            None
+                        }
+                        None => None,
+                    };
+                }
+
+                // Per rule overrides, e.g. `desert.vegetation.trees = 0`
+                let prefix = key("vegetation.");
+                let vegetation_rules = config.keys()
+                    .filter(|k| k.starts_with(&prefix))
+                    .map(|k| (k[prefix.len()..].to_owned(), config.get(k, d.vegetation)))
+                    .collect();
+
+                Biome {
+                    name: d.name.clone(),
+                    temperature: config.get(&key("temperature"), d.temperature),
+                    moisture: config.get(&key("moisture"), d.moisture),
+                    amplitude: config.get(&key("amplitude"), d.amplitude),
+                    offset: config.get(&key("offset"), d.offset),
+                    roughness: config.get(&key("roughness"), d.roughness),
+                    vegetation: config.get(&key("vegetation"), d.vegetation),
+                    vegetation_rules: vegetation_rules,
+                    palette: palette,
+                }
+            })
+            .collect();
+
+        let seed = config.get_str("seed").map(|_| config.get("seed", default_seed));
+
+        let mut map = BiomeMap {
+            biomes: biomes,
+            seed: seed,
+            scale: config.get("scale", 0.008),
+            blend: config.get("blend", 0.12),
+            temperature: noise::PermutationTable::new(0),
+            moisture: noise::PermutationTable::new(0),
+            detail: noise::PermutationTable::new(0),
+        };
+        map.reseed(default_seed);
+        map
+    }
+
+    /// Follow a new terrain seed, unless the config sets a seed of its own.
+    pub fn reseed(&mut self, terrain_seed: u32) {
+        let seed = self.seed.unwrap_or(terrain_seed);
+        self.temperature = noise::PermutationTable::new(seed);
+        self.moisture = noise::PermutationTable::new(seed.wrapping_add(1));
+        self.detail = noise::PermutationTable::new(seed.wrapping_add(2));
+    }
+
+    /// Temperature and moisture in [0, 1] at a world space position.
+    pub fn climate(&self, x: f32, z: f32) -> (f32, f32) {
+        let p = [self.scale * x, self.scale * z];
+        let t = 0.5 + 0.5 * perlin2(&self.temperature, &p);
+        let m = 0.5 + 0.5 * perlin2(&self.moisture, &p);
+        (t.max(0.0).min(1.0), m.max(0.0).min(1.0))
+    }
+
+    /// Blend weights of every biome at a world space position. Each biome
+    /// falls off smoothly with the distance to its climate, so borders fade
+    /// over roughly `blend` in temperature and moisture.
+    pub fn weights(&self, x: f32, z: f32) -> [f32; NUM_BIOMES] {
+        let (t, m) = self.climate(x, z);
+        let sigma_sq = 2.0 * self.blend * self.blend;
+
+        let mut weights = [0.0; NUM_BIOMES];
+        let mut total = 0.0;
+        for (w, b) in weights.iter_mut().zip(self.biomes.iter()) {
+            let d_sq = (t - b.temperature) * (t - b.temperature) +
+                       (m - b.moisture) * (m - b.moisture);
+            *w = (-d_sq / sigma_sq).exp();
+            total += *w;
+        }
+
+        if total > 1.0e-6 {
+            for w in weights.iter_mut() {
+                *w /= total;
+            }
+        } else {
+            // Far away from every biome the exponentials underflow, use the
+            // closest one
+            let closest = self.biomes
+                .iter()
+                .enumerate()
+                .map(|(i, b)| {
+                    (i, (t - b.temperature) * (t - b.temperature) + (m - b.moisture) * (m - b.moisture))
+                })
+                .fold((0, ::std::f32::MAX), |a, b| if b.1 < a.1 { b } else { a })
+                .0;
+            weights[closest] = 1.0;
+        }
+
+        weights
+    }
+
+    /// Apply the blended height profiles of the biomes to a heightmap.
+    pub fn shape(&self, hm: &mut Heightmap) {
+        let v = hm.vertical_scale();
+
+        for iz in 0..hm.depth() {
+            for ix in 0..hm.width() {
+                let p = hm.position(ix, iz);
+                let weights = self.weights(p[0], p[2]);
+                let detail = perlin2(&self.detail, &[0.05 * p[0], 0.05 * p[2]]);
+
+                let mut h = 0.0;
+                for (w, b) in weights.iter().zip(self.biomes.iter()) {
+                    h += w * (b.amplitude * p[1] + (b.offset + b.roughness * detail) * v);
+                }
+                hm.set_height(ix, iz, h);
+            }
+        }
+    }
+
+    /// Terrain color for a set of layer weights, with the layer colors taken
+    /// from the palettes of the biomes at the position.
+    pub fn color(&self,
+                 x: f32,
+                 z: f32,
+                 layers: &TerrainLayers,
+                 layer_weights: &[f32; 4])
+                 -> [f32; 3] {
+        let biome_weights = self.weights(x, z);
+
+        let mut c = [0.0; 3];
+        for (i, (layer, lw)) in layers.layers().iter().zip(layer_weights.iter()).enumerate() {
+            for (b, bw) in self.biomes.iter().zip(biome_weights.iter()) {
+                let color = b.palette[i].unwrap_or(layer.color);
+                for k in 0..3 {
+                    c[k] += lw * bw * color[k];
+                }
+            }
+        }
+        c
+    }
+
+    /// Blended vegetation density of a scatter rule at a world space position.
+    pub fn vegetation_density(&self, x: f32, z: f32, rule: &str) -> f32 {
+        self.weights(x, z)
+            .iter()
+            .zip(self.biomes.iter())
+            .fold(0.0, |d, (w, b)| d + w * b.vegetation_density(rule))
+    }
+}
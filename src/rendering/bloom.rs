@@ -0,0 +1,300 @@
+use gfx;
+use gfx::{Bundle, format, texture};
+use gfx::traits::FactoryExt;
+
+use rendering::shadows::insert_after_version;
+use rendering::tonemap::{ScreenVertex, SCREEN_TRIANGLE, SCREEN_VERTEX_SHADER};
+use support::config::Config;
+
+type BloomFormat = (format::R16_G16_B16_A16, format::Float);
+
+gfx_defines!{
+    constant PrefilterLocals {
+        // Threshold, knee, unused, unused
+        curve: [f32; 4] = "u_Curve",
+    }
+
+    pipeline prefilter {
+        vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+        locals: gfx::ConstantBuffer<PrefilterLocals> = "PrefilterLocals",
+        tex_source: gfx::TextureSampler<[f32; 4]> = "t_Source",
+        out: gfx::RenderTarget<BloomFormat> = "Target0",
+    }
+
+    pipeline downsample {
+        vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+        tex_source: gfx::TextureSampler<[f32; 4]> = "t_Source",
+        out: gfx::RenderTarget<BloomFormat> = "Target0",
+    }
+
+    pipeline upsample {
+        vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+        tex_source: gfx::TextureSampler<[f32; 4]> = "t_Source",
+        out: gfx::BlendTarget<BloomFormat> =
+            ("Target0", gfx::state::MASK_ALL, gfx::preset::blend::ADD),
+    }
+}
+
+// Four bilinear taps on the corners of the texel, which averages the 4x4
+// texels around it
+const BOX_GLSL: &'static [u8] = b"
+    uniform sampler2D t_Source;
+
+    vec3 box(vec2 uv) {
+        vec2 texel = 1.0 / vec2(textureSize(t_Source, 0));
+        vec4 d = texel.xyxy * vec4(-1.0, -1.0, 1.0, 1.0);
+        return 0.25 * (texture(t_Source, uv + d.xy).rgb + texture(t_Source, uv + d.zy).rgb +
+                       texture(t_Source, uv + d.xw).rgb + texture(t_Source, uv + d.zw).rgb);
+    }
+";
+
+// Keeps what is brighter than the threshold. The knee blends it in
+// quadratically around the threshold instead of cutting it off hard.
+const PREFILTER_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform PrefilterLocals {
+        vec4 u_Curve;
+    };
+
+    in vec2 v_TexCoord;
+
+    out vec4 Target0;
+
+    void main() {
+        vec3 color = box(v_TexCoord);
+
+        float threshold = u_Curve.x;
+        float knee = u_Curve.y;
+        float brightness = max(color.r, max(color.g, color.b));
+        float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+        soft = soft * soft / (4.0 * knee + 1.0e-4);
+        float weight = max(soft, brightness - threshold) / max(brightness, 1.0e-4);
+
+        Target0 = vec4(color * weight, 1.0);
+    }
+";
+
+const DOWNSAMPLE_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    in vec2 v_TexCoord;
+
+    out vec4 Target0;
+
+    void main() {
+        Target0 = vec4(box(v_TexCoord), 1.0);
+    }
+";
+
+// 3x3 tent filter over the smaller level, added to the larger one
+const UPSAMPLE_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    uniform sampler2D t_Source;
+
+    in vec2 v_TexCoord;
+
+    out vec4 Target0;
+
+    void main() {
+        vec2 uv = v_TexCoord;
+        vec2 texel = 1.0 / vec2(textureSize(t_Source, 0));
+        vec4 d = texel.xyxy * vec4(1.0, 1.0, -1.0, 0.0);
+
+        vec3 sum = texture(t_Source, uv - d.xy).rgb;
+        sum += 2.0 * texture(t_Source, uv - d.wy).rgb;
+        sum += texture(t_Source, uv - d.zy).rgb;
+        sum += 2.0 * texture(t_Source, uv + d.zw).rgb;
+        sum += 4.0 * texture(t_Source, uv).rgb;
+        sum += 2.0 * texture(t_Source, uv + d.xw).rgb;
+        sum += texture(t_Source, uv + d.zy).rgb;
+        sum += 2.0 * texture(t_Source, uv + d.wy).rgb;
+        sum += texture(t_Source, uv + d.xy).rgb;
+
+        Target0 = vec4(sum / 16.0, 1.0);
+    }
+";
+
+#[derive(Debug, Clone)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    // Light brighter than this blooms, the knee softens the cut around it
+    pub threshold: f32,
+    pub knee: f32,
+    // How much of the blurred light is added to the scene
+    pub intensity: f32,
+    // Halvings of the blur chain, more spread the glow wider
+    pub levels: usize,
+}
+
+impl BloomSettings {
+    pub fn from_config(config: &Config) -> BloomSettings {
+        BloomSettings {
+            enabled: config.get("bloom.enabled", true),
+            threshold: config.get::<f32>("bloom.threshold", 1.0).max(0.0),
+            knee: config.get::<f32>("bloom.knee", 0.5).max(0.0),
+            intensity: config.get::<f32>("bloom.intensity", 0.05).max(0.0),
+            levels: config.get::<usize>("bloom.levels", 6).max(1),
+        }
+    }
+}
+
+/// Glow around bright light. The bright parts of the scene are blurred by
+/// halving them down a chain of textures and adding each level back onto
+/// the one above on the way up, which ends at half the screen size.
+pub struct Bloom<R: gfx::Resources> {
+    settings: BloomSettings,
+    prefilter: Bundle<R, prefilter::Data<R>>,
+    downsample: Vec<Bundle<R, downsample::Data<R>>>,
+    // From the smallest level up
+    upsample: Vec<Bundle<R, upsample::Data<R>>>,
+    output: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+}
+
+impl<R: gfx::Resources> Bloom<R> {
+    /// Builds the chain of blur textures, at most `settings.levels` of them,
+    /// starting at half the target size. `hdr` is the scene the bright parts
+    /// are taken from, `render` can switch it later.
+    pub fn new<F: gfx::Factory<R>>(factory: &mut F,
+                                   settings: BloomSettings,
+                                   target_width: u16,
+                                   target_height: u16,
+                                   hdr: gfx::handle::ShaderResourceView<R, [f32; 4]>)
+                                   -> Self {
+        info!(target: "DAT205", "Loading bloom...");
+
+        let (vbuf, slice) = factory.create_vertex_buffer_with_slice(&SCREEN_TRIANGLE, ());
+
+        let sampler = factory.create_sampler(
+            texture::SamplerInfo::new(texture::FilterMethod::Bilinear, texture::WrapMode::Clamp));
+
+        // There is always a first level, even for a target of a pixel or two
+        let mut levels = Vec::new();
+        let (mut w, mut h) = ((target_width / 2).max(1), (target_height / 2).max(1));
+        loop {
+            let (_, srv, rtv) = factory.create_render_target::<BloomFormat>(w, h).unwrap();
+            levels.push((srv, rtv));
+            if levels.len() >= settings.levels || w < 2 || h < 2 {
+                break;
+            }
+            w /= 2;
+            h /= 2;
+        }
+
+        let prefilter = {
+            let pso = factory.create_pipeline_simple(SCREEN_VERTEX_SHADER,
+                                        &insert_after_version(PREFILTER_FRAGMENT_SHADER,
+                                                              BOX_GLSL),
+                                        prefilter::new())
+                .unwrap();
+
+            let data = prefilter::Data {
+                vbuf: vbuf.clone(),
+                locals: factory.create_constant_buffer(1),
+                tex_source: (hdr, sampler.clone()),
+                out: levels[0].1.clone(),
+            };
+
+            Bundle::new(slice.clone(), pso, data)
+        };
+
+        let downsample_pso = factory.create_pipeline_simple(SCREEN_VERTEX_SHADER,
+                                    &insert_after_version(DOWNSAMPLE_FRAGMENT_SHADER,
+                                                          BOX_GLSL),
+                                    downsample::new())
+            .unwrap();
+
+        let downsample = levels.windows(2)
+            .map(|pair| {
+                let data = downsample::Data {
+                    vbuf: vbuf.clone(),
+                    tex_source: (pair[0].0.clone(), sampler.clone()),
+                    out: pair[1].1.clone(),
+                };
+                Bundle::new(slice.clone(), downsample_pso.clone(), data)
+            })
+            .collect();
+
+        let upsample_pso = factory.create_pipeline_simple(SCREEN_VERTEX_SHADER,
+                                    UPSAMPLE_FRAGMENT_SHADER,
+                                    upsample::new())
+            .unwrap();
+
+        let upsample = levels.windows(2)
+            .rev()
+            .map(|pair| {
+                let data = upsample::Data {
+                    vbuf: vbuf.clone(),
+                    tex_source: (pair[1].0.clone(), sampler.clone()),
+                    out: pair[0].1.clone(),
+                };
+                Bundle::new(slice.clone(), upsample_pso.clone(), data)
+            })
+            .collect();
+
+        info!(target: "DAT205", "Done!");
+
+        Bloom {
+            settings: settings,
+            prefilter: prefilter,
+            downsample: downsample,
+            upsample: upsample,
+            output: levels[0].0.clone(),
+        }
+    }
+
+    pub fn settings(&self) -> &BloomSettings {
+        &self.settings
+    }
+
+    pub fn toggle(&mut self) {
+        self.settings.enabled = !self.settings.enabled;
+    }
+
+    pub fn set_intensity(&mut self, intensity: f32) {
+        self.settings.intensity = intensity.max(0.0);
+    }
+
+    pub fn set_threshold(&mut self, threshold: f32) {
+        self.settings.threshold = threshold.max(0.0);
+    }
+
+    /// How much of the output to add to the scene, zero while disabled.
+    pub fn strength(&self) -> f32 {
+        if self.settings.enabled {
+            self.settings.intensity
+        } else {
+            0.0
+        }
+    }
+
+    /// The blurred bright light of the last `render`, at half the size of
+    /// the screen.
+    pub fn output(&self) -> &gfx::handle::ShaderResourceView<R, [f32; 4]> {
+        &self.output
+    }
+
+    pub fn render<C: gfx::CommandBuffer<R>>(&mut self,
+                                            encoder: &mut gfx::Encoder<R, C>,
+                                            hdr: &gfx::handle::ShaderResourceView<R, [f32; 4]>) {
+        if !self.settings.enabled {
+            return;
+        }
+
+        self.prefilter.data.tex_source.0 = hdr.clone();
+        let locals = PrefilterLocals {
+            curve: [self.settings.threshold, self.settings.knee, 0.0, 0.0],
+        };
+        encoder.update_constant_buffer(&self.prefilter.data.locals, &locals);
+        self.prefilter.encode(encoder);
+
+        for pass in &self.downsample {
+            pass.encode(encoder);
+        }
+        for pass in &self.upsample {
+            pass.encode(encoder);
+        }
+    }
+}
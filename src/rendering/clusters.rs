@@ -0,0 +1,461 @@
+
+use std::time::Instant;
+
+use gfx;
+use gfx::{format, texture};
+use na::{Matrix4, Vector4};
+
+use rendering::camera::Camera;
+use rendering::deferred::LightInfo;
+use rendering::displacement::create_texture;
+use rendering::shadows::insert_after_version;
+use support::benchmark::{FrameBenchmark, MEASURED_FRAMES};
+use support::config::Config;
+
+/// Most point lights that can be drawn.
+pub const MAX_LIGHTS: usize = 4096;
+
+// Width of the light and index textures, the widest GL 3.2 guarantees.
+// Longer data wraps around into more rows.
+const TEXTURE_WIDTH: usize = 1024;
+
+// Reads a row of a light from `t_LightData`, see `with_light_data`
+const LIGHT_DATA_GLSL: &'static [u8] = b"
+    uniform sampler2D t_LightData;
+
+    const int LIGHT_DATA_WIDTH = 1024;
+
+    vec4 light_data(int light, int row) {
+        ivec2 texel = ivec2(light % LIGHT_DATA_WIDTH, 3 * (light / LIGHT_DATA_WIDTH) + row);
+        return texelFetch(t_LightData, texel, 0);
+    }
+";
+
+/// Add `light_data(light, row)` to a shader, right after its version line.
+/// It reads one row of a light from `t_LightData`, see `LightClusters`.
+pub fn with_light_data(shader: &[u8]) -> Vec<u8> {
+    insert_after_version(shader, LIGHT_DATA_GLSL)
+}
+
+type LightSurface = format::R32_G32_B32_A32;
+type LightFormat = (format::R32_G32_B32_A32, format::Float);
+type IndexSurface = format::R32;
+type IndexFormat = (format::R32, format::Float);
+
+gfx_defines!{
+    constant ClusterLocals {
+        view: [[f32; 4]; 4] = "u_View",
+        // Tiles across, tiles up, depth slices, unused
+        grid: [f32; 4] = "u_ClusterGrid",
+        // Near end of the first slice, slices per log unit of depth,
+        // viewport size
+        depth: [f32; 4] = "u_ClusterDepth",
+    }
+}
+
+/// How the point lights are applied to the G-buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightingMode {
+    // A blended sphere per light
+    Volumes,
+    // Lights sorted into cells of the view frustum on the CPU, every pixel
+    // is shaded once with the lights of its cell
+    Clustered,
+}
+
+impl LightingMode {
+    pub fn from_name(name: &str) -> Option<LightingMode> {
+        match name {
+            "volumes" => Some(LightingMode::Volumes),
+            "clustered" => Some(LightingMode::Clustered),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LightingMode::Volumes => "volumes",
+            LightingMode::Clustered => "clustered",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClusterSettings {
+    pub mode: LightingMode,
+    pub tiles_x: usize,
+    pub tiles_y: usize,
+    pub slices: usize,
+    // Slices are spaced exponentially between these. Everything closer than
+    // `near` is in the first slice and everything past `far` in the last.
+    pub near: f32,
+    pub far: f32,
+    // Room for light indices summed over all cells
+    pub max_indices: usize,
+}
+
+impl ClusterSettings {
+    pub fn from_config(config: &Config) -> ClusterSettings {
+        let mode = match config.get_str("lighting.mode") {
+            Some(name) => {
+                LightingMode::from_name(name).unwrap_or_else(|| {
+                    warn!(target: "DAT205", "Unknown lighting mode {}", name);
+                    LightingMode::Clustered
+                })
+            }
+            None => LightingMode::Clustered,
+        };
+
+        ClusterSettings {
+            mode: mode,
+            // The tiles of a slice are one row of a texture
+            tiles_x: config.get::<usize>("cluster.tiles_x", 16).max(1).min(32),
+            tiles_y: config.get::<usize>("cluster.tiles_y", 9).max(1).min(32),
+            slices: config.get::<usize>("cluster.slices", 24).max(1).min(TEXTURE_WIDTH),
+            near: config.get::<f32>("cluster.near", 1.0).max(1.0e-3),
+            far: config.get("cluster.far", 1000.0),
+            max_indices: config.get::<usize>("cluster.max_indices", 262144)
+                .max(1)
+                .min(TEXTURE_WIDTH * TEXTURE_WIDTH),
+        }
+    }
+
+    fn cells(&self) -> usize {
+        self.tiles_x * self.tiles_y * self.slices
+    }
+}
+
+/// The point lights as textures for the lighting shaders, and the cluster
+/// grid that sorts them by the cells of the view frustum they touch.
+///
+/// Lights are columns of `t_LightData`, with position and radius in row 0,
+/// color and intensity in row 1 and the falloff and shadow slot in row 2.
+/// Every 1024 lights start a new block of three rows, which shaders read
+/// with `with_light_data`. `t_Clusters` holds the offset and count of each
+/// cell in `t_LightIndices`, with the tiles of a slice along x and the
+/// slices along y. The indices wrap around every 1024 texels.
+pub struct LightClusters<R: gfx::Resources> {
+    settings: ClusterSettings,
+    light_data: gfx::handle::Texture<R, LightSurface>,
+    light_view: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+    cells: gfx::handle::Texture<R, LightSurface>,
+    cell_view: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+    indices: gfx::handle::Texture<R, IndexSurface>,
+    index_view: gfx::handle::ShaderResourceView<R, f32>,
+    sampler: gfx::handle::Sampler<R>,
+    // Kept between frames to save on allocations
+    cell_lights: Vec<Vec<u32>>,
+    cell_data: Vec<[f32; 4]>,
+    index_data: Vec<f32>,
+    light_rows: Vec<[f32; 4]>,
+    overflow: bool,
+    assign_ms: f32,
+}
+
+impl<R: gfx::Resources> LightClusters<R> {
+    pub fn new<F: gfx::Factory<R>>(factory: &mut F, settings: ClusterSettings) -> Self {
+        let tiles = settings.tiles_x * settings.tiles_y;
+        let rows = (settings.max_indices + TEXTURE_WIDTH - 1) / TEXTURE_WIDTH;
+        let light_rows = 3 * light_blocks(MAX_LIGHTS);
+        let (light_data, light_view) =
+            create_texture::<LightFormat, _, _>(factory, TEXTURE_WIDTH, light_rows);
+        let (cells, cell_view) =
+            create_texture::<LightFormat, _, _>(factory, tiles, settings.slices);
+        let (indices, index_view) =
+            create_texture::<IndexFormat, _, _>(factory, TEXTURE_WIDTH, rows);
+
+        // Only read with texelFetch
+        let sampler = factory.create_sampler(texture::SamplerInfo::new(texture::FilterMethod::Scale,
+                                                      texture::WrapMode::Clamp));
+
+        LightClusters {
+            cell_lights: vec![Vec::new(); settings.cells()],
+            cell_data: vec![[0.0; 4]; settings.cells()],
+            index_data: Vec::with_capacity(rows * TEXTURE_WIDTH),
+            light_rows: Vec::with_capacity(3 * TEXTURE_WIDTH * light_blocks(MAX_LIGHTS)),
+            settings: settings,
+            light_data: light_data,
+            light_view: light_view,
+            cells: cells,
+            cell_view: cell_view,
+            indices: indices,
+            index_view: index_view,
+            sampler: sampler,
+            overflow: false,
+            assign_ms: 0.0,
+        }
+    }
+
+    pub fn mode(&self) -> LightingMode {
+        self.settings.mode
+    }
+
+    pub fn set_mode(&mut self, mode: LightingMode) {
+        self.settings.mode = mode;
+    }
+
+    pub fn light_data(&self)
+                      -> (gfx::handle::ShaderResourceView<R, [f32; 4]>, gfx::handle::Sampler<R>) {
+        (self.light_view.clone(), self.sampler.clone())
+    }
+
+    pub fn clusters(&self)
+                    -> (gfx::handle::ShaderResourceView<R, [f32; 4]>, gfx::handle::Sampler<R>) {
+        (self.cell_view.clone(), self.sampler.clone())
+    }
+
+    pub fn indices(&self) -> (gfx::handle::ShaderResourceView<R, f32>, gfx::handle::Sampler<R>) {
+        (self.index_view.clone(), self.sampler.clone())
+    }
+
+    /// Milliseconds the last `assign` spent sorting lights into cells.
+    pub fn assign_time(&self) -> f32 {
+        self.assign_ms
+    }
+
+    /// Upload the lights, at most `MAX_LIGHTS` of them.
+    pub fn update_lights<C: gfx::CommandBuffer<R>>(&mut self,
+                                                   encoder: &mut gfx::Encoder<R, C>,
+                                                   lights: &[LightInfo]) {
+        let lights = &lights[..lights.len().min(MAX_LIGHTS)];
+        if lights.is_empty() {
+            return;
+        }
+
+        // Whole blocks only, the last one padded
+        self.light_rows.clear();
+        for block in lights.chunks(TEXTURE_WIDTH) {
+            let pad = TEXTURE_WIDTH - block.len();
+            self.light_rows.extend(block.iter().map(|l| l.pos));
+            self.light_rows.extend((0..pad).map(|_| [0.0; 4]));
+            self.light_rows.extend(block.iter().map(|l| l.color));
+            self.light_rows.extend((0..pad).map(|_| [0.0; 4]));
+            self.light_rows.extend(block.iter().map(|l| l.falloff));
+            self.light_rows.extend((0..pad).map(|_| [0.0; 4]));
+        }
+
+        let data = gfx::memory::cast_slice(&self.light_rows);
+        let rows = 3 * light_blocks(lights.len());
+        encoder.update_texture::<LightSurface, LightFormat>(&self.light_data,
+                                                            None,
+                                                            region(TEXTURE_WIDTH, rows),
+                                                            data)
+            .unwrap();
+    }
+
+    // Depth range the slices are spread over
+    fn depth_range(&self, cam: &Camera) -> (f32, f32) {
+        let (cam_near, cam_far) = cam.near_far();
+        let near = self.settings.near.max(cam_near);
+        let far = self.settings.far.min(cam_far).max(near * 1.01);
+        (near, far)
+    }
+
+    /// Sort the lights into the cells they reach and upload the cells. Each
+    /// light is bounded by a box around its sphere in view space, which is
+    /// projected to find the tiles it covers.
+    pub fn assign<C: gfx::CommandBuffer<R>>(&mut self,
+                                            encoder: &mut gfx::Encoder<R, C>,
+                                            cam: &Camera,
+                                            lights: &[LightInfo]) {
+        let start = Instant::now();
+
+        let lights = &lights[..lights.len().min(MAX_LIGHTS)];
+        let (tiles_x, tiles_y, slices) =
+            (self.settings.tiles_x, self.settings.tiles_y, self.settings.slices);
+        let view = cam.get_view_matrix();
+        let proj = cam.get_proj_matrix();
+        let (cam_near, _) = cam.near_far();
+        let (near, far) = self.depth_range(cam);
+        let slice_scale = slices as f32 / (far / near).ln();
+
+        let slice = |depth: f32| if depth <= near {
+            0
+        } else {
+            (((depth / near).ln() * slice_scale) as usize).min(slices - 1)
+        };
+        let tile = |ndc: f32, count: usize| {
+            (((ndc * 0.5 + 0.5) * count as f32).max(0.0) as usize).min(count - 1)
+        };
+
+        for cell in &mut self.cell_lights {
+            cell.clear();
+        }
+
+        for (i, light) in lights.iter().enumerate() {
+            let p = view * Vector4::new(light.pos[0], light.pos[1], light.pos[2], 1.0);
+            let r = light.pos[3];
+            let depth = -p.z;
+            if depth + r < cam_near {
+                continue;
+            }
+
+            let rect = if depth - r <= cam_near {
+                // Around the camera, where the box can not be projected
+                Some((0, tiles_x - 1, 0, tiles_y - 1))
+            } else {
+                screen_rect(&proj, &p, r).map(|(x0, x1, y0, y1)| {
+                    (tile(x0, tiles_x), tile(x1, tiles_x), tile(y0, tiles_y), tile(y1, tiles_y))
+                })
+            };
+            let (x0, x1, y0, y1) = match rect {
+                Some(rect) => rect,
+                None => continue,
+            };
+
+            for s in slice(depth - r)..slice(depth + r) + 1 {
+                for y in y0..y1 + 1 {
+                    for x in x0..x1 + 1 {
+                        self.cell_lights[(s * tiles_y + y) * tiles_x + x].push(i as u32);
+                    }
+                }
+            }
+        }
+
+        let max = self.settings.max_indices;
+        let mut overflow = false;
+        self.index_data.clear();
+        for (cell, lights) in self.cell_data.iter_mut().zip(self.cell_lights.iter()) {
+            let count = lights.len().min(max - self.index_data.len());
+            overflow |= count < lights.len();
+
+            *cell = [self.index_data.len() as f32, count as f32, 0.0, 0.0];
+            self.index_data.extend(lights[..count].iter().map(|&i| i as f32));
+        }
+
+        if overflow && !self.overflow {
+            warn!(target: "DAT205",
+                  "More than {} light indices, raise cluster.max_indices",
+                  max);
+        }
+        self.overflow = overflow;
+
+        let data = gfx::memory::cast_slice(&self.cell_data);
+        encoder.update_texture::<LightSurface, LightFormat>(&self.cells,
+                                                            None,
+                                                            region(tiles_x * tiles_y, slices),
+                                                            data)
+            .unwrap();
+
+        // Whole rows only
+        let rows = (self.index_data.len() + TEXTURE_WIDTH - 1) / TEXTURE_WIDTH;
+        if rows > 0 {
+            self.index_data.resize(rows * TEXTURE_WIDTH, 0.0);
+            let data = gfx::memory::cast_slice(&self.index_data);
+            encoder.update_texture::<IndexSurface, IndexFormat>(&self.indices,
+                                                                None,
+                                                                region(TEXTURE_WIDTH, rows),
+                                                                data)
+                .unwrap();
+        }
+
+        let t = start.elapsed();
+        self.assign_ms = t.as_secs() as f32 * 1000.0 + t.subsec_nanos() as f32 / 1.0e6;
+    }
+
+    pub fn locals(&self, cam: &Camera, viewport: (f32, f32)) -> ClusterLocals {
+        let s = &self.settings;
+        let (near, far) = self.depth_range(cam);
+        ClusterLocals {
+            view: cam.get_view_matrix().into(),
+            grid: [s.tiles_x as f32, s.tiles_y as f32, s.slices as f32, 0.0],
+            depth: [near, s.slices as f32 / (far / near).ln(), viewport.0, viewport.1],
+        }
+    }
+}
+
+fn region(width: usize, height: usize) -> texture::NewImageInfo {
+    texture::ImageInfoCommon {
+        xoffset: 0,
+        yoffset: 0,
+        zoffset: 0,
+        width: width as u16,
+        height: height as u16,
+        depth: 0,
+        format: (),
+        mipmap: 0,
+    }
+}
+
+// Blocks of three rows of the light texture needed for `count` lights.
+fn light_blocks(count: usize) -> usize {
+    (count + TEXTURE_WIDTH - 1) / TEXTURE_WIDTH
+}
+
+// Bounds in normalized device coordinates of a sphere in front of the near
+// plane, from the corners of the box around it. None if it is off screen.
+fn screen_rect(proj: &Matrix4<f32>,
+               center: &Vector4<f32>,
+               r: f32)
+               -> Option<(f32, f32, f32, f32)> {
+    let (mut x0, mut x1, mut y0, mut y1) = (1.0f32, -1.0f32, 1.0f32, -1.0f32);
+    for i in 0..8 {
+        let sign = |bit: usize| if i & bit == 0 { -r } else { r };
+        let corner = Vector4::new(center.x + sign(1), center.y + sign(2), center.z + sign(4), 1.0);
+        let c = proj * corner;
+        let (x, y) = (c.x / c.w, c.y / c.w);
+        x0 = x0.min(x);
+        x1 = x1.max(x);
+        y0 = y0.min(y);
+        y1 = y1.max(y);
+    }
+
+    if x0 > 1.0 || x1 < -1.0 || y0 > 1.0 || y1 < -1.0 {
+        None
+    } else {
+        Some((x0, x1, y0, y1))
+    }
+}
+
+/// Compares the frame time of both lighting modes at a few light counts,
+/// see `FrameBenchmark`.
+pub struct LightBenchmark {
+    frames: FrameBenchmark<(LightingMode, usize)>,
+}
+
+impl LightBenchmark {
+    /// Measure each of `counts` with both modes. `previous` is the setup to
+    /// go back to afterwards.
+    pub fn new(counts: &[usize], previous: (LightingMode, usize)) -> LightBenchmark {
+        let runs = counts.iter()
+            .flat_map(|&n| vec![(LightingMode::Volumes, n), (LightingMode::Clustered, n)])
+            .collect();
+
+        LightBenchmark { frames: FrameBenchmark::new(runs, previous) }
+    }
+
+    /// Count a frame. Returns the mode and light count to draw the next
+    /// frame with, or None once every setup has been measured, after logging
+    /// the results. `assign_ms` is the time spent sorting lights this frame.
+    pub fn next_frame(&mut self, assign_ms: f32) -> Option<(LightingMode, usize)> {
+        let next = self.frames.next_frame(assign_ms);
+        if next.is_none() {
+            self.log();
+        }
+        next
+    }
+
+    pub fn previous(&self) -> (LightingMode, usize) {
+        self.frames.previous()
+    }
+
+    fn log(&self) {
+        info!(target: "DAT205", "Light benchmark, average over {} frames:", MEASURED_FRAMES);
+        for &((mode, count), frame_ms, assign_ms) in self.frames.results() {
+            if mode == LightingMode::Clustered {
+                info!(target: "DAT205",
+                      "  {:>5} lights {:>9}: {:7.2} ms/frame, {:.2} ms sorting",
+                      count,
+                      mode.name(),
+                      frame_ms,
+                      assign_ms);
+            } else {
+                info!(target: "DAT205",
+                      "  {:>5} lights {:>9}: {:7.2} ms/frame",
+                      count,
+                      mode.name(),
+                      frame_ms);
+            }
+        }
+    }
+}
@@ -1,16 +1,44 @@
 
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+
 use gfx;
 use gfx::{Bundle, texture};
 pub use gfx::format::Depth;
 use na::{Point3, Vector2, Vector3, Matrix4, Isometry3, Perspective3, Translation3};
 use na;
-use noise::perlin2;
-use noise;
 
 use alewife;
+use rand;
+use rand::Rng;
+use glutin;
 use core::event;
 use rendering;
 use rendering::colors;
+use rendering::heightmap::Heightmap;
+use rendering::erosion;
+use rendering::splatting::TerrainLayers;
+use rendering::biome::BiomeMap;
+use rendering::displacement::{DisplacedTerrain, TerrainBenchmark};
+use rendering::occlusion;
+use rendering::occlusion::OcclusionSettings;
+use rendering::lights;
+use rendering::lights::{DirectionalLight, LightSettings, PointLight, SpotLight};
+use rendering::clusters::{with_light_data, ClusterLocals, ClusterSettings, LightBenchmark,
+                          LightClusters, LightingMode, MAX_LIGHTS};
+use rendering::lsystem;
+use rendering::planet::Planet;
+use rendering::water::Water;
+use rendering::scatter::{Scatter, ScatterRule};
+use rendering::shadows;
+use rendering::shadows::{PointShadowLocals, PointShadowSettings, PointShadows, ShadowLocals,
+                          ShadowMaps, ShadowSettings};
+use rendering::roads::{Roads, RoadSettings};
+use rendering::tonemap::{ToneMapSettings, ToneMapper};
+use rendering::bloom::{Bloom, BloomSettings};
+use rendering::export;
+use rendering::sculpt::{BrushTool, GridRect, Sculptor, TerrainEdits};
+use support::config::Config;
 
 use genmesh::generators::SphereUV;
 use genmesh::{Vertices, Triangulate};
@@ -29,17 +57,23 @@ gfx_defines!{
         pos: [f32; 3] = "a_Pos",
         normal: [f32; 3] = "a_Normal",
         color: [f32; 3] = "a_Color",
+        weights: [f32; 4] = "a_Weights",
+        occlusion: [f32; 2] = "a_Occlusion",
+    }
+
+    vertex SphereVertex {
+        pos: [f32; 3] = "a_Pos",
     }
 
-    vertex CubeVertex {
-        pos: [i8; 4] = "a_Pos",
+    vertex ConeVertex {
+        pos: [f32; 3] = "a_Pos",
     }
 
     constant LightLocals {
-        cam_pos_and_radius: [f32; 4] = "u_CamPosAndRadius",
+        cam_pos: [f32; 4] = "u_CamPos",
     }
 
-    constant CubeLocals {
+    constant VolumeLocals {
         transform: [[f32; 4]; 4] = "u_Transform",
         radius: f32 = "u_Radius",
     }
@@ -49,8 +83,50 @@ gfx_defines!{
         viewProj: [[f32; 4]; 4] = "u_ViewProj",
     }
 
+    constant TerrainMaterial {
+        tiling: [f32; 4] = "u_LayerTiling",
+        roughness: [f32; 4] = "u_LayerRoughness",
+        metallic: [f32; 4] = "u_LayerMetallic",
+    }
+
+    constant AmbientLocals {
+        color: [f32; 4] = "u_AmbientColor",
+        cam_pos: [f32; 4] = "u_CamPos",
+    }
+
     constant LightInfo {
+        // Position, radius
+        pos: [f32; 4] = "pos",
+        // Color, intensity
+        color: [f32; 4] = "color",
+        // Falloff model, shadow map slot or -1, unused
+        falloff: [f32; 4] = "falloff",
+    }
+
+    constant SpotInfo {
+        // Position, range
         pos: [f32; 4] = "pos",
+        // Direction, cosine of the outer angle
+        direction: [f32; 4] = "direction",
+        // Color, intensity
+        color: [f32; 4] = "color",
+        // Cosine of the inner angle, tangent of the outer angle, unused
+        cone: [f32; 4] = "cone",
+    }
+
+    constant SunLocals {
+        direction: [f32; 4] = "u_SunDirection",
+        color: [f32; 4] = "u_SunColor",
+        cam_pos: [f32; 4] = "u_CamPos",
+    }
+
+    constant GBufferLocals {
+        inv_view_proj: [[f32; 4]; 4] = "u_InvViewProj",
+    }
+
+    constant DebugLocals {
+        // Which part of the G-buffer to show, see `DebugView`
+        view: [f32; 4] = "u_DebugView",
     }
 /*
     constant BlitLocals {
@@ -60,17 +136,19 @@ gfx_defines!{
     pipeline terrain {
         vbuf: gfx::VertexBuffer<TerrainVertex> = (),
         locals: gfx::ConstantBuffer<TerrainLocals> = "TerrainLocals",
-        out_position: gfx::RenderTarget<GFormat> = "Target0",
-        out_normal: gfx::RenderTarget<GFormat> = "Target1",
-        out_color: gfx::RenderTarget<GFormat> = "Target2",
+        material: gfx::ConstantBuffer<TerrainMaterial> = "TerrainMaterial",
+        layers: gfx::TextureSampler<[f32; 4]> = "t_Layers",
+        out_normal: gfx::RenderTarget<NormalFormat> = "Target0",
+        out_color: gfx::RenderTarget<AlbedoFormat> = "Target1",
+        out_material: gfx::RenderTarget<MaterialFormat> = "Target2",
         out_depth: gfx::DepthTarget<Depth> =
             gfx::preset::depth::LESS_EQUAL_WRITE,
     }
 
     pipeline emitter {
-        vbuf: gfx::VertexBuffer<CubeVertex> = (),
-        locals: gfx::ConstantBuffer<CubeLocals> = "CubeLocals",
-        light_pos_buf: gfx::ConstantBuffer<LightInfo> = "LightPosBlock",
+        vbuf: gfx::VertexBuffer<SphereVertex> = (),
+        locals: gfx::ConstantBuffer<VolumeLocals> = "VolumeLocals",
+        light_data: gfx::TextureSampler<[f32; 4]> = "t_LightData",
         out_color: gfx::BlendTarget<GFormat> =
             ("Target0", gfx::state::MASK_ALL, gfx::preset::blend::ADD),
         out_depth: gfx::DepthTarget<Depth> =
@@ -78,17 +156,98 @@ gfx_defines!{
     }
     
     pipeline light {
-        vbuf: gfx::VertexBuffer<CubeVertex> = (),
-        locals_vs: gfx::ConstantBuffer<CubeLocals> = "CubeLocals",
+        vbuf: gfx::VertexBuffer<SphereVertex> = (),
+        locals_vs: gfx::ConstantBuffer<VolumeLocals> = "VolumeLocals",
         locals_ps: gfx::ConstantBuffer<LightLocals> = "LightLocals",
-        light_pos_buf: gfx::ConstantBuffer<LightInfo> = "LightPosBlock",
-        tex_pos: gfx::TextureSampler<[f32; 4]> = "t_Position",
+        light_data: gfx::TextureSampler<[f32; 4]> = "t_LightData",
+        shadow_locals: gfx::ConstantBuffer<PointShadowLocals> = "PointShadowLocals",
+        shadow_map: gfx::TextureSampler<f32> = "t_PointShadow",
+        gbuffer_locals: gfx::ConstantBuffer<GBufferLocals> = "GBufferLocals",
+        // The copy, since the depth buffer is bound for testing
+        tex_depth: gfx::TextureSampler<f32> = "t_Depth",
         tex_normal: gfx::TextureSampler<[f32; 4]> = "t_Normal",
         tex_diffuse: gfx::TextureSampler<[f32; 4]> = "t_Diffuse",
+        tex_material: gfx::TextureSampler<[f32; 4]> = "t_Material",
+        out_color: gfx::BlendTarget<GFormat> =
+            ("Target0", gfx::state::MASK_ALL, gfx::preset::blend::ADD),
+        out_depth: gfx::DepthTarget<Depth> = BEHIND_SCENE,
+    }
+
+    pipeline clustered {
+        vbuf: gfx::VertexBuffer<BlitVertex> = (),
+        locals: gfx::ConstantBuffer<LightLocals> = "LightLocals",
+        cluster_locals: gfx::ConstantBuffer<ClusterLocals> = "ClusterLocals",
+        shadow_locals: gfx::ConstantBuffer<PointShadowLocals> = "PointShadowLocals",
+        shadow_map: gfx::TextureSampler<f32> = "t_PointShadow",
+        light_data: gfx::TextureSampler<[f32; 4]> = "t_LightData",
+        clusters: gfx::TextureSampler<[f32; 4]> = "t_Clusters",
+        light_indices: gfx::TextureSampler<f32> = "t_LightIndices",
+        gbuffer_locals: gfx::ConstantBuffer<GBufferLocals> = "GBufferLocals",
+        tex_depth: gfx::TextureSampler<[f32; 4]> = "t_Depth",
+        tex_normal: gfx::TextureSampler<[f32; 4]> = "t_Normal",
+        tex_diffuse: gfx::TextureSampler<[f32; 4]> = "t_Diffuse",
+        tex_material: gfx::TextureSampler<[f32; 4]> = "t_Material",
+        out_color: gfx::BlendTarget<GFormat> =
+            ("Target0", gfx::state::MASK_ALL, gfx::preset::blend::ADD),
+    }
+
+    pipeline spot {
+        vbuf: gfx::VertexBuffer<ConeVertex> = (),
+        locals_vs: gfx::ConstantBuffer<VolumeLocals> = "VolumeLocals",
+        locals_ps: gfx::ConstantBuffer<LightLocals> = "LightLocals",
+        spot_buf: gfx::ConstantBuffer<SpotInfo> = "SpotBlock",
+        gbuffer_locals: gfx::ConstantBuffer<GBufferLocals> = "GBufferLocals",
+        // The copy, since the depth buffer is bound for testing
+        tex_depth: gfx::TextureSampler<f32> = "t_Depth",
+        tex_normal: gfx::TextureSampler<[f32; 4]> = "t_Normal",
+        tex_diffuse: gfx::TextureSampler<[f32; 4]> = "t_Diffuse",
+        tex_material: gfx::TextureSampler<[f32; 4]> = "t_Material",
+        out_color: gfx::BlendTarget<GFormat> =
+            ("Target0", gfx::state::MASK_ALL, gfx::preset::blend::ADD),
+        out_depth: gfx::DepthTarget<Depth> = BEHIND_SCENE,
+    }
+
+    pipeline sun {
+        vbuf: gfx::VertexBuffer<BlitVertex> = (),
+        locals: gfx::ConstantBuffer<SunLocals> = "SunLocals",
+        shadow_locals: gfx::ConstantBuffer<ShadowLocals> = "ShadowLocals",
+        shadow_map: gfx::TextureSampler<f32> = "t_Shadow",
+        gbuffer_locals: gfx::ConstantBuffer<GBufferLocals> = "GBufferLocals",
+        tex_depth: gfx::TextureSampler<[f32; 4]> = "t_Depth",
+        tex_normal: gfx::TextureSampler<[f32; 4]> = "t_Normal",
+        tex_diffuse: gfx::TextureSampler<[f32; 4]> = "t_Diffuse",
+        tex_material: gfx::TextureSampler<[f32; 4]> = "t_Material",
+        out_color: gfx::BlendTarget<GFormat> =
+            ("Target0", gfx::state::MASK_ALL, gfx::preset::blend::ADD),
+    }
+
+    pipeline ambient {
+        vbuf: gfx::VertexBuffer<BlitVertex> = (),
+        locals: gfx::ConstantBuffer<AmbientLocals> = "AmbientLocals",
+        gbuffer_locals: gfx::ConstantBuffer<GBufferLocals> = "GBufferLocals",
+        tex_depth: gfx::TextureSampler<[f32; 4]> = "t_Depth",
+        tex_normal: gfx::TextureSampler<[f32; 4]> = "t_Normal",
+        tex_diffuse: gfx::TextureSampler<[f32; 4]> = "t_Diffuse",
+        tex_material: gfx::TextureSampler<[f32; 4]> = "t_Material",
         out_color: gfx::BlendTarget<GFormat> =
             ("Target0", gfx::state::MASK_ALL, gfx::preset::blend::ADD),
-        out_depth: gfx::DepthTarget<Depth> =
-            gfx::preset::depth::LESS_EQUAL_TEST,
+    }
+
+    pipeline gbuffer_debug {
+        vbuf: gfx::VertexBuffer<BlitVertex> = (),
+        locals: gfx::ConstantBuffer<DebugLocals> = "DebugLocals",
+        gbuffer_locals: gfx::ConstantBuffer<GBufferLocals> = "GBufferLocals",
+        tex_depth: gfx::TextureSampler<[f32; 4]> = "t_Depth",
+        tex_normal: gfx::TextureSampler<[f32; 4]> = "t_Normal",
+        tex_diffuse: gfx::TextureSampler<[f32; 4]> = "t_Diffuse",
+        tex_material: gfx::TextureSampler<[f32; 4]> = "t_Material",
+        out: gfx::RenderTarget<GFormat> = "Target0",
+    }
+
+    pipeline depth_copy {
+        vbuf: gfx::VertexBuffer<BlitVertex> = (),
+        tex_depth: gfx::TextureSampler<[f32; 4]> = "t_Depth",
+        out: gfx::RenderTarget<DepthCopyFormat> = "Target0",
     }
 
     pipeline blit {
@@ -201,131 +360,497 @@ const BLIT_FRAGMENT_SHADER: &'static [u8] = b"
     }
 ";
 
-const LIGHT_FRAGMENT_SHADER: &'static [u8] = b"
-    #version 150 core
+// Octahedral normal encoding. The sphere is folded onto an octahedron and
+// flattened into the [-1, 1] square, which spreads the precision of the two
+// channels evenly over all directions.
+const NORMAL_ENCODING_GLSL: &'static [u8] = b"
+    vec2 oct_wrap(vec2 v) {
+        vec2 signs = vec2(v.x >= 0.0 ? 1.0 : -1.0, v.y >= 0.0 ? 1.0 : -1.0);
+        return (1.0 - abs(v.yx)) * signs;
+    }
+
+    vec2 encode_normal(vec3 n) {
+        n /= abs(n.x) + abs(n.y) + abs(n.z);
+        return n.z >= 0.0 ? n.xy : oct_wrap(n.xy);
+    }
+
+    vec3 decode_normal(vec2 e) {
+        vec3 n = vec3(e, 1.0 - abs(e.x) - abs(e.y));
+        if (n.z < 0.0) {
+            n.xy = oct_wrap(n.xy);
+        }
+        return normalize(n);
+    }
+";
 
+// Reading the G-buffer. Positions are reconstructed from the depth buffer.
+// The light volumes test against the depth buffer, so they read a copy of
+// it instead, see `DEPTH_COPY_FRAGMENT_SHADER`.
+const GBUFFER_GLSL: &'static [u8] = b"
     layout(std140)
-    
-    uniform LightLocals {
-        vec4 u_CamPosAndRadius;
+    uniform GBufferLocals {
+        mat4 u_InvViewProj;
     };
 
-    uniform sampler2D t_Position;
+    uniform sampler2D t_Depth;
     uniform sampler2D t_Normal;
     uniform sampler2D t_Diffuse;
-    
-    in vec3 v_LightPos;
-    
+    uniform sampler2D t_Material;
+
+    // Nothing was drawn where the depth is still cleared
+    bool is_sky(ivec2 itc) {
+        return texelFetch(t_Depth, itc, 0).r >= 1.0;
+    }
+
+    vec3 world_position(ivec2 itc) {
+        vec2 uv = (vec2(itc) + 0.5) / vec2(textureSize(t_Depth, 0));
+        float depth = texelFetch(t_Depth, itc, 0).r;
+        vec4 pos = u_InvViewProj * vec4(2.0 * vec3(uv, depth) - 1.0, 1.0);
+        return pos.xyz / pos.w;
+    }
+";
+
+// Copies the depth buffer into a color target, for the passes that have the
+// depth buffer bound. Sampling a bound depth buffer is undefined in GL, even
+// without writing to it.
+const DEPTH_COPY_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    uniform sampler2D t_Depth;
+
+    out float Target0;
+
+    void main() {
+        Target0 = texelFetch(t_Depth, ivec2(gl_FragCoord.xy), 0).r;
+    }
+";
+
+/// Add `encode_normal` and `decode_normal` to a shader, for everything that
+/// writes normals to the G-buffer.
+pub fn with_normal_encoding(shader: &[u8]) -> Vec<u8> {
+    shadows::insert_after_version(shader, NORMAL_ENCODING_GLSL)
+}
+
+// Add the G-buffer textures, the `GBufferLocals` block and the functions to
+// read them to a lighting shader.
+fn with_gbuffer(shader: &[u8]) -> Vec<u8> {
+    with_normal_encoding(&shadows::insert_after_version(shader, GBUFFER_GLSL))
+}
+
+// Shows one part of the G-buffer as a color, see `DebugView`. Positions and
+// normals are decoded first, so they look the same as when they were stored
+// as they are.
+const GBUFFER_DEBUG_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform DebugLocals {
+        vec4 u_DebugView;
+    };
+
     out vec4 Target0;
 
     void main() {
         ivec2 itc = ivec2(gl_FragCoord.xy);
-        vec3 pos     = texelFetch(t_Position, itc, 0).xyz;
-        vec3 normal  = texelFetch(t_Normal,   itc, 0).xyz;
-        vec3 diffuse = texelFetch(t_Diffuse,  itc, 0).xyz;
+        int view = int(u_DebugView.x);
+
+        if (view == 0) {
+            Target0 = is_sky(itc) ? vec4(0.0) : vec4(world_position(itc), 1.0);
+        } else if (view == 1) {
+            vec4 normal = texelFetch(t_Normal, itc, 0);
+            Target0 = vec4(decode_normal(normal.xy), normal.z);
+        } else if (view == 2) {
+            Target0 = texelFetch(t_Diffuse, itc, 0);
+        } else if (view == 3) {
+            Target0 = texelFetch(t_Material, itc, 0);
+        } else {
+            Target0 = texelFetch(t_Depth, itc, 0);
+        }
+    }
+";
 
-        vec3 light    = v_LightPos;
-        vec3 to_light = normalize(light - pos);
-        vec3 to_cam   = normalize(u_CamPosAndRadius.xyz - pos);
+// Light from the sky, dimmed by the occlusion stored in the blue and alpha
+// channels of the normal buffer. Drawn once over the whole screen, since
+// adding it to the light volumes would count it once per overlapping light.
+const AMBIENT_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
 
-        vec3 n = normalize(normal);
-        float s = pow(max(0.0, dot(to_cam, reflect(-to_light, n))), 20.0);
-        float d = max(0.0, dot(n, to_light));
+    layout(std140)
+    uniform AmbientLocals {
+        vec4 u_AmbientColor;
+        vec4 u_CamPos;
+    };
 
-        float dist_sq = dot(light - pos, light - pos);
-        float scale = max(0.0, 1.0 - dist_sq * u_CamPosAndRadius.w);
+    in vec2 v_TexCoord;
 
-        vec3 res_color = d * diffuse + vec3(s);
+    out vec4 Target0;
 
-        Target0 = vec4(scale * res_color, 1.0);
+    void main() {
+        ivec2 itc = ivec2(gl_FragCoord.xy);
+        if (is_sky(itc)) {
+            discard;
+        }
+
+        vec3 pos      = world_position(itc);
+        vec4 normal   = texelFetch(t_Normal,   itc, 0);
+        vec3 diffuse  = texelFetch(t_Diffuse,  itc, 0).rgb;
+        vec2 material = texelFetch(t_Material, itc, 0).xy;
+
+        // Surfaces facing up see more of the sky
+        vec3 n = decode_normal(normal.xy);
+        float up = 0.75 + 0.25 * n.y;
+
+        // The sky is the same in every direction, so whatever the shape of
+        // the specular lobe it reflects the sky weighted by Fresnel. Rough
+        // surfaces lose most of the bright reflections at grazing angles.
+        float n_dot_v = max(dot(n, normalize(u_CamPos.xyz - pos)), 0.0);
+        vec3 f0 = base_reflectance(diffuse, material.y);
+        vec3 f = f0 + (max(vec3(1.0 - material.x), f0) - f0) * pow(1.0 - n_dot_v, 5.0);
+        vec3 kd = (vec3(1.0) - f) * (1.0 - material.y);
+
+        vec3 sky = u_AmbientColor.rgb * normal.z * normal.w * up;
+        Target0 = vec4(sky * (kd * diffuse + f), 1.0);
     }
 ";
 
-// TODO: NUM_LIGHTS as uniform
-const LIGHT_VERTEX_SHADER: &'static [u8] = b"
+// Directional light over the whole screen, like the ambient pass. Built
+// with `with_gbuffer`, `shadows::with_shadows` and `lights::with_brdf`.
+const SUN_FRAGMENT_SHADER: &'static [u8] = b"
     #version 150 core
 
-    in ivec3 a_Pos;
+    layout(std140)
+    uniform SunLocals {
+        vec4 u_SunDirection;
+        vec4 u_SunColor;
+        vec4 u_CamPos;
+    };
 
-    out vec3 v_LightPos;
+    in vec2 v_TexCoord;
+
+    out vec4 Target0;
+
+    void main() {
+        ivec2 itc = ivec2(gl_FragCoord.xy);
+        if (is_sky(itc)) {
+            discard;
+        }
+
+        vec3 pos      = world_position(itc);
+        vec3 n        = decode_normal(texelFetch(t_Normal, itc, 0).xy);
+        vec3 diffuse  = texelFetch(t_Diffuse,  itc, 0).rgb;
+        vec2 material = texelFetch(t_Material, itc, 0).xy;
+
+        vec3 to_light = u_SunDirection.xyz;
+        vec3 to_cam   = normalize(u_CamPos.xyz - pos);
+
+        vec3 res_color =
+            u_SunColor.rgb * brdf(n, to_cam, to_light, diffuse, material.x, material.y);
+        float lit = shadow(pos, n, to_light);
+
+        Target0 = vec4(u_SunColor.a * lit * res_color * cascade_tint(pos), 1.0);
+    }
+";
+
+const SPOT_VERTEX_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    in vec3 a_Pos;
+
+    flat out vec4 v_SpotPos;
+    flat out vec4 v_SpotDirection;
+    flat out vec4 v_SpotColor;
+    flat out vec4 v_SpotCone;
 
     layout(std140)
-    uniform CubeLocals {
+    uniform VolumeLocals {
         mat4 u_Transform;
         float u_Radius;
     };
 
-    struct LightInfo {
+    struct SpotInfo {
         vec4 pos;
+        vec4 direction;
+        vec4 color;
+        vec4 cone;
     };
 
-    const int NUM_LIGHTS = 250;
-    
+    const int MAX_SPOT_LIGHTS = 32;
+
     layout(std140)
-    uniform LightPosBlock {
-        LightInfo u_Lights[NUM_LIGHTS];
+    uniform SpotBlock {
+        SpotInfo u_Spots[MAX_SPOT_LIGHTS];
     };
 
     void main() {
-        v_LightPos = u_Lights[gl_InstanceID].pos.xyz;
-        gl_Position = u_Transform * vec4(u_Radius * a_Pos + v_LightPos, 1.0);
+        SpotInfo spot = u_Spots[gl_InstanceID];
+
+        // The unit cone opens along z, turn it to the spot direction
+        vec3 w = spot.direction.xyz;
+        vec3 up = abs(w.y) < 0.99 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+        vec3 u = normalize(cross(up, w));
+        vec3 v = cross(w, u);
+
+        float range = spot.pos.w;
+        float r = range * spot.cone.y;
+        vec3 p = spot.pos.xyz + u * (a_Pos.x * r) + v * (a_Pos.y * r) + w * (a_Pos.z * range);
+
+        v_SpotPos = spot.pos;
+        v_SpotDirection = spot.direction;
+        v_SpotColor = spot.color;
+        v_SpotCone = spot.cone;
+        gl_Position = u_Transform * vec4(p, 1.0);
     }
 ";
 
-const EMITTER_FRAGMENT_SHADER: &'static [u8] = b"
+const SPOT_FRAGMENT_SHADER: &'static [u8] = b"
     #version 150 core
 
+    layout(std140)
+    uniform LightLocals {
+        vec4 u_CamPos;
+    };
+
+    flat in vec4 v_SpotPos;
+    flat in vec4 v_SpotDirection;
+    flat in vec4 v_SpotColor;
+    flat in vec4 v_SpotCone;
+
     out vec4 Target0;
 
     void main() {
-        Target0 = vec4(1.0, 1.0, 1.0, 1.0);
+        ivec2 itc = ivec2(gl_FragCoord.xy);
+        vec3 pos      = world_position(itc);
+        vec3 n        = decode_normal(texelFetch(t_Normal, itc, 0).xy);
+        vec3 diffuse  = texelFetch(t_Diffuse,  itc, 0).rgb;
+        vec2 material = texelFetch(t_Material, itc, 0).xy;
+
+        vec3 to_light = v_SpotPos.xyz - pos;
+        float dist_sq = dot(to_light, to_light);
+        to_light = normalize(to_light);
+        vec3 to_cam = normalize(u_CamPos.xyz - pos);
+
+        float range = max(0.0, 1.0 - dist_sq / (v_SpotPos.w * v_SpotPos.w));
+        float cone = smoothstep(v_SpotDirection.w,
+                                v_SpotCone.x,
+                                dot(-to_light, v_SpotDirection.xyz));
+        float scale = v_SpotColor.a * range * cone;
+
+        vec3 res_color =
+            v_SpotColor.rgb * brdf(n, to_cam, to_light, diffuse, material.x, material.y);
+
+        Target0 = vec4(scale * res_color, 1.0);
     }
 ";
 
-const EMITTER_VERTEX_SHADER: &'static [u8] = b"
+const LIGHT_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    
+    uniform LightLocals {
+        vec4 u_CamPos;
+    };
+
+    in vec3 v_LightPos;
+    flat in vec4 v_LightColor;
+    flat in float v_LightRadius;
+    flat in int v_Falloff;
+    flat in int v_ShadowSlot;
+    
+    out vec4 Target0;
+
+    void main() {
+        ivec2 itc = ivec2(gl_FragCoord.xy);
+        vec3 pos      = world_position(itc);
+        vec3 n        = decode_normal(texelFetch(t_Normal, itc, 0).xy);
+        vec3 diffuse  = texelFetch(t_Diffuse,  itc, 0).rgb;
+        vec2 material = texelFetch(t_Material, itc, 0).xy;
+
+        vec3 res_color = point_light(pos, n, diffuse, material, u_CamPos.xyz,
+                                     v_LightPos, v_LightColor, v_LightRadius,
+                                     v_Falloff, v_ShadowSlot);
+
+        Target0 = vec4(res_color, 1.0);
+    }
+";
+
+const LIGHT_VERTEX_SHADER: &'static [u8] = b"
     #version 150 core
 
-    in ivec3 a_Pos;
+    in vec3 a_Pos;
+
+    out vec3 v_LightPos;
+    flat out vec4 v_LightColor;
+    flat out float v_LightRadius;
+    flat out int v_Falloff;
+    flat out int v_ShadowSlot;
 
     layout(std140)
-    uniform CubeLocals {
+    uniform VolumeLocals {
         mat4 u_Transform;
         float u_Radius;
     };
 
-    struct LightInfo {
-        vec4 pos;
+    void main() {
+        vec4 pos = light_data(gl_InstanceID, 0);
+        vec4 falloff = light_data(gl_InstanceID, 2);
+        v_LightPos = pos.xyz;
+        v_LightColor = light_data(gl_InstanceID, 1);
+        v_LightRadius = pos.w;
+        v_Falloff = int(falloff.x);
+        v_ShadowSlot = int(falloff.y);
+        gl_Position = u_Transform * vec4(pos.w * a_Pos + pos.xyz, 1.0);
+    }
+";
+
+// Every pixel is lit by the lights of the cluster it falls in, see
+// `LightClusters`.
+const CLUSTERED_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform LightLocals {
+        vec4 u_CamPos;
     };
 
-    const int NUM_LIGHTS = 250;
-    
     layout(std140)
-    uniform LightPosBlock {
-        LightInfo u_Lights[NUM_LIGHTS];
+    uniform ClusterLocals {
+        mat4 u_View;
+        vec4 u_ClusterGrid;
+        vec4 u_ClusterDepth;
+    };
+
+    uniform sampler2D t_Clusters;
+    uniform sampler2D t_LightIndices;
+
+    out vec4 Target0;
+
+    const int INDEX_WIDTH = 1024;
+
+    void main() {
+        ivec2 itc = ivec2(gl_FragCoord.xy);
+        // Nothing to light in the sky
+        if (is_sky(itc)) {
+            discard;
+        }
+        vec3 pos      = world_position(itc);
+        vec3 n        = decode_normal(texelFetch(t_Normal, itc, 0).xy);
+        vec3 diffuse  = texelFetch(t_Diffuse, itc, 0).rgb;
+        vec2 material = texelFetch(t_Material, itc, 0).xy;
+
+        ivec3 grid = ivec3(u_ClusterGrid.xyz);
+        float depth = -(u_View * vec4(pos, 1.0)).z;
+        int slice = int(log(max(depth, u_ClusterDepth.x) / u_ClusterDepth.x) * u_ClusterDepth.y);
+        ivec2 tile = ivec2(gl_FragCoord.xy / u_ClusterDepth.zw * u_ClusterGrid.xy);
+        tile = clamp(tile, ivec2(0), grid.xy - 1);
+        slice = clamp(slice, 0, grid.z - 1);
+
+        vec2 cluster = texelFetch(t_Clusters, ivec2(tile.y * grid.x + tile.x, slice), 0).xy;
+        int offset = int(cluster.x);
+        int count = int(cluster.y);
+
+        vec3 res_color = vec3(0.0);
+        for (int i = offset; i < offset + count; i++) {
+            int light = int(texelFetch(t_LightIndices, ivec2(i % INDEX_WIDTH, i / INDEX_WIDTH), 0).r);
+            vec4 light_pos = light_data(light, 0);
+            vec4 color = light_data(light, 1);
+            vec4 falloff = light_data(light, 2);
+            res_color += point_light(pos, n, diffuse, material, u_CamPos.xyz,
+                                     light_pos.xyz, color, light_pos.w,
+                                     int(falloff.x), int(falloff.y));
+        }
+
+        Target0 = vec4(res_color, 1.0);
+    }
+";
+
+const EMITTER_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    flat in vec3 v_Color;
+
+    out vec4 Target0;
+
+    void main() {
+        Target0 = vec4(v_Color, 1.0);
+    }
+";
+
+const EMITTER_VERTEX_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    in vec3 a_Pos;
+
+    flat out vec3 v_Color;
+
+    layout(std140)
+    uniform VolumeLocals {
+        mat4 u_Transform;
+        float u_Radius;
     };
 
     void main() {
-        gl_Position = u_Transform * vec4(u_Radius * a_Pos + u_Lights[gl_InstanceID].pos.xyz, 1.0);
+        vec3 pos = light_data(gl_InstanceID, 0).xyz;
+        vec4 color = light_data(gl_InstanceID, 1);
+        // As bright as the light they give off, so they stand out and bloom
+        v_Color = color.rgb * color.a;
+        gl_Position = u_Transform * vec4(u_Radius * a_Pos + pos, 1.0);
     }
 ";
 
-const TERRAIN_FRAGMENT_SHADER: &'static [u8] = b"
+/// Fragment shader of the terrain, also used for displaced terrain. Needs
+/// `with_normal_encoding`.
+pub const TERRAIN_FRAGMENT_SHADER: &'static [u8] = b"
     #version 150 core
 
+    layout(std140)
+    uniform TerrainMaterial {
+        vec4 u_LayerTiling;
+        vec4 u_LayerRoughness;
+        vec4 u_LayerMetallic;
+    };
+
+    uniform sampler2DArray t_Layers;
+
     in vec3 v_FragPos;
     in vec3 v_Normal;
     in vec3 v_Color;
+    in vec4 v_Weights;
+    in vec2 v_Occlusion;
     
     out vec4 Target0;
     out vec4 Target1;
     out vec4 Target2;
 
+    // Project the layer texture along all three axes and blend by the
+    // normal, so steep faces do not get stretched.
+    vec3 triplanar(int layer, vec3 p, vec3 blend) {
+        vec3 uv = vec3(p * u_LayerTiling[layer]);
+        vec3 x = texture(t_Layers, vec3(uv.zy, layer)).rgb;
+        vec3 y = texture(t_Layers, vec3(uv.xz, layer)).rgb;
+        vec3 z = texture(t_Layers, vec3(uv.xy, layer)).rgb;
+        return x * blend.x + y * blend.y + z * blend.z;
+    }
+
     void main() {
         vec3 n = normalize(v_Normal);
 
-        Target0 = vec4(v_FragPos, 0.0);
-        Target1 = vec4(n, 0.0);
-        Target2 = vec4(v_Color, 1.0);
+        vec3 blend = pow(abs(n), vec3(4.0));
+        blend /= dot(blend, vec3(1.0));
+
+        // Every layer is sampled, skipping the ones with no weight would put
+        // the texture lookups in non-uniform control flow, where the mip
+        // level is undefined
+        vec3 detail = vec3(0.0);
+        for (int i = 0; i < 4; i++) {
+            detail += v_Weights[i] * triplanar(i, v_FragPos, blend);
+        }
+
+        // Ambient occlusion and sky visibility ride along with the normal,
+        // for the ambient pass
+        Target0 = vec4(encode_normal(n), v_Occlusion);
+        Target1 = vec4(v_Color * detail, 1.0);
+        Target2 = vec4(dot(v_Weights, u_LayerRoughness), dot(v_Weights, u_LayerMetallic), 0.0, 0.0);
     }
 ";
 
@@ -341,28 +866,79 @@ const TERRAIN_VERTEX_SHADER: &'static [u8] = b"
     in vec3 a_Pos;
     in vec3 a_Normal;
     in vec3 a_Color;
+    in vec4 a_Weights;
+    in vec2 a_Occlusion;
 
     out vec3 v_FragPos;
     out vec3 v_Normal;
     out vec3 v_Color;
+    out vec4 v_Weights;
+    out vec2 v_Occlusion;
 
     void main() {
         v_FragPos = (u_Model * vec4(a_Pos, 1.0)).xyz;
         v_Normal = mat3(u_Model) * a_Normal;
         v_Color = a_Color;
+        v_Weights = a_Weights;
+        v_Occlusion = a_Occlusion;
         gl_Position = u_ViewProj * u_Model * vec4(a_Pos, 1.0);
     }
 ";
 
 pub type ColorFormat = gfx::format::Srgba8;
 
-const NUMBER_OF_LIGHTS: u32 = 250;
-const LIGHT_RADIUS: f32 = 10.0;
 const EMITTER_RADIUS: f32 = 0.5;
-const TERRAIN_SCALE: [f32; 3] = [100.0, 100.0, 100.0];
-
+// Point light counts `light_benchmark` compares the lighting modes at
+const BENCHMARK_LIGHTS: [usize; 4] = [250, 1000, 2000, 4096];
+const MAX_SPOT_LIGHTS: usize = 32;
+// Droplets of console erosion flowed each frame
+const EROSION_DROPLETS_PER_FRAME: usize = 2000;
+const CONE_SIDES: usize = 16;
+const SPHERE_SEGMENTS: usize = 16;
+const SPHERE_RINGS: usize = 8;
+
+// Light volumes are drawn with their back faces where these lie behind the
+// scene. Unlike testing the front faces, this keeps working with the camera
+// inside a volume, where the front faces are behind the near plane.
+const BEHIND_SCENE: gfx::state::Depth = gfx::state::Depth {
+    fun: gfx::state::Comparison::GreaterEqual,
+    write: false,
+};
+
+/// Light is accumulated in floats, so it can go past one until it is tone
+/// mapped.
 pub type GFormat = [f32; 4];
 
+/// Octahedral encoded normal in red and green, ambient occlusion and sky
+/// visibility in blue and alpha.
+pub type NormalFormat = (gfx::format::R16_G16_B16_A16, gfx::format::Float);
+
+/// Albedo of the surface, alpha is unused.
+pub type AlbedoFormat = gfx::format::Srgba8;
+
+/// Roughness and metallic of the surface, in the red and green channels.
+pub type MaterialFormat = gfx::format::Rgba8;
+
+/// Copy of the depth buffer, read by the light volumes.
+pub type DepthCopyFormat = (gfx::format::R32, gfx::format::Float);
+
+// Bytes per pixel of the G-buffer, depth and its copy included. For
+// comparison, the same with position, normal and diffuse in full `GFormat`
+// targets.
+const GBUFFER_BYTES: usize = 8 + 4 + 4 + 4 + 4;
+const GBUFFER_BYTES_FLOAT: usize = 3 * 16 + 4 + 4 + 4;
+
+// The parts of the G-buffer the debug pass can show, in the order of
+// `u_DebugView`.
+#[derive(Clone, Copy, Debug)]
+enum DebugView {
+    Position,
+    Normal,
+    Diffuse,
+    Material,
+    Depth,
+}
+
 pub struct ViewPair<R: gfx::Resources, T: gfx::format::Formatted> {
     resource: gfx::handle::ShaderResourceView<R, T::View>,
     target: gfx::handle::RenderTargetView<R, T>,
@@ -381,59 +957,218 @@ impl gfx::format::Formatted for DepthFormat {
     }
 }
 
-fn calculate_normal(seed: &noise::PermutationTable, x: f32, z: f32) -> [f32; 3] {
-
-    let sample_distance = 0.001;
-    let s_x0 = x - sample_distance;
-    let s_x1 = x + sample_distance;
-    let s_y0 = z - sample_distance;
-    let s_y1 = z + sample_distance;
-
-    let dzdx = (perlin2(seed, &[s_x1, z]) - perlin2(seed, &[s_x0, z])) / (s_x1 - s_x0);
-    let dzdy = (perlin2(seed, &[x, s_y1]) - perlin2(seed, &[x, s_y0])) / (s_y1 - s_y0);
+/// Lowest point of the terrain and the height from there to the highest.
+/// Layer and scatter heights are relative to these, so the same rules work
+/// for any vertical scale.
+pub fn terrain_range(heightmap: &Heightmap) -> (f32, f32) {
+    let min = heightmap.heights().iter().fold(::std::f32::MAX, |a, &b| a.min(b));
+    let max = heightmap.heights().iter().fold(::std::f32::MIN, |a, &b| a.max(b));
+    (min, (max - min).max(1.0e-4))
+}
 
-    // Cross gradient vectors to get normal
-    let v1 = Vector3::new(1.0, 0.0, dzdx);
-    let v2 = Vector3::new(0.0, 1.0, dzdy);
-    let normal = v1.cross(&v2).normalize();
+fn terrain_vertex(heightmap: &Heightmap,
+                  layers: &TerrainLayers,
+                  biomes: Option<&BiomeMap>,
+                  occlusion: &[[f32; 2]],
+                  range: (f32, f32),
+                  ix: usize,
+                  iz: usize)
+                  -> TerrainVertex {
+    let pos = heightmap.position(ix, iz);
+    let normal = heightmap.normal(ix, iz);
+    let slope = normal[1].max(-1.0).min(1.0).acos().to_degrees();
+    let weights = layers.weights(pos[0], pos[2], (pos[1] - range.0) / range.1, slope);
+    let color = match biomes {
+        Some(biomes) => biomes.color(pos[0], pos[2], layers, &weights),
+        None => layers.color(&weights),
+    };
+    TerrainVertex {
+        pos: pos,
+        normal: normal,
+        color: color,
+        weights: weights,
+        occlusion: occlusion[iz * heightmap.width() + ix],
+    }
+}
 
-    return normal.into();
+fn terrain_mesh(heightmap: &Heightmap,
+                layers: &TerrainLayers,
+                biomes: Option<&BiomeMap>,
+                occlusion: &[[f32; 2]])
+                -> (Vec<TerrainVertex>, Vec<u32>) {
+    let range = terrain_range(heightmap);
+
+    // Shared vertices of the plane come out in the same row order as the
+    // heightmap samples, so they can be matched up by index.
+    let plane = Plane::subdivide(heightmap.width() - 1, heightmap.depth() - 1);
+    let vertex_data: Vec<TerrainVertex> = (0..plane.shared_vertex_count())
+        .map(|i| {
+            terrain_vertex(heightmap,
+                           layers,
+                           biomes,
+                           occlusion,
+                           range,
+                           i % heightmap.width(),
+                           i / heightmap.width())
+        })
+        .collect();
+
+    let index_data: Vec<u32> = plane.indexed_polygon_iter()
+        .triangulate()
+        .vertices()
+        .map(|i| i as u32)
+        .collect();
+
+    (vertex_data, index_data)
 }
 
-fn calculate_color(height: f32) -> [f32; 3] {
-    if height > 80.0 {
-        colors::WHITE.into() // Snow
-    } else if height > 70.0 {
-        colors::BROWN.into() // Ground
-    } else if height > -5.0 {
-        colors::LIGHT_GREEN.into() // Grass
-    } else {
-        colors::LIGHT_BLUE.into() // Water
-    }
+/// Part of a terrain that is not a heightfield, in world space.
+pub struct TerrainChunk {
+    pub vertices: Vec<TerrainVertex>,
+    pub indices: Vec<u32>,
 }
 
 pub struct DeferredLightSystem<R: gfx::Resources> {
     event_queue: alewife::Subscriber<event::EventID, event::Event>,
     fxaa_enabled: bool,
     terrain: Bundle<R, terrain::Data<R>>,
+    displaced: Option<DisplacedTerrain<R>>,
+    // Whether the terrain vertex buffer holds the mesh, and whether the
+    // displaced terrain is drawn in its place
+    has_terrain_mesh: bool,
+    draw_displaced: bool,
+    terrain_benchmark: Option<TerrainBenchmark>,
+    terrain_chunks: Vec<TerrainChunk>,
+    chunk_bundles: Vec<Bundle<R, terrain::Data<R>>>,
+    planet: Option<Planet>,
     skybox: rendering::skybox::Skybox<R>,
+    water: Water<R>,
+    scatter: Scatter<R>,
+    roads: Roads<R>,
     blit: Bundle<R, blit::Data<R>>,
     fxaa: Bundle<R, fxaa::Data<R>>,
+    depth_copy: Bundle<R, depth_copy::Data<R>>,
     light: Bundle<R, light::Data<R>>,
+    clustered: Bundle<R, clustered::Data<R>>,
+    clusters: LightClusters<R>,
+    benchmark: Option<LightBenchmark>,
+    ambient: Bundle<R, ambient::Data<R>>,
+    ambient_color: [f32; 4],
+    sun: Bundle<R, sun::Data<R>>,
+    sun_light: DirectionalLight,
+    shadows: ShadowMaps<R>,
+    point_shadows: PointShadows<R>,
+    spot: Bundle<R, spot::Data<R>>,
+    spot_lights: Vec<SpotLight>,
+    spot_info: Vec<SpotInfo>,
     emitter: Bundle<R, emitter::Data<R>>,
     intermediate: ViewPair<R, GFormat>,
-    light_pos: Vec<LightInfo>,
-    depth_resource: gfx::handle::ShaderResourceView<R, [f32; 4]>,
-    debug_buf: Option<gfx::handle::ShaderResourceView<R, [f32; 4]>>,
+    bloom: Bloom<R>,
+    tonemap: ToneMapper<R>,
+    lights: Vec<PointLight>,
+    light_info: Vec<LightInfo>,
+    light_settings: LightSettings,
+    heightmap: Heightmap,
+    terrain_dirty: bool,
+    terrain_layers: TerrainLayers,
+    biomes: Option<BiomeMap>,
+    occlusion_settings: OcclusionSettings,
+    terrain_occlusion: Vec<[f32; 2]>,
+    // Lowest height and the range above it, see `terrain_range`
+    terrain_range: (f32, f32),
+    base_heights: Vec<f32>,
+    sculptor: Sculptor,
+    // Console paths, for loading and exporting alike, are relative to it,
+    // like the ones in the config
+    asset_dir: PathBuf,
+    viewport: (f32, f32),
+    last_time: f32,
+    erosion_settings: erosion::ErosionSettings,
+    erode_on_regen: bool,
+    // Erosion started from the console, run a slice every frame
+    erosion: Option<erosion::Erosion>,
+    gbuffer_debug: Bundle<R, gbuffer_debug::Data<R>>,
+    debug_view: Option<DebugView>,
     inverse_tex_size: [f32; 3],
 }
 
+// Pipeline for a light volume, drawing only the back faces, see
+// `BEHIND_SCENE`.
+fn create_volume_pipeline<R, F, I>(factory: &mut F,
+                                   vs: &[u8],
+                                   fs: &[u8],
+                                   init: I)
+                                   -> gfx::PipelineState<R, I::Meta>
+    where R: gfx::Resources,
+          F: gfx::Factory<R>,
+          I: gfx::pso::PipelineInit
+{
+    let mut rasterizer = gfx::state::Rasterizer::new_fill();
+    rasterizer.cull_face = gfx::state::CullFace::Front;
+
+    let shaders = factory.create_shader_set(vs, fs).unwrap();
+    factory.create_pipeline_state(&shaders, gfx::Primitive::TriangleList, rasterizer, init)
+        .unwrap()
+}
+
+// A UV sphere around the origin that encloses the unit sphere, so no part
+// of a light is cut off by the flat faces.
+fn sphere_mesh(segments: usize, rings: usize) -> (Vec<SphereVertex>, Vec<u16>) {
+    let sphere = SphereUV::new(segments, rings);
+    let scale = 1.0 / ((PI / segments as f32).cos() * (PI / (2 * rings) as f32).cos());
+
+    let vertex_data: Vec<SphereVertex> = sphere.shared_vertex_iter()
+        .map(|(x, y, z)| SphereVertex { pos: [scale * x, scale * y, scale * z] })
+        .collect();
+
+    // Wound counter clockwise seen from outside, whatever genmesh does
+    let mut index_data: Vec<u16> = Vec::new();
+    for t in sphere.indexed_polygon_iter().triangulate() {
+        let p = |i: usize| {
+            let v = vertex_data[i].pos;
+            Vector3::new(v[0], v[1], v[2])
+        };
+        let (a, b, c) = (p(t.x), p(t.y), p(t.z));
+        if (b - a).cross(&(c - a)).dot(&(a + b + c)) >= 0.0 {
+            index_data.extend_from_slice(&[t.x as u16, t.y as u16, t.z as u16]);
+        } else {
+            index_data.extend_from_slice(&[t.x as u16, t.z as u16, t.y as u16]);
+        }
+    }
+
+    (vertex_data, index_data)
+}
+
+// A cone with its tip at the origin that opens along z, with a base of
+// radius one at z = 1. The base ring is pushed out a little so the flat
+// sides enclose the round cone.
+fn cone_mesh(sides: usize) -> (Vec<ConeVertex>, Vec<u16>) {
+    let ring = 1.0 / (PI / sides as f32).cos();
+
+    // Tip, then the center of the base
+    let mut vertices = vec![ConeVertex { pos: [0.0, 0.0, 0.0] },
+                            ConeVertex { pos: [0.0, 0.0, 1.0] }];
+    for i in 0..sides {
+        let a = 2.0 * PI * i as f32 / sides as f32;
+        vertices.push(ConeVertex { pos: [ring * a.cos(), ring * a.sin(), 1.0] });
+    }
+
+    // Counter clockwise seen from outside
+    let mut indices = Vec::with_capacity(sides * 6);
+    for i in 0..sides {
+        let (a, b) = (2 + i as u16, 2 + ((i + 1) % sides) as u16);
+        indices.extend_from_slice(&[0, b, a, 1, a, b]);
+    }
+
+    (vertices, indices)
+}
+
 fn create_g_buffer<R: gfx::Resources, F: gfx::Factory<R>>(target_width: texture::Size,
                                                           target_height: texture::Size,
                                                           factory: &mut F)
-                                                          -> (ViewPair<R, GFormat>,
-                                                              ViewPair<R, GFormat>,
-                                                              ViewPair<R, GFormat>,
+                                                          -> (ViewPair<R, NormalFormat>,
+                                                              ViewPair<R, AlbedoFormat>,
+                                                              ViewPair<R, MaterialFormat>,
                                                               gfx::handle::ShaderResourceView<R,
                                                                                               [f32;
                                                                                                4]>,
@@ -441,7 +1176,7 @@ fn create_g_buffer<R: gfx::Resources, F: gfx::Factory<R>>(target_width: texture:
                                                                                             Depth>) {
     use gfx::format::ChannelSource;
 
-    let pos = {
+    let normal = {
         let (_, srv, rtv) = factory.create_render_target(target_width, target_height)
             .unwrap();
         ViewPair {
@@ -450,7 +1185,7 @@ fn create_g_buffer<R: gfx::Resources, F: gfx::Factory<R>>(target_width: texture:
         }
     };
 
-    let normal = {
+    let diffuse = {
         let (_, srv, rtv) = factory.create_render_target(target_width, target_height)
             .unwrap();
         ViewPair {
@@ -459,7 +1194,7 @@ fn create_g_buffer<R: gfx::Resources, F: gfx::Factory<R>>(target_width: texture:
         }
     };
 
-    let diffuse = {
+    let material = {
         let (_, srv, rtv) = factory.create_render_target(target_width, target_height)
             .unwrap();
         ViewPair {
@@ -477,7 +1212,13 @@ fn create_g_buffer<R: gfx::Resources, F: gfx::Factory<R>>(target_width: texture:
     let depth_srv = factory.view_texture_as_shader_resource::<DepthFormat>(&tex, (0, 0), swizzle)
         .unwrap();
 
-    (pos, normal, diffuse, depth_srv, depth_rtv)
+    let pixels = target_width as usize * target_height as usize;
+    info!(target: "DAT205",
+          "G-buffer uses {:.1} MB, {:.1} MB with float targets",
+          (pixels * GBUFFER_BYTES) as f32 / (1024.0 * 1024.0),
+          (pixels * GBUFFER_BYTES_FLOAT) as f32 / (1024.0 * 1024.0));
+
+    (normal, diffuse, material, depth_srv, depth_rtv)
 }
 
 impl<R: gfx::Resources> DeferredLightSystem<R> {
@@ -485,15 +1226,22 @@ impl<R: gfx::Resources> DeferredLightSystem<R> {
                                    factory: &mut F,
                                    target_width: u16,
                                    target_height: u16,
-                                   seed: &noise::PermutationTable,
+                                   heightmap: Heightmap,
+                                   erosion_settings: erosion::ErosionSettings,
+                                   terrain_layers: TerrainLayers,
+                                   biomes: Option<BiomeMap>,
+                                   scatter_rules: Vec<ScatterRule>,
+                                   config: &Config,
+                                   asset_dir: &Path,
                                    main_color: gfx::handle::RenderTargetView<R, ColorFormat>)
                                    -> Self {
         use gfx::traits::FactoryExt;
 
         info!(target: "DAT205", "Loading lighting system...");
 
-        let (gpos, gnormal, gdiffuse, depth_resource, depth_target) =
+        let (gnormal, gdiffuse, gmaterial, depth_resource, depth_target) =
             create_g_buffer(target_width, target_height, factory);
+        let gbuffer_locals = factory.create_constant_buffer(1);
 
         let res = {
             let (_, srv, rtv) = factory.create_render_target(target_width, target_height).unwrap();
@@ -503,50 +1251,125 @@ impl<R: gfx::Resources> DeferredLightSystem<R> {
             }
         };
 
+        let bloom = Bloom::new(factory,
+                               BloomSettings::from_config(config),
+                               target_width,
+                               target_height,
+                               res.resource.clone());
+
+        let tonemap = ToneMapper::new(factory,
+                                      ToneMapSettings::from_config(config),
+                                      target_width,
+                                      target_height,
+                                      res.resource.clone(),
+                                      bloom.output().clone(),
+                                      depth_resource.clone());
+
         let sampler = factory.create_sampler(texture::SamplerInfo::new(texture::FilterMethod::Scale,
                                                       texture::WrapMode::Clamp));
 
-        let terrain = {
-            let plane = Plane::subdivide(256, 256);
-            let vertex_data: Vec<TerrainVertex> = plane.shared_vertex_iter()
-                .map(|(x, z)| {
-                    let y = TERRAIN_SCALE[2] * perlin2(seed, &[x, z]);
-                    TerrainVertex {
-                        pos: [TERRAIN_SCALE[0] * x, y, TERRAIN_SCALE[1] * z],
-                        normal: calculate_normal(seed, x, z),
-                        color: calculate_color(y),
-                    }
-                })
-                .collect();
+        // Occlusion is baked on the first frame, along with the first upload
+        // of the terrain vertices
+        let occlusion_settings = OcclusionSettings::from_config(config);
+        let terrain_occlusion = vec![occlusion::UNOCCLUDED; heightmap.width() * heightmap.depth()];
 
-            let index_data: Vec<u32> = plane.indexed_polygon_iter()
-                .triangulate()
-                .vertices()
-                .map(|i| i as u32)
-                .collect();
+        let displaced_mode = config.get_str("terrain.mode") == Some("displaced");
+        // Volumes and planets are drawn from chunks, see `set_terrain_chunks`
+        let chunked_mode = match config.get_str("terrain.mode") {
+            Some("volume") | Some("planet") => true,
+            _ => false,
+        };
+        // Keeping both the mesh and the displaced terrain lets the benchmark
+        // compare them
+        let keep_both = config.get("terrain.benchmark", false);
+        let has_terrain_mesh = !chunked_mode && (!displaced_mode || keep_both);
 
-            let (vbuf, slice) =
-                factory.create_vertex_buffer_with_slice(&vertex_data, &index_data[..]);
+        let terrain = {
+            use gfx::IntoIndexBuffer;
+
+            // Chunks replace the heightfield, which only needs a placeholder
+            let (vertex_data, index_data) = if chunked_mode {
+                (Vec::new(), vec![0, 0, 0])
+            } else {
+                terrain_mesh(&heightmap, &terrain_layers, biomes.as_ref(), &terrain_occlusion)
+            };
+
+            // The vertices are uploaded on the first frame, and again whenever
+            // the heightmap changes. Displaced terrain never draws them, its
+            // buffer is only a placeholder unless both are kept.
+            let vertex_count = if has_terrain_mesh { vertex_data.len() } else { 1 };
+            let vbuf = factory.create_buffer(vertex_count,
+                               gfx::buffer::Role::Vertex,
+                               gfx::memory::Usage::Dynamic,
+                               gfx::Bind::empty())
+                .unwrap();
+
+            let slice = gfx::Slice {
+                start: 0,
+                end: index_data.len() as u32,
+                base_vertex: 0,
+                instances: None,
+                buffer: index_data[..].into_index_buffer(factory),
+            };
 
             let pso = factory.create_pipeline_simple(TERRAIN_VERTEX_SHADER,
-                                        TERRAIN_FRAGMENT_SHADER,
+                                        &with_normal_encoding(TERRAIN_FRAGMENT_SHADER),
                                         terrain::new())
                 .unwrap();
 
+            let layer_sampler = factory.create_sampler(
+                texture::SamplerInfo::new(texture::FilterMethod::Bilinear,
+                                          texture::WrapMode::Tile));
+
             let data = terrain::Data {
                 vbuf: vbuf,
                 locals: factory.create_constant_buffer(1),
-                out_position: gpos.target.clone(),
+                material: factory.create_constant_buffer(1),
+                layers: (terrain_layers.create_texture_array(factory).unwrap(), layer_sampler),
                 out_normal: gnormal.target.clone(),
                 out_color: gdiffuse.target.clone(),
+                out_material: gmaterial.target.clone(),
                 out_depth: depth_target.clone(),
             };
 
             Bundle::new(slice, pso, data)
         };
 
+        let displaced = if displaced_mode || keep_both {
+            Some(DisplacedTerrain::new(factory, &heightmap, terrain.slice.clone(), &terrain.data))
+        } else {
+            None
+        };
+
         let skybox = rendering::skybox::Skybox::new(factory, terrain.data.out_color.clone());
 
+        let scatter = Scatter::new(factory,
+                                   scatter_rules,
+                                   gnormal.target.clone(),
+                                   gdiffuse.target.clone(),
+                                   gmaterial.target.clone(),
+                                   depth_target.clone());
+
+        let roads = Roads::new(factory,
+                               RoadSettings::from_config(config),
+                               gnormal.target.clone(),
+                               gdiffuse.target.clone(),
+                               gmaterial.target.clone(),
+                               depth_target.clone());
+
+        let water = Water::new(factory,
+                               config,
+                               target_width,
+                               target_height,
+                               heightmap.horizontal_scale(),
+                               depth_resource.clone(),
+                               res.resource.clone(),
+                               if has_terrain_mesh {
+                                   Some((terrain.data.vbuf.clone(), terrain.slice.clone()))
+                               } else {
+                                   None
+                               });
+
         let blit = {
             let vertex_data = [BlitVertex { pos_tex: [-3, -1, -1, 0] },
                                BlitVertex { pos_tex: [1, -1, 1, 0] },
@@ -561,7 +1384,7 @@ impl<R: gfx::Resources> DeferredLightSystem<R> {
             let data = blit::Data {
                 vbuf: vbuf,
                 //     locals: factory.create_constant_buffer(1),
-                tex: (gpos.resource.clone(), sampler.clone()),
+                tex: (tonemap.output().clone(), sampler.clone()),
                 out: main_color.clone(),
             };
 
@@ -583,89 +1406,69 @@ impl<R: gfx::Resources> DeferredLightSystem<R> {
             let data = fxaa::Data {
                 vbuf: vbuf,
                 //     locals: factory.create_constant_buffer(1),
-                tex: (gpos.resource.clone(), sampler.clone()),
+                tex: (tonemap.output().clone(), sampler.clone()),
                 out: main_color.clone(),
             };
 
             Bundle::new(slice, pso, data)
         };
 
-        let light_pos_buffer = factory.create_constant_buffer(NUMBER_OF_LIGHTS as usize);
+        let light_settings = LightSettings::from_config(config);
+        let clusters = LightClusters::new(factory, ClusterSettings::from_config(config));
 
-        // TODO: SPHERES
         let (light_vbuf, mut light_slice) = {
+            let (vertex_data, index_data) = sphere_mesh(SPHERE_SEGMENTS, SPHERE_RINGS);
+            factory.create_vertex_buffer_with_slice(&vertex_data, &index_data[..])
+        };
 
-            /* let sphere = SphereUV::new(10, 10);
-            let vertex_data: Vec<CubeVertex> = sphere.shared_vertex_iter()
-                .map(|(x, y, z)| CubeVertex { pos: [x as i8, y as i8, z as i8, 1] })
-                .collect();
+        light_slice.instances = Some((light_settings.count as gfx::InstanceCount, 0));
 
-            let index_data: Vec<u32> = sphere.indexed_polygon_iter()
-                .triangulate()
-                .vertices()
-                .map(|i| i as u32)
-                .collect();
-*/
-            let vertex_data = [// top (0, 0, 1)
-                               CubeVertex { pos: [-1, -1, 1, 1] },
-                               CubeVertex { pos: [1, -1, 1, 1] },
-                               CubeVertex { pos: [1, 1, 1, 1] },
-                               CubeVertex { pos: [-1, 1, 1, 1] },
-                               // bottom (0, 0, -1)
-                               CubeVertex { pos: [-1, 1, -1, 1] },
-                               CubeVertex { pos: [1, 1, -1, 1] },
-                               CubeVertex { pos: [1, -1, -1, 1] },
-                               CubeVertex { pos: [-1, -1, -1, 1] },
-                               // right (1, 0, 0)
-                               CubeVertex { pos: [1, -1, -1, 1] },
-                               CubeVertex { pos: [1, 1, -1, 1] },
-                               CubeVertex { pos: [1, 1, 1, 1] },
-                               CubeVertex { pos: [1, -1, 1, 1] },
-                               // left (-1, 0, 0)
-                               CubeVertex { pos: [-1, -1, 1, 1] },
-                               CubeVertex { pos: [-1, 1, 1, 1] },
-                               CubeVertex { pos: [-1, 1, -1, 1] },
-                               CubeVertex { pos: [-1, -1, -1, 1] },
-                               // front (0, 1, 0)
-                               CubeVertex { pos: [1, 1, -1, 1] },
-                               CubeVertex { pos: [-1, 1, -1, 1] },
-                               CubeVertex { pos: [-1, 1, 1, 1] },
-                               CubeVertex { pos: [1, 1, 1, 1] },
-                               // back (0, -1, 0)
-                               CubeVertex { pos: [1, -1, 1, 1] },
-                               CubeVertex { pos: [-1, -1, 1, 1] },
-                               CubeVertex { pos: [-1, -1, -1, 1] },
-                               CubeVertex { pos: [1, -1, -1, 1] }];
-
-            let index_data: &[u16] = &[
-                 0,  1,  2,  2,  3,  0, // top
-                 4,  5,  6,  6,  7,  4, // bottom
-                 8,  9, 10, 10, 11,  8, // right
-                12, 13, 14, 14, 15, 12, // left
-                16, 17, 18, 18, 19, 16, // front
-                20, 21, 22, 22, 23, 20, // back
-            ];
-
-            factory.create_vertex_buffer_with_slice(&vertex_data, index_data)
-        };
+        let point_shadows = PointShadows::new(factory, PointShadowSettings::from_config(config));
 
-        light_slice.instances = Some((NUMBER_OF_LIGHTS as gfx::InstanceCount, 0));
+        let (depth_copy, depth_copy_resource) = {
+            let vertex_data = [BlitVertex { pos_tex: [-3, -1, -1, 0] },
+                               BlitVertex { pos_tex: [1, -1, 1, 0] },
+                               BlitVertex { pos_tex: [1, 3, 1, 2] }];
 
-        let light = {
-            let pso =
-                factory.create_pipeline_simple(LIGHT_VERTEX_SHADER,
-                                            LIGHT_FRAGMENT_SHADER,
-                                            light::new())
+            let (vbuf, slice) = factory.create_vertex_buffer_with_slice(&vertex_data, ());
+
+            let pso = factory.create_pipeline_simple(BLIT_VERTEX_SHADER,
+                                        DEPTH_COPY_FRAGMENT_SHADER,
+                                        depth_copy::new())
+                .unwrap();
+
+            let (_, srv, rtv) =
+                factory.create_render_target::<DepthCopyFormat>(target_width, target_height)
                     .unwrap();
 
+            let data = depth_copy::Data {
+                vbuf: vbuf,
+                tex_depth: (depth_resource.clone(), sampler.clone()),
+                out: rtv,
+            };
+
+            (Bundle::new(slice, pso, data), srv)
+        };
+
+        let light = {
+            let pso = create_volume_pipeline(factory,
+                                             &with_light_data(LIGHT_VERTEX_SHADER),
+                                             &lights::with_point_lights(&with_gbuffer(
+                                                 LIGHT_FRAGMENT_SHADER)),
+                                             light::new());
+
             let data = light::Data {
                 vbuf: light_vbuf.clone(),
                 locals_vs: factory.create_constant_buffer(1),
                 locals_ps: factory.create_constant_buffer(1),
-                light_pos_buf: light_pos_buffer.clone(),
-                tex_pos: (gpos.resource.clone(), sampler.clone()),
+                light_data: clusters.light_data(),
+                shadow_locals: factory.create_constant_buffer(1),
+                shadow_map: point_shadows.texture(),
+                gbuffer_locals: gbuffer_locals.clone(),
+                tex_depth: (depth_copy_resource.clone(), sampler.clone()),
                 tex_normal: (gnormal.resource.clone(), sampler.clone()),
                 tex_diffuse: (gdiffuse.resource.clone(), sampler.clone()),
+                tex_material: (gmaterial.resource.clone(), sampler.clone()),
                 out_color: res.target.clone(),
                 out_depth: depth_target.clone(),
             };
@@ -673,8 +1476,146 @@ impl<R: gfx::Resources> DeferredLightSystem<R> {
             Bundle::new(light_slice.clone(), pso, data)
         };
 
+        let ambient = {
+            let vertex_data = [BlitVertex { pos_tex: [-3, -1, -1, 0] },
+                               BlitVertex { pos_tex: [1, -1, 1, 0] },
+                               BlitVertex { pos_tex: [1, 3, 1, 2] }];
+
+            let (vbuf, slice) = factory.create_vertex_buffer_with_slice(&vertex_data, ());
+
+            let pso = factory.create_pipeline_simple(BLIT_VERTEX_SHADER,
+                                        &lights::with_brdf(&with_gbuffer(
+                                            AMBIENT_FRAGMENT_SHADER)),
+                                        ambient::new())
+                .unwrap();
+
+            let data = ambient::Data {
+                vbuf: vbuf,
+                locals: factory.create_constant_buffer(1),
+                gbuffer_locals: gbuffer_locals.clone(),
+                tex_depth: (depth_resource.clone(), sampler.clone()),
+                tex_normal: (gnormal.resource.clone(), sampler.clone()),
+                tex_diffuse: (gdiffuse.resource.clone(), sampler.clone()),
+                tex_material: (gmaterial.resource.clone(), sampler.clone()),
+                out_color: res.target.clone(),
+            };
+
+            Bundle::new(slice, pso, data)
+        };
+
+        let gbuffer_debug = {
+            let pso = factory.create_pipeline_simple(BLIT_VERTEX_SHADER,
+                                        &with_gbuffer(GBUFFER_DEBUG_FRAGMENT_SHADER),
+                                        gbuffer_debug::new())
+                .unwrap();
+
+            let data = gbuffer_debug::Data {
+                vbuf: ambient.data.vbuf.clone(),
+                locals: factory.create_constant_buffer(1),
+                gbuffer_locals: gbuffer_locals.clone(),
+                tex_depth: (depth_resource.clone(), sampler.clone()),
+                tex_normal: (gnormal.resource.clone(), sampler.clone()),
+                tex_diffuse: (gdiffuse.resource.clone(), sampler.clone()),
+                tex_material: (gmaterial.resource.clone(), sampler.clone()),
+                out: res.target.clone(),
+            };
+
+            Bundle::new(ambient.slice.clone(), pso, data)
+        };
+
+        let ambient_color = match config.get_floats("lighting.ambient") {
+            Some(ref c) if c.len() == 3 => [c[0], c[1], c[2], 1.0],
+            Some(_) => {
+                warn!(target: "DAT205", "Expected a color for lighting.ambient");
+                [0.0, 0.0, 0.0, 1.0]
+            }
+            None => [0.0, 0.0, 0.0, 1.0],
+        };
+
+        let shadows = ShadowMaps::new(factory, ShadowSettings::from_config(config));
+
+        let sun = {
+            let shader =
+                lights::with_brdf(&shadows::with_shadows(&with_gbuffer(SUN_FRAGMENT_SHADER)));
+            let pso = factory.create_pipeline_simple(BLIT_VERTEX_SHADER, &shader, sun::new())
+                .unwrap();
+
+            let data = sun::Data {
+                vbuf: ambient.data.vbuf.clone(),
+                locals: factory.create_constant_buffer(1),
+                shadow_locals: factory.create_constant_buffer(1),
+                shadow_map: shadows.texture(),
+                gbuffer_locals: gbuffer_locals.clone(),
+                tex_depth: (depth_resource.clone(), sampler.clone()),
+                tex_normal: (gnormal.resource.clone(), sampler.clone()),
+                tex_diffuse: (gdiffuse.resource.clone(), sampler.clone()),
+                tex_material: (gmaterial.resource.clone(), sampler.clone()),
+                out_color: res.target.clone(),
+            };
+
+            Bundle::new(ambient.slice.clone(), pso, data)
+        };
+
+        let clustered = {
+            let pso = factory.create_pipeline_simple(BLIT_VERTEX_SHADER,
+                                        &lights::with_point_lights(&with_gbuffer(
+                                            &with_light_data(CLUSTERED_FRAGMENT_SHADER))),
+                                        clustered::new())
+                .unwrap();
+
+            let data = clustered::Data {
+                vbuf: ambient.data.vbuf.clone(),
+                locals: light.data.locals_ps.clone(),
+                cluster_locals: factory.create_constant_buffer(1),
+                shadow_locals: light.data.shadow_locals.clone(),
+                shadow_map: point_shadows.texture(),
+                light_data: clusters.light_data(),
+                clusters: clusters.clusters(),
+                light_indices: clusters.indices(),
+                gbuffer_locals: gbuffer_locals.clone(),
+                tex_depth: (depth_resource.clone(), sampler.clone()),
+                tex_normal: (gnormal.resource.clone(), sampler.clone()),
+                tex_diffuse: (gdiffuse.resource.clone(), sampler.clone()),
+                tex_material: (gmaterial.resource.clone(), sampler.clone()),
+                out_color: res.target.clone(),
+            };
+
+            Bundle::new(ambient.slice.clone(), pso, data)
+        };
+
+        let spot_lights = lights::spots_from_config(config, MAX_SPOT_LIGHTS);
+
+        let spot = {
+            let (vertex_data, index_data) = cone_mesh(CONE_SIDES);
+            let (vbuf, mut slice) =
+                factory.create_vertex_buffer_with_slice(&vertex_data, &index_data[..]);
+            slice.instances = Some((spot_lights.len() as gfx::InstanceCount, 0));
+
+            let pso = create_volume_pipeline(factory,
+                                             SPOT_VERTEX_SHADER,
+                                             &lights::with_brdf(&with_gbuffer(
+                                                 SPOT_FRAGMENT_SHADER)),
+                                             spot::new());
+
+            let data = spot::Data {
+                vbuf: vbuf,
+                locals_vs: factory.create_constant_buffer(1),
+                locals_ps: factory.create_constant_buffer(1),
+                spot_buf: factory.create_constant_buffer(MAX_SPOT_LIGHTS),
+                gbuffer_locals: gbuffer_locals.clone(),
+                tex_depth: (depth_copy_resource.clone(), sampler.clone()),
+                tex_normal: (gnormal.resource.clone(), sampler.clone()),
+                tex_diffuse: (gdiffuse.resource.clone(), sampler.clone()),
+                tex_material: (gmaterial.resource.clone(), sampler.clone()),
+                out_color: res.target.clone(),
+                out_depth: depth_target.clone(),
+            };
+
+            Bundle::new(slice, pso, data)
+        };
+
         let emitter = {
-            let pso = factory.create_pipeline_simple(EMITTER_VERTEX_SHADER,
+            let pso = factory.create_pipeline_simple(&with_light_data(EMITTER_VERTEX_SHADER),
                                         EMITTER_FRAGMENT_SHADER,
                                         emitter::new())
                 .unwrap();
@@ -682,7 +1623,7 @@ impl<R: gfx::Resources> DeferredLightSystem<R> {
             let data = emitter::Data {
                 vbuf: light_vbuf.clone(),
                 locals: factory.create_constant_buffer(1),
-                light_pos_buf: light_pos_buffer.clone(),
+                light_data: clusters.light_data(),
                 out_color: res.target.clone(),
                 out_depth: depth_target.clone(),
             };
@@ -690,34 +1631,334 @@ impl<R: gfx::Resources> DeferredLightSystem<R> {
             Bundle::new(light_slice, pso, data)
         };
 
+        let lights = light_settings.lights(light_settings.count);
+
+        let range = terrain_range(&heightmap);
+        let base_heights = heightmap.heights().to_vec();
+
         info!(target: "DAT205", "Done!");
 
         DeferredLightSystem {
             event_queue: e_que,
             fxaa_enabled: true,
             skybox: skybox,
+            water: water,
+            scatter: scatter,
+            roads: roads,
             terrain: terrain,
+            displaced: displaced,
+            has_terrain_mesh: has_terrain_mesh,
+            draw_displaced: displaced_mode,
+            terrain_benchmark: None,
+            terrain_chunks: Vec::new(),
+            chunk_bundles: Vec::new(),
+            planet: None,
             blit: blit,
             fxaa: fxaa,
-            debug_buf: None,
+            depth_copy: depth_copy,
+            gbuffer_debug: gbuffer_debug,
+            debug_view: None,
             light: light,
+            clustered: clustered,
+            clusters: clusters,
+            benchmark: None,
+            ambient: ambient,
+            ambient_color: ambient_color,
+            sun: sun,
+            sun_light: lights::sun_from_config(config),
+            shadows: shadows,
+            point_shadows: point_shadows,
+            spot: spot,
+            spot_info: spot_lights.iter().map(|l| l.info()).collect(),
+            spot_lights: spot_lights,
             emitter: emitter,
             intermediate: res,
-            light_pos: (0..NUMBER_OF_LIGHTS)
-                .map(|_| LightInfo { pos: [0.0, 0.0, 0.0, 0.0] })
-                .collect(),
-            depth_resource: depth_resource,
+            bloom: bloom,
+            tonemap: tonemap,
+            light_info: lights.iter().map(|l| l.info()).collect(),
+            lights: lights,
+            light_settings: light_settings,
+            heightmap: heightmap,
+            terrain_dirty: true,
+            terrain_layers: terrain_layers,
+            biomes: biomes,
+            occlusion_settings: occlusion_settings,
+            terrain_occlusion: terrain_occlusion,
+            terrain_range: range,
+            base_heights: base_heights,
+            sculptor: Sculptor::new(),
+            asset_dir: asset_dir.to_path_buf(),
+            viewport: (target_width as f32, target_height as f32),
+            last_time: 0.0,
+            erosion_settings: erosion_settings,
+            erode_on_regen: config.get("erosion.enabled", false),
+            erosion: None,
             inverse_tex_size: [1.0 / target_width as f32, 1.0 / target_height as f32, 0.0],
         }
     }
 
+    pub fn process_input(&mut self, event: &glutin::Event) {
+        if !self.is_heightfield() {
+            return;
+        }
+
+        // Clicks place road points while a road is laid out
+        if self.roads.is_placing() {
+            self.roads.process_input(event);
+        } else {
+            self.sculptor.process_input(event);
+        }
+    }
+
+    /// Whether the heightfield is what is drawn. Volumes and planets are
+    /// drawn with chunks and only keep it for lights and the water, so
+    /// edits to it would not show.
+    fn is_heightfield(&self) -> bool {
+        self.terrain_chunks.is_empty()
+    }
+
+    /// Replace the point lights with `count` new ones, at most `MAX_LIGHTS`.
+    pub fn set_light_count(&mut self, count: usize) {
+        let count = count.min(MAX_LIGHTS);
+        self.lights = self.light_settings.lights(count);
+        self.light_info = self.lights.iter().map(|l| l.info()).collect();
+        self.light.slice.instances = Some((count as gfx::InstanceCount, 0));
+        self.emitter.slice.instances = Some((count as gfx::InstanceCount, 0));
+    }
+
+    // Decode one part of the G-buffer into the intermediate target, in place
+    // of the lighting.
+    fn show_debug_view<C: gfx::CommandBuffer<R>>(&mut self,
+                                                 encoder: &mut gfx::Encoder<R, C>,
+                                                 view: DebugView) {
+        let locals = DebugLocals { view: [view as i32 as f32, 0.0, 0.0, 0.0] };
+        encoder.update_constant_buffer(&self.gbuffer_debug.data.locals, &locals);
+        self.gbuffer_debug.encode(encoder);
+    }
+
+    // Meshes that cast shadows. Displaced terrain has no mesh to render and
+    // casts none, unless the mesh is kept for the benchmark.
+    fn shadow_casters(&self) -> Vec<(&gfx::handle::Buffer<R, TerrainVertex>, &gfx::Slice<R>)> {
+        if !self.chunk_bundles.is_empty() {
+            self.chunk_bundles.iter().map(|c| (&c.data.vbuf, &c.slice)).collect()
+        } else if self.has_terrain_mesh {
+            vec![(&self.terrain.data.vbuf, &self.terrain.slice)]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Draw meshes, like a volume or a planet, instead of the heightfield.
+    /// Each chunk gets its own buffers but shares the pipeline and everything
+    /// else with the heightfield terrain, so it ends up in the same G-buffer.
+    /// Water is turned off.
+    pub fn set_terrain_chunks<F: gfx::Factory<R>>(&mut self,
+                                                  factory: &mut F,
+                                                  chunks: Vec<TerrainChunk>) {
+        use gfx::traits::FactoryExt;
+
+        self.chunk_bundles = chunks.iter()
+            .map(|chunk| {
+                let (vbuf, slice) =
+                    factory.create_vertex_buffer_with_slice(&chunk.vertices, &chunk.indices[..]);
+                let data = terrain::Data { vbuf: vbuf, ..self.terrain.data.clone() };
+                Bundle::new(slice, self.terrain.pso.clone(), data)
+            })
+            .collect();
+
+        // The water reflects the heightfield, which would not match the
+        // chunks, so it is turned off
+        if self.water.is_enabled() {
+            self.water.toggle();
+        }
+
+        self.terrain_chunks = chunks;
+    }
+
+    /// Draw a planet instead of the heightfield. Lights are spread over its
+    /// surface, and like with any chunks the water plane is turned off.
+    pub fn set_planet<F: gfx::Factory<R>>(&mut self, factory: &mut F, planet: Planet) {
+        let chunks = planet.mesh(&self.terrain_layers);
+        self.set_terrain_chunks(factory, chunks);
+        self.planet = Some(planet);
+    }
+
+    /// Reset the terrain to how it was generated and apply saved edits to it.
+    pub fn apply_terrain_edits(&mut self, edits: &TerrainEdits) -> Result<(), String> {
+        if !self.is_heightfield() {
+            return Err("Terrain edits only apply to heightfield terrain".to_owned());
+        }
+
+        let mut heightmap = self.heightmap.clone();
+        heightmap.heights_mut().copy_from_slice(&self.base_heights);
+        try!(edits.apply(&mut heightmap));
+
+        self.heightmap = heightmap;
+        self.roads.set_roads(edits.roads.clone());
+        self.sculptor.clear_history();
+        self.terrain_dirty = true;
+        info!(target: "DAT205", "Applied {} terrain edits", edits.deltas.len());
+        Ok(())
+    }
+
+    /// Seed the current terrain was generated from, None for loaded
+    /// heightmaps.
+    pub fn terrain_seed(&self) -> Option<u32> {
+        self.heightmap.seed()
+    }
+
+    /// Replace the terrain with one generated from a new seed. The grid keeps
+    /// its size, so the vertex buffer is refilled on the next frame and the
+    /// index buffer stays as it is. Sculpting history is dropped since it
+    /// belongs to the old surface.
+    pub fn regenerate_terrain(&mut self, seed: u32) {
+        if !self.is_heightfield() {
+            warn!(target: "DAT205", "Only heightfield terrain can be regenerated at runtime");
+            return;
+        }
+
+        info!(target: "DAT205", "Generating terrain with seed {}...", seed);
+
+        let mut heightmap = Heightmap::from_noise_sized(seed,
+                                                        self.heightmap.width(),
+                                                        self.heightmap.depth(),
+                                                        self.heightmap.horizontal_scale(),
+                                                        self.heightmap.vertical_scale());
+
+        if let Some(ref mut biomes) = self.biomes {
+            biomes.reseed(seed);
+            biomes.shape(&mut heightmap);
+        }
+
+        if self.erode_on_regen {
+            erosion::erode(&mut heightmap, &self.erosion_settings);
+        }
+
+        // Roads stay where they are and flatten the new terrain, which counts
+        // as an edit
+        self.base_heights = heightmap.heights().to_vec();
+        for road in self.roads.roads() {
+            road.flatten(&mut heightmap);
+        }
+
+        self.heightmap = heightmap;
+        self.sculptor.clear_history();
+        // Erosion still running from the console would go on with the new
+        // terrain
+        self.erosion = None;
+        self.terrain_dirty = true;
+        info!(target: "DAT205", "Done!");
+    }
+
+    // Like a new terrain, eroded terrain is the base that edits are stored
+    // against. Undo history from before would bring back heights that were
+    // eroded away, and the roads flatten it again.
+    fn finish_erosion(&mut self) {
+        self.base_heights = self.heightmap.heights().to_vec();
+        for road in self.roads.roads() {
+            road.flatten(&mut self.heightmap);
+        }
+
+        self.sculptor.clear_history();
+        self.terrain_dirty = true;
+        info!(target: "DAT205", "Done!");
+    }
+
+    /// The terrain mesh and the scattered objects as they are right now.
+    pub fn export_objects(&self) -> Vec<export::ExportObject> {
+        let terrain_object = |name: String, vertex_data: &[TerrainVertex], index_data: Vec<u32>| {
+            export::ExportObject {
+                mesh: export::ExportMesh {
+                    name: name,
+                    positions: vertex_data.iter().map(|v| v.pos).collect(),
+                    normals: vertex_data.iter().map(|v| v.normal).collect(),
+                    colors: vertex_data.iter().map(|v| v.color).collect(),
+                    indices: index_data,
+                },
+                instances: vec![export::ExportTransform::identity()],
+            }
+        };
+
+        let mut objects = if self.terrain_chunks.is_empty() {
+            let (vertex_data, index_data) = terrain_mesh(&self.heightmap,
+                                                         &self.terrain_layers,
+                                                         self.biomes.as_ref(),
+                                                         &self.terrain_occlusion);
+            vec![terrain_object("terrain".to_owned(), &vertex_data, index_data)]
+        } else {
+            self.terrain_chunks
+                .iter()
+                .enumerate()
+                .map(|(i, c)| {
+                    terrain_object(format!("terrain_{}", i), &c.vertices, c.indices.clone())
+                })
+                .collect()
+        };
+
+        objects.extend(self.scatter.export_objects());
+        objects
+    }
+
+    /// Save the scene as glTF if `path` ends in `.gltf`, otherwise as OBJ.
+    pub fn export_scene<P: AsRef<::std::path::Path>>(&self, path: P) -> Result<(), String> {
+        export::export_scene(path, &self.export_objects())
+    }
+
+    // Update everything that depends on the heights inside a part of the
+    // terrain after it has been edited.
+    fn terrain_region_changed<C: gfx::CommandBuffer<R>>(&mut self,
+                                                        encoder: &mut gfx::Encoder<R, C>,
+                                                        rect: GridRect) {
+        // Normals of the samples next to the edit change too
+        let rect = rect.grow(1, &self.heightmap);
+        occlusion::bake_region(&self.heightmap,
+                               &self.occlusion_settings,
+                               rect,
+                               &mut self.terrain_occlusion);
+        self.update_terrain_region(encoder, rect);
+        self.scatter.snap_to_surface(&self.heightmap);
+        self.roads.terrain_changed();
+    }
+
+    // Re-upload the vertices of part of the terrain, one row at a time, or
+    // the texels under it for displaced terrain.
+    fn update_terrain_region<C: gfx::CommandBuffer<R>>(&mut self,
+                                                       encoder: &mut gfx::Encoder<R, C>,
+                                                       rect: GridRect) {
+        let w = self.heightmap.width();
+        let mut region = Vec::new();
+        for iz in rect.z0..rect.z1 + 1 {
+            let row: Vec<TerrainVertex> = (rect.x0..rect.x1 + 1)
+                .map(|ix| {
+                    terrain_vertex(&self.heightmap,
+                                   &self.terrain_layers,
+                                   self.biomes.as_ref(),
+                                   &self.terrain_occlusion,
+                                   self.terrain_range,
+                                   ix,
+                                   iz)
+                })
+                .collect();
+            if self.has_terrain_mesh {
+                encoder.update_buffer(&self.terrain.data.vbuf, &row, iz * w + rect.x0).unwrap();
+            }
+            if self.displaced.is_some() {
+                region.extend(row);
+            }
+        }
+
+        if let Some(ref mut displaced) = self.displaced {
+            displaced.update(encoder, &self.heightmap, rect, &region);
+        }
+    }
+
     pub fn render<C: gfx::CommandBuffer<R>>(&mut self,
                                             time: f32,
-                                            seed: &noise::PermutationTable,
                                             encoder: &mut gfx::Encoder<R, C>,
                                             cam: &rendering::camera::Camera) {
 
         let events: Vec<_> = self.event_queue.fetch();
+        let mut finished_road = None;
 
         for event in events {
             match event {
@@ -725,30 +1966,303 @@ impl<R: gfx::Resources> DeferredLightSystem<R> {
                     self.fxaa_enabled = !self.fxaa_enabled;
                     info!(target: "DAT205", "FXAA state changed to {}", self.fxaa_enabled);
                 }
+                (_, event::Event::ToggleBloom) => {
+                    self.bloom.toggle();
+                    info!(target: "DAT205",
+                          "Bloom state changed to {}",
+                          self.bloom.settings().enabled);
+                }
+                (_, event::Event::SetBloomIntensity(intensity)) => {
+                    self.bloom.set_intensity(intensity);
+                    info!(target: "DAT205",
+                          "Bloom intensity set to {}",
+                          self.bloom.settings().intensity);
+                }
+                (_, event::Event::SetBloomThreshold(threshold)) => {
+                    self.bloom.set_threshold(threshold);
+                    info!(target: "DAT205",
+                          "Bloom threshold set to {}",
+                          self.bloom.settings().threshold);
+                }
+                (_, event::Event::SetToneMapOperator(op)) => {
+                    self.tonemap.set_operator(op);
+                    info!(target: "DAT205", "Tone mapping with {}", op.name());
+                }
+                (_, event::Event::SetExposure(stops)) => {
+                    self.tonemap.set_exposure(stops);
+                    info!(target: "DAT205", "Exposure set to {} stops", stops);
+                }
+                (_, event::Event::ToggleAutoExposure) => {
+                    self.tonemap.toggle_auto_exposure();
+                    info!(target: "DAT205",
+                          "Auto exposure state changed to {}",
+                          self.tonemap.settings().auto_exposure);
+                }
+                (_, event::Event::SetExposureKey(key)) => {
+                    self.tonemap.set_key(key);
+                    info!(target: "DAT205", "Exposure key set to {}", self.tonemap.settings().key);
+                }
+                (_, event::Event::SetAdaptationSpeed(speed)) => {
+                    self.tonemap.set_adaptation_speed(speed);
+                    info!(target: "DAT205",
+                          "Adaptation speed set to {}",
+                          self.tonemap.settings().adaptation_speed);
+                }
                 (_, event::Event::DebugShowLightBuffer) => {
-                    self.debug_buf = Some(self.light.data.tex_pos.0.clone());
-                    info!(target: "DAT205", "Showing light buffer only");
+                    self.debug_view = Some(DebugView::Position);
+                    info!(target: "DAT205", "Showing reconstructed positions only");
                 }
                 (_, event::Event::DebugShowNormalBuffer) => {
-                    self.debug_buf = Some(self.light.data.tex_normal.0.clone());
+                    self.debug_view = Some(DebugView::Normal);
                     info!(target: "DAT205", "Showing normal buffer only");
                 }
                 (_, event::Event::DebugShowDiffuseBuffer) => {
-                    self.debug_buf = Some(self.light.data.tex_diffuse.0.clone());
+                    self.debug_view = Some(DebugView::Diffuse);
                     info!(target: "DAT205", "Showing diffuse buffer only");
                 }
+                (_, event::Event::DebugShowMaterialBuffer) => {
+                    self.debug_view = Some(DebugView::Material);
+                    info!(target: "DAT205", "Showing material buffer only");
+                }
                 (_, event::Event::DebugShowDepthBuffer) => {
-                    self.debug_buf = Some(self.depth_resource.clone());
+                    self.debug_view = Some(DebugView::Depth);
                     info!(target: "DAT205", "Showing depth buffer only");
                 }
+                (_, event::Event::DebugShowCascades) => {
+                    self.shadows.toggle_debug();
+                    info!(target: "DAT205", "Toggled shadow cascade colors");
+                }
                 (_, event::Event::DebugOff) => {
-                    self.debug_buf = None;
+                    self.debug_view = None;
+                    self.shadows.set_debug(false);
                     info!(target: "DAT205", "Debug turned off");
                 }
+                (_, event::Event::ExportHeightmap(path)) => {
+                    if let Err(e) = self.heightmap.save_png_16(self.asset_dir.join(path)) {
+                        error!(target: "DAT205", "{}", e);
+                    }
+                }
+                (_, event::Event::ExportNormalMap(path)) => {
+                    if let Err(e) = self.heightmap.save_normal_map(self.asset_dir.join(path)) {
+                        error!(target: "DAT205", "{}", e);
+                    }
+                }
+                (_, event::Event::ToggleWater) if !self.is_heightfield() => {
+                    warn!(target: "DAT205", "Water only works with heightfield terrain");
+                }
+                (_, event::Event::ToggleWater) => {
+                    self.water.toggle();
+                    info!(target: "DAT205", "Water state changed to {}", self.water.is_enabled());
+                }
+                (_, event::Event::SetWaterLevel(level)) => {
+                    self.water.set_level(level);
+                    info!(target: "DAT205", "Water level set to {}", level);
+                }
+                (_, event::Event::SetBrushTool(tool)) => {
+                    self.sculptor.set_tool(tool);
+                    info!(target: "DAT205", "Brush set to {:?}", tool);
+                }
+                (_, event::Event::SetBrushRadius(r)) => self.sculptor.set_radius(r),
+                (_, event::Event::SetBrushStrength(s)) => self.sculptor.set_strength(s),
+                (_, event::Event::SetBrushFalloff(f)) => self.sculptor.set_falloff(f),
+                (_, event::Event::UndoSculpt) |
+                (_, event::Event::RedoSculpt) if !self.is_heightfield() => {
+                    warn!(target: "DAT205", "Only heightfield terrain can be sculpted");
+                }
+                (_, event::Event::UndoSculpt) => self.sculptor.undo(),
+                (_, event::Event::RedoSculpt) => self.sculptor.redo(),
+                (_, event::Event::SaveTerrainEdits(path)) => {
+                    let edits =
+                        TerrainEdits::diff(&self.base_heights, &self.heightmap, self.roads.roads());
+                    if let Err(e) = edits.save(self.asset_dir.join(path)) {
+                        error!(target: "DAT205", "{}", e);
+                    }
+                }
+                (_, event::Event::LoadTerrainEdits(path)) => {
+                    let res = TerrainEdits::load(self.asset_dir.join(path))
+                        .and_then(|e| self.apply_terrain_edits(&e));
+                    if let Err(e) = res {
+                        error!(target: "DAT205", "{}", e);
+                    }
+                }
+                (_, event::Event::RegenerateTerrain(seed)) => {
+                    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+                    self.regenerate_terrain(seed);
+                }
+                (_, event::Event::BeginRoad(width)) => {
+                    if self.is_heightfield() {
+                        self.roads.begin(width);
+                    } else {
+                        warn!(target: "DAT205", "Roads can only be placed on heightfield terrain");
+                    }
+                }
+                (_, event::Event::EndRoad) => finished_road = self.roads.end(),
+                (_, event::Event::ClearRoads) => {
+                    self.roads.clear();
+                    info!(target: "DAT205", "Removed all roads");
+                }
+                (_, event::Event::SetSunDirection(dir)) => {
+                    match self.sun_light.set_direction(dir) {
+                        Ok(_) => info!(target: "DAT205", "Sun direction set to {:?}", dir),
+                        Err(e) => error!(target: "DAT205", "{}", e),
+                    }
+                }
+                (_, event::Event::SetLightingMode(mode)) => {
+                    self.clusters.set_mode(mode);
+                    info!(target: "DAT205", "Lighting mode set to {}", mode.name());
+                }
+                (_, event::Event::SetLightCount(count)) => {
+                    self.set_light_count(count);
+                    info!(target: "DAT205", "Drawing {} point lights", self.lights.len());
+                }
+                (_, event::Event::BenchmarkLights) => {
+                    info!(target: "DAT205", "Benchmarking point lights...");
+                    let previous = (self.clusters.mode(), self.lights.len());
+                    self.benchmark = Some(LightBenchmark::new(&BENCHMARK_LIGHTS, previous));
+                }
+                (_, event::Event::BenchmarkTerrain) => {
+                    if self.is_heightfield() && self.has_terrain_mesh && self.displaced.is_some() {
+                        info!(target: "DAT205", "Benchmarking terrain...");
+                        self.terrain_benchmark =
+                            Some(TerrainBenchmark::new(&self.heightmap, self.draw_displaced));
+                    } else {
+                        warn!(target: "DAT205",
+                              "Comparing the terrain modes needs heightfield terrain and \
+                               terrain.benchmark = true");
+                    }
+                }
+                (_, event::Event::SetSunColor(color)) => self.sun_light.color = color,
+                (_, event::Event::SetSunIntensity(i)) => self.sun_light.intensity = i.max(0.0),
+                (_, event::Event::ExportObj(path)) => {
+                    let objects = self.export_objects();
+                    if let Err(e) = export::write_obj(self.asset_dir.join(path), &objects) {
+                        error!(target: "DAT205", "{}", e);
+                    }
+                }
+                (_, event::Event::ExportGltf(path)) => {
+                    let objects = self.export_objects();
+                    if let Err(e) = export::write_gltf(self.asset_dir.join(path), &objects) {
+                        error!(target: "DAT205", "{}", e);
+                    }
+                }
+                (_, event::Event::ExportTree(preset, path, seed)) => {
+                    let res = lsystem::export_tree(self.asset_dir.join(preset),
+                                                   self.asset_dir.join(path),
+                                                   seed.unwrap_or(1));
+                    if let Err(e) = res {
+                        error!(target: "DAT205", "{}", e);
+                    }
+                }
+                (_, event::Event::ErodeTerrain(_)) if !self.is_heightfield() => {
+                    warn!(target: "DAT205", "Only heightfield terrain can be eroded at runtime");
+                }
+                (_, event::Event::ErodeTerrain(overrides)) => {
+                    let mut settings = self.erosion_settings.clone();
+                    let parsed: Result<Vec<_>, _> = overrides.iter()
+                        .map(|&(ref name, value)| settings.set(name, value))
+                        .collect();
+
+                    match parsed {
+                        Ok(_) if self.erosion.is_some() => {
+                            warn!(target: "DAT205", "The terrain is already being eroded");
+                        }
+                        Ok(_) => {
+                            info!(target: "DAT205", "Eroding terrain...");
+                            self.erosion = Some(erosion::Erosion::new(&self.heightmap, &settings));
+                        }
+                        Err(e) => error!(target: "DAT205", "{}", e),
+                    }
+                }
                 _ => {}
             }
         }
 
+        // The benchmark picks the setup of each frame until it is done, then
+        // goes back to the one from before
+        let assign_time = self.clusters.assign_time();
+        let setup = self.benchmark.as_mut().map(|b| (b.next_frame(assign_time), b.previous()));
+        if let Some((next, previous)) = setup {
+            let (mode, count) = next.unwrap_or(previous);
+            if next.is_none() {
+                self.benchmark = None;
+            }
+            self.clusters.set_mode(mode);
+            if count != self.lights.len() {
+                self.set_light_count(count);
+            }
+        }
+
+        let displaced_setup =
+            self.terrain_benchmark.as_mut().map(|b| (b.next_frame(), b.previous()));
+        if let Some((next, previous)) = displaced_setup {
+            if next.is_none() {
+                self.terrain_benchmark = None;
+            }
+            self.draw_displaced = next.unwrap_or(previous);
+        }
+
+        let dt = (time - self.last_time).max(0.0).min(0.1);
+        self.last_time = time;
+
+        let eroded = match self.erosion {
+            Some(ref mut erosion) => erosion.step(&mut self.heightmap, EROSION_DROPLETS_PER_FRAME),
+            None => false,
+        };
+        if eroded {
+            self.erosion = None;
+            self.finish_erosion();
+        }
+
+        let history = self.sculptor.has_pending_history();
+        if let Some(mut rect) = self.sculptor.update(&mut self.heightmap, cam, self.viewport, dt) {
+            // Undo and redo bring back heights from before a road was laid
+            // or flattened over, so the roads flatten them again
+            if history {
+                for road in self.roads.roads() {
+                    if let Some(flat) = road.flatten(&mut self.heightmap) {
+                        rect = rect.union(&flat);
+                    }
+                }
+            }
+            self.terrain_region_changed(encoder, rect);
+        }
+
+        let placed = self.roads.update(&self.heightmap, cam, self.viewport);
+        if let Some(road) = placed.or(finished_road) {
+            if let Some(rect) = road.flatten(&mut self.heightmap) {
+                self.terrain_region_changed(encoder, rect);
+            }
+        }
+
+        if self.terrain_dirty {
+            self.terrain_range = terrain_range(&self.heightmap);
+            // Chunked terrain is not drawn from the heightmap, so there is no
+            // use in baking or meshing it
+            if self.terrain_chunks.is_empty() {
+                self.terrain_occlusion = occlusion::bake(&self.heightmap, &self.occlusion_settings);
+                let (vertex_data, _) = terrain_mesh(&self.heightmap,
+                                                    &self.terrain_layers,
+                                                    self.biomes.as_ref(),
+                                                    &self.terrain_occlusion);
+                if let Some(ref mut displaced) = self.displaced {
+                    let all = GridRect::all(&self.heightmap);
+                    displaced.update(encoder, &self.heightmap, all, &vertex_data);
+                }
+                if self.has_terrain_mesh {
+                    encoder.update_buffer(&self.terrain.data.vbuf, &vertex_data, 0).unwrap();
+                }
+            }
+            self.scatter.replace(&self.heightmap, self.biomes.as_ref());
+            self.roads.terrain_changed();
+            let material = TerrainMaterial {
+                tiling: self.terrain_layers.tiling(),
+                roughness: self.terrain_layers.roughness(),
+                metallic: self.terrain_layers.metallic(),
+            };
+            encoder.update_constant_buffer(&self.terrain.data.material, &material);
+            self.terrain_dirty = false;
+        }
+
         let cam_pos = cam.get_eye();
         let view_proj: [[f32; 4]; 4] = cam.get_view_proj().into();
 
@@ -758,81 +2272,189 @@ impl<R: gfx::Resources> DeferredLightSystem<R> {
         };
         encoder.update_constant_buffer(&self.terrain.data.locals, &terrain_locals);
 
-        let light_locals = LightLocals {
-            cam_pos_and_radius: [cam_pos.x,
-                                 cam_pos.y,
-                                 cam_pos.z,
-                                 1.0 / (LIGHT_RADIUS * LIGHT_RADIUS)],
-        };
+        let gbuffer_locals = GBufferLocals { inv_view_proj: cam.get_inv_view_proj().into() };
+        encoder.update_constant_buffer(&self.ambient.data.gbuffer_locals, &gbuffer_locals);
+
+        let light_locals = LightLocals { cam_pos: [cam_pos.x, cam_pos.y, cam_pos.z, 1.0] };
         encoder.update_buffer(&self.light.data.locals_ps, &[light_locals], 0).unwrap();
+        let ambient_locals = AmbientLocals {
+            color: self.ambient_color,
+            cam_pos: [cam_pos.x, cam_pos.y, cam_pos.z, 1.0],
+        };
+        encoder.update_constant_buffer(&self.ambient.data.locals, &ambient_locals);
 
-        let mut cube_locals = CubeLocals {
+        // The light volumes are scaled by the radius of each light
+        let volume_locals = VolumeLocals {
             transform: view_proj.clone(),
-            radius: LIGHT_RADIUS,
+            radius: EMITTER_RADIUS,
         };
-        encoder.update_constant_buffer(&self.light.data.locals_vs, &cube_locals);
-        cube_locals.radius = EMITTER_RADIUS;
-        encoder.update_constant_buffer(&self.emitter.data.locals, &cube_locals);
+        encoder.update_constant_buffer(&self.light.data.locals_vs, &volume_locals);
+        encoder.update_constant_buffer(&self.emitter.data.locals, &volume_locals);
+        encoder.update_constant_buffer(&self.spot.data.locals_vs, &volume_locals);
+        encoder.update_constant_buffer(&self.spot.data.locals_ps, &light_locals);
+
+        let sun_locals = self.sun_light.locals([cam_pos.x, cam_pos.y, cam_pos.z]);
+        encoder.update_constant_buffer(&self.sun.data.locals, &sun_locals);
+
+        if self.sun_light.intensity > 0.0 {
+            self.shadows.update(cam, self.sun_light.direction);
+            self.shadows.render(encoder, &self.shadow_casters());
+            encoder.update_constant_buffer(&self.sun.data.shadow_locals, &self.shadows.locals());
+        }
 
         // Update light positions
-        for (i, d) in self.light_pos.iter_mut().enumerate() {
+        let scale = self.heightmap.horizontal_scale();
+        let light_count = self.lights.len() as f32;
+        for (i, light) in self.lights.iter_mut().enumerate() {
+            if let Some(ref planet) = self.planet {
+                // Spread evenly over the sphere along a golden angle spiral
+                let y = 1.0 - 2.0 * (i as f32 + 0.5) / light_count;
+                let r = (1.0 - y * y).sqrt();
+                let theta = 2.39996 * i as f32;
+                let dir = Vector3::new(r * theta.cos(), y, r * theta.sin());
+                let p = dir * (planet.surface_radius(&dir) + 5.0 * time.cos() + 5.0);
+
+                light.position = [p.x, p.y, p.z];
+                continue;
+            }
+
             let (x, z) = {
                 let fi = i as f32;
-                let r = 1.0 - (fi * fi) / ((NUMBER_OF_LIGHTS * NUMBER_OF_LIGHTS) as f32);
+                let r = 1.0 - (fi * fi) / (light_count * light_count);
                 (r * (0.2 + i as f32).cos(), r * (0.2 + i as f32).sin())
             };
-            let y = perlin2(seed, &[x, z]);
+            let y = self.heightmap.sample(scale * x, scale * z);
+
+            light.position = [scale * x, y + 5.0 * time.cos() + 5.0, scale * z];
+        }
+        for (info, light) in self.light_info.iter_mut().zip(self.lights.iter()) {
+            *info = light.info();
+        }
+
+        let forward = (cam.at() - cam_pos).normalize();
+        let shadowed = self.point_shadows.select(&self.lights, &cam_pos, &forward);
+        for (slot, &i) in shadowed.iter().enumerate() {
+            self.light_info[i].falloff[1] = slot as f32;
+        }
+        self.point_shadows.render(encoder, &self.shadow_casters());
+        encoder.update_constant_buffer(&self.light.data.shadow_locals,
+                                       &self.point_shadows.locals());
+        self.clusters.update_lights(encoder, &self.light_info);
+        if self.clusters.mode() == LightingMode::Clustered {
+            self.clusters.assign(encoder, cam, &self.light_info);
+            encoder.update_constant_buffer(&self.clustered.data.cluster_locals,
+                                           &self.clusters.locals(cam, self.viewport));
+        }
+
+        // Spot lights hang above the terrain and sweep around
+        let spot_count = self.spot_lights.len();
+        for (i, spot) in self.spot_lights.iter_mut().enumerate() {
+            let a = 2.0 * PI * i as f32 / spot_count as f32;
+
+            if let Some(ref planet) = self.planet {
+                let dir = Vector3::new(a.cos(), 0.5 * (a + 0.3 * time).sin(), a.sin()).normalize();
+                let p = dir * (planet.surface_radius(&dir) + 25.0);
+
+                spot.position = [p.x, p.y, p.z];
+                spot.direction = [-dir.x, -dir.y, -dir.z];
+                continue;
+            }
 
-            d.pos[0] = TERRAIN_SCALE[0] * x;
-            d.pos[1] = TERRAIN_SCALE[1] * y + 5.0 * time.cos() + 5.0;
-            d.pos[2] = TERRAIN_SCALE[2] * z;
+            let (x, z) = (0.35 * scale * a.cos(), 0.35 * scale * a.sin());
+            let sweep = a + 0.5 * time;
+            let dir = Vector3::new(0.5 * sweep.cos(), -1.0, 0.5 * sweep.sin()).normalize();
+
+            spot.position = [x, self.heightmap.sample(x, z) + 25.0, z];
+            spot.direction = [dir.x, dir.y, dir.z];
+        }
+        for (info, spot) in self.spot_info.iter_mut().zip(self.spot_lights.iter()) {
+            *info = spot.info();
+        }
+        if !self.spot_info.is_empty() {
+            encoder.update_buffer(&self.spot.data.spot_buf, &self.spot_info, 0).unwrap();
         }
-        encoder.update_buffer(&self.light.data.light_pos_buf, &self.light_pos, 0).unwrap();
 
         encoder.clear_depth(&self.terrain.data.out_depth, 1.0);
-        encoder.clear(&self.terrain.data.out_position, [0.0, 0.0, 0.0, 1.0]);
-        encoder.clear(&self.terrain.data.out_normal, [0.0, 0.0, 0.0, 1.0]);
+        encoder.clear(&self.terrain.data.out_normal, [0.0, 0.0, 1.0, 1.0]);
         encoder.clear(&self.terrain.data.out_color, [0.0, 0.0, 0.0, 1.0]);
+        encoder.clear(&self.terrain.data.out_material, [1.0, 0.0, 0.0, 0.0]);
 
         if let Some(inv) = cam.get_proj_matrix().try_inverse() {
             self.skybox.render(encoder, inv.into(), cam.get_view_matrix().into());
         }
 
-        self.terrain.encode(encoder);
+        if !self.chunk_bundles.is_empty() {
+            for chunk in &self.chunk_bundles {
+                chunk.encode(encoder);
+            }
+        } else {
+            match self.displaced {
+                Some(ref displaced) if self.draw_displaced => displaced.render(encoder),
+                _ => self.terrain.encode(encoder),
+            }
+        }
+        self.scatter.render(encoder, view_proj, cam_pos);
+        if self.chunk_bundles.is_empty() {
+            self.roads.render(encoder, &self.heightmap, view_proj);
+        }
 
-        if self.fxaa_enabled {
-            let fxaa_tex = match self.debug_buf {
-                Some(ref tex) => tex, 
-                None => {
-                    encoder.clear(&self.intermediate.target, [0.0, 0.0, 0.0, 1.0]);
+        self.water.render_reflection(encoder, cam, &mut self.skybox);
 
-                    // Apply lights
-                    self.light.encode(encoder);
+        // Debug views are shown as they are, without tone mapping
+        let output = match self.debug_view {
+            Some(view) => {
+                self.show_debug_view(encoder, view);
+                self.intermediate.resource.clone()
+            }
+            None => {
+                encoder.clear(&self.intermediate.target, [0.0, 0.0, 0.0, 1.0]);
 
-                    // Draw light emitters
-                    self.emitter.encode(encoder);
-                    &self.intermediate.resource
-                }
-            };
+                // Light from the sky
+                self.ambient.encode(encoder);
 
-            self.fxaa.data.tex.0 = fxaa_tex.clone();
-            self.fxaa.encode(encoder);
-        } else {
-            let blit_tex = match self.debug_buf {
-                Some(ref tex) => tex,  
-                None => {
-                    encoder.clear(&self.intermediate.target, [0.0, 0.0, 0.0, 1.0]);
+                // Light from the sun
+                if self.sun_light.intensity > 0.0 {
+                    self.sun.encode(encoder);
+                }
 
-                    // Apply lights
-                    self.light.encode(encoder);
+                // The light volumes test against the depth buffer, so they
+                // read positions from a copy of it
+                let volumes = self.clusters.mode() == LightingMode::Volumes;
+                if volumes || !self.spot_lights.is_empty() {
+                    self.depth_copy.encode(encoder);
+                }
 
-                    // Draw light emitters
-                    self.emitter.encode(encoder);
-                    &self.intermediate.resource
+                // Apply lights
+                match self.clusters.mode() {
+                    LightingMode::Volumes => self.light.encode(encoder),
+                    LightingMode::Clustered => self.clustered.encode(encoder),
+                }
+                if !self.spot_lights.is_empty() {
+                    self.spot.encode(encoder);
                 }
-            };
 
-            self.blit.data.tex.0 = blit_tex.clone();
+                // Draw light emitters
+                self.emitter.encode(encoder);
+
+                let hdr = if self.water.is_enabled() {
+                    self.water.render(encoder, cam, time);
+                    self.water.output().clone()
+                } else {
+                    self.intermediate.resource.clone()
+                };
+
+                // Bloom is added before tone mapping
+                self.bloom.render(encoder, &hdr);
+                self.tonemap.render(encoder, &hdr, self.bloom.strength(), dt);
+                self.tonemap.output().clone()
+            }
+        };
+
+        if self.fxaa_enabled {
+            self.fxaa.data.tex.0 = output;
+            self.fxaa.encode(encoder);
+        } else {
+            self.blit.data.tex.0 = output;
             self.blit.encode(encoder);
         }
     }
@@ -0,0 +1,328 @@
+
+use gfx;
+use gfx::{Bundle, format, texture};
+use gfx::format::Rgba8;
+use gfx::traits::FactoryExt;
+
+use rendering::deferred::{terrain, with_normal_encoding, AlbedoFormat, Depth, MaterialFormat,
+                          NormalFormat, TerrainLocals, TerrainMaterial, TerrainVertex,
+                          TERRAIN_FRAGMENT_SHADER};
+use rendering::heightmap::Heightmap;
+use rendering::sculpt::GridRect;
+use support::benchmark::{FrameBenchmark, MEASURED_FRAMES};
+
+type HeightSurface = format::R32;
+type HeightFormat = (format::R32, format::Float);
+type AttributeSurface = format::R8_G8_B8_A8;
+
+gfx_defines!{
+    vertex GridVertex {
+        pos: [u16; 2] = "a_GridPos",
+    }
+
+    constant GridLocals {
+        origin_and_cell: [f32; 4] = "u_GridOrigin",
+    }
+
+    pipeline displaced {
+        vbuf: gfx::VertexBuffer<GridVertex> = (),
+        locals: gfx::ConstantBuffer<TerrainLocals> = "TerrainLocals",
+        material: gfx::ConstantBuffer<TerrainMaterial> = "TerrainMaterial",
+        grid: gfx::ConstantBuffer<GridLocals> = "GridLocals",
+        heights: gfx::TextureSampler<f32> = "t_Height",
+        normals: gfx::TextureSampler<[f32; 4]> = "t_Normal",
+        surface: gfx::TextureSampler<[f32; 4]> = "t_Surface",
+        weights: gfx::TextureSampler<[f32; 4]> = "t_Weights",
+        layers: gfx::TextureSampler<[f32; 4]> = "t_Layers",
+        out_normal: gfx::RenderTarget<NormalFormat> = "Target0",
+        out_color: gfx::RenderTarget<AlbedoFormat> = "Target1",
+        out_material: gfx::RenderTarget<MaterialFormat> = "Target2",
+        out_depth: gfx::DepthTarget<Depth> =
+            gfx::preset::depth::LESS_EQUAL_WRITE,
+    }
+}
+
+// Looks everything up from the textures and hands the fragment shader of the
+// regular terrain the same inputs a mesh vertex would.
+const DISPLACED_VERTEX_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform TerrainLocals {
+        mat4 u_Model;
+        mat4 u_ViewProj;
+    };
+
+    layout(std140)
+    uniform GridLocals {
+        // x and z of the first sample, cell size, unused
+        vec4 u_GridOrigin;
+    };
+
+    uniform sampler2D t_Height;
+    uniform sampler2D t_Normal;
+    uniform sampler2D t_Surface;
+    uniform sampler2D t_Weights;
+
+    in uvec2 a_GridPos;
+
+    out vec3 v_FragPos;
+    out vec3 v_Normal;
+    out vec3 v_Color;
+    out vec4 v_Weights;
+    out vec2 v_Occlusion;
+
+    void main() {
+        ivec2 texel = ivec2(a_GridPos);
+        float height = texelFetch(t_Height, texel, 0).r;
+        vec4 normal = texelFetch(t_Normal, texel, 0);
+        vec4 surface = texelFetch(t_Surface, texel, 0);
+
+        vec2 xz = u_GridOrigin.xy + vec2(a_GridPos) * u_GridOrigin.z;
+        vec4 pos = u_Model * vec4(xz.x, height, xz.y, 1.0);
+
+        v_FragPos = pos.xyz;
+        v_Normal = mat3(u_Model) * (2.0 * normal.xyz - 1.0);
+        v_Color = surface.rgb;
+        v_Weights = texelFetch(t_Weights, texel, 0);
+        v_Occlusion = vec2(normal.w, surface.a);
+        gl_Position = u_ViewProj * pos;
+    }
+";
+
+/// A 2D texture that is updated from the CPU, with a view to sample it.
+pub fn create_texture<T, F, R>(factory: &mut F,
+                               width: usize,
+                               depth: usize)
+                               -> (gfx::handle::Texture<R, T::Surface>,
+                                   gfx::handle::ShaderResourceView<R, T::View>)
+    where F: gfx::Factory<R>,
+          R: gfx::Resources,
+          T: format::TextureFormat
+{
+    use gfx::memory::{Usage, SHADER_RESOURCE};
+    use gfx_core::memory::Typed;
+
+    let desc = texture::Info {
+        kind: texture::Kind::D2(width as texture::Size,
+                                depth as texture::Size,
+                                texture::AaMode::Single),
+        levels: 1,
+        format: <T::Surface as format::SurfaceTyped>::get_surface_type(),
+        bind: SHADER_RESOURCE,
+        usage: Usage::Dynamic,
+    };
+    let cty = <T::Channel as format::ChannelTyped>::get_channel_type();
+    let raw = factory.create_texture_raw(desc, Some(cty), None).unwrap();
+    let tex = Typed::new(raw);
+    let view = factory.view_texture_as_shader_resource::<T>(&tex, (0, 0), format::Swizzle::new())
+        .unwrap();
+    (tex, view)
+}
+
+fn unorm(v: f32) -> u8 {
+    (v.max(0.0).min(1.0) * 255.0 + 0.5) as u8
+}
+
+/// Bytes of GPU memory the terrain vertices take with and without
+/// displacement, indices left out since both use the same.
+pub fn memory_use(hm: &Heightmap) -> (usize, usize) {
+    use std::mem::size_of;
+
+    let samples = hm.width() * hm.depth();
+    // A height, and normals, colors and weights at 8 bits per channel
+    let texels = size_of::<f32>() + 3 * size_of::<[u8; 4]>();
+    (samples * size_of::<TerrainVertex>(), samples * (size_of::<GridVertex>() + texels))
+}
+
+/// Heightfield terrain drawn from one grid of vertices that only hold their
+/// grid coordinates. Heights, normals, colors and layer weights live in
+/// textures, so editing the terrain means updating texels instead of
+/// rebuilding the mesh.
+pub struct DisplacedTerrain<R: gfx::Resources> {
+    heights: gfx::handle::Texture<R, HeightSurface>,
+    normals: gfx::handle::Texture<R, AttributeSurface>,
+    surface: gfx::handle::Texture<R, AttributeSurface>,
+    weights: gfx::handle::Texture<R, AttributeSurface>,
+    bundle: Bundle<R, displaced::Data<R>>,
+    width: usize,
+}
+
+impl<R: gfx::Resources> DisplacedTerrain<R> {
+    /// Share the index buffer, constants, layer textures and targets of the
+    /// regular terrain. The textures are filled by `update`.
+    pub fn new<F: gfx::Factory<R>>(factory: &mut F,
+                                   hm: &Heightmap,
+                                   slice: gfx::Slice<R>,
+                                   template: &terrain::Data<R>)
+                                   -> Self {
+        let (width, depth) = (hm.width(), hm.depth());
+
+        let grid: Vec<GridVertex> = (0..width * depth)
+            .map(|i| GridVertex { pos: [(i % width) as u16, (i / width) as u16] })
+            .collect();
+        let vbuf = factory.create_vertex_buffer(&grid);
+
+        let pso = factory.create_pipeline_simple(DISPLACED_VERTEX_SHADER,
+                                    &with_normal_encoding(TERRAIN_FRAGMENT_SHADER),
+                                    displaced::new())
+            .unwrap();
+
+        let (heights, heights_view) = create_texture::<HeightFormat, F, R>(factory, width, depth);
+        let (normals, normals_view) = create_texture::<Rgba8, F, R>(factory, width, depth);
+        let (surface, surface_view) = create_texture::<Rgba8, F, R>(factory, width, depth);
+        let (weights, weights_view) = create_texture::<Rgba8, F, R>(factory, width, depth);
+
+        // Every sample is read at its exact texel, no filtering needed
+        let sampler = factory.create_sampler(texture::SamplerInfo::new(texture::FilterMethod::Scale,
+                                                      texture::WrapMode::Clamp));
+
+        let grid_locals = factory.create_constant_buffer(1);
+
+        let data = displaced::Data {
+            vbuf: vbuf,
+            locals: template.locals.clone(),
+            material: template.material.clone(),
+            grid: grid_locals,
+            heights: (heights_view, sampler.clone()),
+            normals: (normals_view, sampler.clone()),
+            surface: (surface_view, sampler.clone()),
+            weights: (weights_view, sampler),
+            layers: template.layers.clone(),
+            out_normal: template.out_normal.clone(),
+            out_color: template.out_color.clone(),
+            out_material: template.out_material.clone(),
+            out_depth: template.out_depth.clone(),
+        };
+
+        let (mesh_bytes, displaced_bytes) = memory_use(hm);
+        info!(target: "DAT205",
+              "Displaced terrain uses {:.1} MB of vertex data, {:.1} MB as a mesh",
+              displaced_bytes as f32 / (1024.0 * 1024.0),
+              mesh_bytes as f32 / (1024.0 * 1024.0));
+
+        DisplacedTerrain {
+            heights: heights,
+            normals: normals,
+            surface: surface,
+            weights: weights,
+            bundle: Bundle::new(slice, pso, data),
+            width: width,
+        }
+    }
+
+    /// Upload the samples inside `rect`, given in row order. The grid
+    /// position of the first sample is taken from the rect as well.
+    pub fn update<C: gfx::CommandBuffer<R>>(&mut self,
+                                            encoder: &mut gfx::Encoder<R, C>,
+                                            hm: &Heightmap,
+                                            rect: GridRect,
+                                            vertices: &[TerrainVertex]) {
+        debug_assert_eq!(hm.width(), self.width);
+
+        let heights: Vec<f32> = vertices.iter().map(|v| v.pos[1]).collect();
+        let normals: Vec<[u8; 4]> = vertices.iter()
+            .map(|v| {
+                [unorm(0.5 * v.normal[0] + 0.5),
+                 unorm(0.5 * v.normal[1] + 0.5),
+                 unorm(0.5 * v.normal[2] + 0.5),
+                 unorm(v.occlusion[0])]
+            })
+            .collect();
+        let surface: Vec<[u8; 4]> = vertices.iter()
+            .map(|v| {
+                [unorm(v.color[0]),
+                 unorm(v.color[1]),
+                 unorm(v.color[2]),
+                 unorm(v.occlusion[1])]
+            })
+            .collect();
+        let weights: Vec<[u8; 4]> = vertices.iter()
+            .map(|v| {
+                [unorm(v.weights[0]),
+                 unorm(v.weights[1]),
+                 unorm(v.weights[2]),
+                 unorm(v.weights[3])]
+            })
+            .collect();
+
+        let info = texture::ImageInfoCommon {
+            xoffset: rect.x0 as u16,
+            yoffset: rect.z0 as u16,
+            zoffset: 0,
+            width: (rect.x1 - rect.x0 + 1) as u16,
+            height: (rect.z1 - rect.z0 + 1) as u16,
+            depth: 0,
+            format: (),
+            mipmap: 0,
+        };
+
+        encoder.update_texture::<HeightSurface, HeightFormat>(&self.heights,
+                                                              None,
+                                                              info,
+                                                              gfx::memory::cast_slice(&heights))
+            .unwrap();
+        for &(tex, data) in &[(&self.normals, &normals),
+                              (&self.surface, &surface),
+                              (&self.weights, &weights)] {
+            encoder.update_texture::<AttributeSurface, Rgba8>(tex, None, info, data).unwrap();
+        }
+
+        let p = hm.position(0, 0);
+        let grid = GridLocals { origin_and_cell: [p[0], p[2], hm.cell_size(), 0.0] };
+        encoder.update_constant_buffer(&self.bundle.data.grid, &grid);
+    }
+
+    pub fn render<C: gfx::CommandBuffer<R>>(&self, encoder: &mut gfx::Encoder<R, C>) {
+        self.bundle.encode(encoder);
+    }
+}
+
+/// Compares the frame time of drawing the terrain as a mesh and displaced,
+/// see `FrameBenchmark`.
+pub struct TerrainBenchmark {
+    // Whether each run draws the terrain displaced
+    frames: FrameBenchmark<bool>,
+    memory: (usize, usize),
+}
+
+impl TerrainBenchmark {
+    pub fn new(hm: &Heightmap, previous: bool) -> TerrainBenchmark {
+        TerrainBenchmark {
+            frames: FrameBenchmark::new(vec![false, true], previous),
+            memory: memory_use(hm),
+        }
+    }
+
+    /// Count a frame. Returns whether to draw the next frame displaced, or
+    /// None once both modes have been measured, after logging the results.
+    pub fn next_frame(&mut self) -> Option<bool> {
+        let next = self.frames.next_frame(0.0);
+        if next.is_none() {
+            self.log();
+        }
+        next
+    }
+
+    pub fn previous(&self) -> bool {
+        self.frames.previous()
+    }
+
+    fn log(&self) {
+        let mb = |bytes: usize| bytes as f32 / (1024.0 * 1024.0);
+
+        info!(target: "DAT205", "Terrain benchmark, average over {} frames:", MEASURED_FRAMES);
+        for &(displaced, frame_ms, _) in self.frames.results() {
+            let (name, bytes) = if displaced {
+                ("displaced", self.memory.1)
+            } else {
+                ("mesh", self.memory.0)
+            };
+            info!(target: "DAT205",
+                  "  {:>9}: {:7.2} ms/frame, {:.1} MB of vertex data",
+                  name,
+                  frame_ms,
+                  mb(bytes));
+        }
+    }
+}
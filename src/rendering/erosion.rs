@@ -0,0 +1,422 @@
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use rendering::heightmap::Heightmap;
+use support::config::Config;
+
+#[derive(Debug, Clone)]
+pub struct ErosionSettings {
+    pub seed: u32,
+    // Hydraulic erosion
+    pub droplets: usize,
+    pub lifetime: usize,
+    pub rain_rate: f32,
+    pub inertia: f32,
+    pub capacity: f32,
+    pub min_slope: f32,
+    pub erosion_rate: f32,
+    pub deposition_rate: f32,
+    pub evaporation: f32,
+    pub gravity: f32,
+    // Thermal weathering
+    pub thermal_iterations: usize,
+    pub talus_angle: f32,
+    pub thermal_rate: f32,
+}
+
+impl ErosionSettings {
+    pub fn new() -> ErosionSettings {
+        ErosionSettings {
+            seed: 1,
+            droplets: 70000,
+            lifetime: 40,
+            rain_rate: 1.0,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_slope: 0.01,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+            evaporation: 0.02,
+            gravity: 4.0,
+            thermal_iterations: 30,
+            talus_angle: 40.0,
+            thermal_rate: 0.5,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> ErosionSettings {
+        let d = ErosionSettings::new();
+        ErosionSettings {
+            seed: config.get("erosion.seed", d.seed),
+            droplets: config.get("erosion.droplets", d.droplets),
+            lifetime: config.get("erosion.lifetime", d.lifetime),
+            rain_rate: config.get("erosion.rain_rate", d.rain_rate),
+            inertia: config.get("erosion.inertia", d.inertia),
+            capacity: config.get("erosion.capacity", d.capacity),
+            min_slope: config.get("erosion.min_slope", d.min_slope),
+            erosion_rate: config.get("erosion.erosion_rate", d.erosion_rate),
+            deposition_rate: config.get("erosion.deposition_rate", d.deposition_rate),
+            evaporation: config.get("erosion.evaporation", d.evaporation),
+            gravity: config.get("erosion.gravity", d.gravity),
+            thermal_iterations: config.get("erosion.thermal_iterations", d.thermal_iterations),
+            talus_angle: config.get("erosion.talus_angle", d.talus_angle),
+            thermal_rate: config.get("erosion.thermal_rate", d.thermal_rate),
+        }
+    }
+
+    /// Override a single parameter by the name used in the config file,
+    /// without the `erosion.` prefix.
+    pub fn set(&mut self, name: &str, value: f32) -> Result<(), String> {
+        match name {
+            "seed" => self.seed = value as u32,
+            "droplets" => self.droplets = value as usize,
+            "lifetime" => self.lifetime = value as usize,
+            "rain_rate" => self.rain_rate = value,
+            "inertia" => self.inertia = value,
+            "capacity" => self.capacity = value,
+            "min_slope" => self.min_slope = value,
+            "erosion_rate" => self.erosion_rate = value,
+            "deposition_rate" => self.deposition_rate = value,
+            "evaporation" => self.evaporation = value,
+            "gravity" => self.gravity = value,
+            "thermal_iterations" => self.thermal_iterations = value as usize,
+            "talus_angle" => self.talus_angle = value,
+            "thermal_rate" => self.thermal_rate = value,
+            _ => return Err(format!("Unknown erosion parameter: {}", name)),
+        }
+        Ok(())
+    }
+}
+
+/// Run hydraulic erosion followed by thermal weathering.
+pub fn erode(heightmap: &mut Heightmap, settings: &ErosionSettings) {
+    hydraulic(heightmap, settings);
+    thermal(heightmap, settings);
+}
+
+// Reports progress to the console in steps of 10%.
+struct Progress {
+    name: &'static str,
+    total: usize,
+    reported: usize,
+}
+
+impl Progress {
+    fn new(name: &'static str, total: usize) -> Progress {
+        Progress {
+            name: name,
+            total: total,
+            reported: 0,
+        }
+    }
+
+    fn update(&mut self, done: usize) {
+        let percent = if self.total == 0 { 100 } else { 100 * done / self.total };
+        if percent >= self.reported + 10 {
+            self.reported = percent - percent % 10;
+            info!(target: "DAT205", "{}: {}%", self.name, self.reported);
+        }
+    }
+}
+
+// Height and gradient at a continuous grid position, both bilinearly
+// interpolated from the four surrounding samples.
+fn height_and_gradient(hm: &Heightmap, x: f32, z: f32) -> (f32, f32, f32) {
+    let (ix, iz) = (x as usize, z as usize);
+    let (u, v) = (x - ix as f32, z - iz as f32);
+
+    let h00 = hm.height(ix, iz);
+    let h10 = hm.height(ix + 1, iz);
+    let h01 = hm.height(ix, iz + 1);
+    let h11 = hm.height(ix + 1, iz + 1);
+
+    let gx = (h10 - h00) * (1.0 - v) + (h11 - h01) * v;
+    let gz = (h01 - h00) * (1.0 - u) + (h11 - h10) * u;
+    let h = h00 * (1.0 - u) * (1.0 - v) + h10 * u * (1.0 - v) + h01 * (1.0 - u) * v +
+            h11 * u * v;
+
+    (h, gx, gz)
+}
+
+// Add `amount` to the four samples around a grid position, weighted by
+// their bilinear weights.
+fn deposit(hm: &mut Heightmap, x: f32, z: f32, amount: f32) {
+    let (ix, iz) = (x as usize, z as usize);
+    let (u, v) = (x - ix as f32, z - iz as f32);
+
+    let corners = [(ix, iz, (1.0 - u) * (1.0 - v)),
+                   (ix + 1, iz, u * (1.0 - v)),
+                   (ix, iz + 1, (1.0 - u) * v),
+                   (ix + 1, iz + 1, u * v)];
+
+    for &(cx, cz, w) in corners.iter() {
+        let h = hm.height(cx, cz);
+        hm.set_height(cx, cz, h + amount * w);
+    }
+}
+
+// Random number generator the droplets start from
+fn droplet_rng(s: &ErosionSettings) -> XorShiftRng {
+    XorShiftRng::from_seed([s.seed, s.seed ^ 0x9e37_79b9, 0x243f_6a88, 0x85a3_08d3])
+}
+
+/// Particle based hydraulic erosion. Each droplet flows downhill, picks up
+/// sediment while it is moving fast and has capacity left, and drops it
+/// again when it slows down or climbs.
+pub fn hydraulic(hm: &mut Heightmap, s: &ErosionSettings) {
+    if hm.width() < 3 || hm.depth() < 3 {
+        return;
+    }
+
+    let mut rng = droplet_rng(s);
+    let mut progress = Progress::new("Hydraulic erosion", s.droplets);
+
+    for i in 0..s.droplets {
+        droplet(hm, s, &mut rng);
+        progress.update(i + 1);
+    }
+}
+
+// Flow a single droplet from a random point until it stops, evaporates or
+// leaves the map. The map must be at least 3x3.
+fn droplet(hm: &mut Heightmap, s: &ErosionSettings, rng: &mut XorShiftRng) {
+    // Heights are stored in world units, but the simulation walks the grid
+    // one cell at a time.
+    let cell = hm.cell_size();
+    let (max_x, max_z) = ((hm.width() - 1) as f32, (hm.depth() - 1) as f32);
+
+    let mut x = rng.gen_range(0.0, max_x);
+    let mut z = rng.gen_range(0.0, max_z);
+    let (mut dir_x, mut dir_z) = (0.0f32, 0.0f32);
+    let mut speed = 1.0f32;
+    let mut water = s.rain_rate;
+    let mut sediment = 0.0f32;
+
+    for _ in 0..s.lifetime {
+        let (h, gx, gz) = height_and_gradient(hm, x, z);
+
+        dir_x = dir_x * s.inertia - gx * (1.0 - s.inertia);
+        dir_z = dir_z * s.inertia - gz * (1.0 - s.inertia);
+        let len = (dir_x * dir_x + dir_z * dir_z).sqrt();
+        if len < 1.0e-6 {
+            break;
+        }
+        dir_x /= len;
+        dir_z /= len;
+
+        let (nx, nz) = (x + dir_x, z + dir_z);
+        if nx < 0.0 || nz < 0.0 || nx >= max_x || nz >= max_z {
+            break;
+        }
+
+        let (new_h, _, _) = height_and_gradient(hm, nx, nz);
+        let dh = new_h - h;
+
+        let capacity = (-dh / cell).max(s.min_slope) * speed * water * s.capacity;
+
+        if dh > 0.0 || sediment > capacity {
+            // Fill the pit we are climbing out of, or drop what we
+            // cannot carry any more.
+            let amount = if dh > 0.0 {
+                dh.min(sediment)
+            } else {
+                (sediment - capacity) * s.deposition_rate
+            };
+            sediment -= amount;
+            deposit(hm, x, z, amount);
+        } else {
+            // Never dig deeper than the height difference, or the
+            // droplet carves holes that it then gets stuck in.
+            let amount = ((capacity - sediment) * s.erosion_rate).min(-dh);
+            sediment += amount;
+            deposit(hm, x, z, -amount);
+        }
+
+        speed = (speed * speed - dh / cell * s.gravity).max(0.0).sqrt();
+        water *= 1.0 - s.evaporation;
+        x = nx;
+        z = nz;
+    }
+}
+
+/// Thermal weathering. Material on slopes steeper than the talus angle
+/// slides down to the lower neighbours.
+pub fn thermal(hm: &mut Heightmap, s: &ErosionSettings) {
+    let mut progress = Progress::new("Thermal erosion", s.thermal_iterations);
+    let mut delta = vec![0.0f32; hm.width() * hm.depth()];
+
+    for i in 0..s.thermal_iterations {
+        thermal_iteration(hm, s, &mut delta);
+        progress.update(i + 1);
+    }
+}
+
+// One pass of thermal weathering over the whole map. `delta` is scratch
+// space of one value per sample.
+fn thermal_iteration(hm: &mut Heightmap, s: &ErosionSettings, delta: &mut [f32]) {
+    let (w, d) = (hm.width(), hm.depth());
+    let cell = hm.cell_size();
+    let talus = s.talus_angle.to_radians().tan();
+
+    let neighbours: [(isize, isize, f32); 8] = [(-1, 0, 1.0),
+                                                (1, 0, 1.0),
+                                                (0, -1, 1.0),
+                                                (0, 1, 1.0),
+                                                (-1, -1, 2.0f32.sqrt()),
+                                                (1, -1, 2.0f32.sqrt()),
+                                                (-1, 1, 2.0f32.sqrt()),
+                                                (1, 1, 2.0f32.sqrt())];
+
+    for v in delta.iter_mut() {
+        *v = 0.0;
+    }
+
+    // Collect all transfers first so the result does not depend on the
+    // order the cells are visited in.
+    for iz in 0..d {
+        for ix in 0..w {
+            let h = hm.height(ix, iz);
+
+            let mut excess = [0.0f32; 8];
+            let mut total = 0.0;
+            let mut max_excess = 0.0f32;
+
+            for (n, &(ox, oz, dist)) in neighbours.iter().enumerate() {
+                let nx = ix as isize + ox;
+                let nz = iz as isize + oz;
+                if nx < 0 || nz < 0 || nx >= w as isize || nz >= d as isize {
+                    continue;
+                }
+
+                let diff = h - hm.height(nx as usize, nz as usize);
+                let e = diff - talus * dist * cell;
+                if e > 0.0 {
+                    excess[n] = e;
+                    total += e;
+                    max_excess = max_excess.max(e);
+                }
+            }
+
+            if total <= 0.0 {
+                continue;
+            }
+
+            let moved = 0.5 * s.thermal_rate * max_excess;
+            delta[iz * w + ix] -= moved;
+
+            for (n, &(ox, oz, _)) in neighbours.iter().enumerate() {
+                if excess[n] > 0.0 {
+                    let nx = (ix as isize + ox) as usize;
+                    let nz = (iz as isize + oz) as usize;
+                    delta[nz * w + nx] += moved * excess[n] / total;
+                }
+            }
+        }
+    }
+
+    for (h, dv) in hm.heights_mut().iter_mut().zip(delta.iter()) {
+        *h += *dv;
+    }
+}
+
+/// Erosion run a slice at a time, so it can be spread over frames while
+/// its progress shows up in the console. Gives the same result as `erode`.
+pub struct Erosion {
+    settings: ErosionSettings,
+    rng: XorShiftRng,
+    droplets: usize,
+    iterations: usize,
+    delta: Vec<f32>,
+    hydraulic_progress: Progress,
+    thermal_progress: Progress,
+}
+
+impl Erosion {
+    pub fn new(hm: &Heightmap, settings: &ErosionSettings) -> Erosion {
+        // Too small a map for droplets to flow on skips hydraulic erosion,
+        // like `hydraulic` does
+        let droplets = if hm.width() < 3 || hm.depth() < 3 { settings.droplets } else { 0 };
+
+        Erosion {
+            settings: settings.clone(),
+            rng: droplet_rng(settings),
+            droplets: droplets,
+            iterations: 0,
+            delta: vec![0.0; hm.width() * hm.depth()],
+            hydraulic_progress: Progress::new("Hydraulic erosion", settings.droplets),
+            thermal_progress: Progress::new("Thermal erosion", settings.thermal_iterations),
+        }
+    }
+
+    /// Flow up to `droplets` droplets, or run one pass of thermal weathering
+    /// once they have all flowed. Returns true when the erosion is done.
+    pub fn step(&mut self, hm: &mut Heightmap, droplets: usize) -> bool {
+        let s = &self.settings;
+        if self.droplets < s.droplets {
+            let end = (self.droplets + droplets.max(1)).min(s.droplets);
+            for _ in self.droplets..end {
+                droplet(hm, s, &mut self.rng);
+            }
+            self.droplets = end;
+            self.hydraulic_progress.update(end);
+        } else if self.iterations < s.thermal_iterations {
+            thermal_iteration(hm, s, &mut self.delta);
+            self.iterations += 1;
+            self.thermal_progress.update(self.iterations);
+        }
+
+        self.droplets >= s.droplets && self.iterations >= s.thermal_iterations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rendering::heightmap::Heightmap;
+
+    fn hill() -> Heightmap {
+        let n = 33;
+        let heights = (0..n * n)
+            .map(|i| {
+                let (x, z) = ((i % n) as f32 - 16.0, (i / n) as f32 - 16.0);
+                20.0 - 0.05 * (x * x + z * z)
+            })
+            .collect();
+        Heightmap::from_heights(n, n, 16.0, 20.0, heights)
+    }
+
+    fn small_settings() -> ErosionSettings {
+        let mut s = ErosionSettings::new();
+        s.droplets = 500;
+        s.thermal_iterations = 5;
+        s
+    }
+
+    #[test]
+    fn test_erosion_deterministic() {
+        let (mut a, mut b) = (hill(), hill());
+        erode(&mut a, &small_settings());
+        erode(&mut b, &small_settings());
+        assert_eq!(a.heights(), b.heights());
+        assert!(a.heights() != hill().heights());
+    }
+
+    #[test]
+    fn test_erosion_steps_match_erode() {
+        let (mut a, mut b) = (hill(), hill());
+        erode(&mut a, &small_settings());
+        let mut erosion = Erosion::new(&b, &small_settings());
+        while !erosion.step(&mut b, 64) {}
+        assert_eq!(a.heights(), b.heights());
+    }
+
+    #[test]
+    fn test_erosion_seed_changes_result() {
+        let (mut a, mut b) = (hill(), hill());
+        let mut other = small_settings();
+        other.seed = 7;
+        hydraulic(&mut a, &small_settings());
+        hydraulic(&mut b, &other);
+        assert!(a.heights() != b.heights());
+    }
+}
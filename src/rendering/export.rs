@@ -0,0 +1,302 @@
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Indexed triangle mesh with per vertex normals and colors.
+#[derive(Debug, Clone)]
+pub struct ExportMesh {
+    pub name: String,
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub colors: Vec<[f32; 3]>,
+    pub indices: Vec<u32>,
+}
+
+/// Placement of a mesh: uniform scale, then rotation around the y axis,
+/// then translation.
+#[derive(Debug, Clone, Copy)]
+pub struct ExportTransform {
+    pub translation: [f32; 3],
+    pub rotation_y: f32,
+    pub scale: f32,
+}
+
+impl ExportTransform {
+    pub fn identity() -> ExportTransform {
+        ExportTransform {
+            translation: [0.0, 0.0, 0.0],
+            rotation_y: 0.0,
+            scale: 1.0,
+        }
+    }
+
+    fn rotate(&self, v: [f32; 3]) -> [f32; 3] {
+        let (s, c) = self.rotation_y.sin_cos();
+        [c * v[0] + s * v[2], v[1], -s * v[0] + c * v[2]]
+    }
+
+    fn point(&self, p: [f32; 3]) -> [f32; 3] {
+        let r = self.rotate([p[0] * self.scale, p[1] * self.scale, p[2] * self.scale]);
+        [r[0] + self.translation[0], r[1] + self.translation[1], r[2] + self.translation[2]]
+    }
+}
+
+/// A mesh together with every place it appears in the scene.
+#[derive(Debug, Clone)]
+pub struct ExportObject {
+    pub mesh: ExportMesh,
+    pub instances: Vec<ExportTransform>,
+}
+
+fn create(path: &Path) -> Result<BufWriter<File>, String> {
+    match File::create(path) {
+        Ok(file) => Ok(BufWriter::new(file)),
+        Err(reason) => Err(format!("Could not create file {}: {}", path.display(), reason)),
+    }
+}
+
+fn write_err(path: &Path, reason: io::Error) -> String {
+    format!("Could not write {}: {}", path.display(), reason)
+}
+
+/// Write the scene as a Wavefront OBJ. Instances are baked into one object
+/// each, and vertex colors are written after the positions, which Blender
+/// and MeshLab both read.
+pub fn write_obj<P: AsRef<Path>>(path: P, objects: &[ExportObject]) -> Result<(), String> {
+    let path = path.as_ref();
+    let err = |e| write_err(path, e);
+    let mut out = try!(create(path));
+
+    try!(writeln!(out, "# Exported terrain scene").map_err(&err));
+
+    // OBJ indices are global and start at 1
+    let mut offset = 1;
+
+    for object in objects {
+        let mesh = &object.mesh;
+
+        for (n, t) in object.instances.iter().enumerate() {
+            try!(writeln!(out, "o {}_{}", mesh.name, n).map_err(&err));
+
+            for (p, c) in mesh.positions.iter().zip(mesh.colors.iter()) {
+                let p = t.point(*p);
+                try!(writeln!(out, "v {} {} {} {} {} {}", p[0], p[1], p[2], c[0], c[1], c[2])
+                    .map_err(&err));
+            }
+
+            for v in &mesh.normals {
+                let v = t.rotate(*v);
+                try!(writeln!(out, "vn {} {} {}", v[0], v[1], v[2]).map_err(&err));
+            }
+
+            for f in mesh.indices.chunks(3) {
+                if f.len() < 3 {
+                    break;
+                }
+                try!(writeln!(out,
+                              "f {a}//{a} {b}//{b} {c}//{c}",
+                              a = f[0] as usize + offset,
+                              b = f[1] as usize + offset,
+                              c = f[2] as usize + offset)
+                    .map_err(&err));
+            }
+
+            offset += mesh.positions.len();
+        }
+    }
+
+    info!(target: "DAT205", "Wrote {}", path.display());
+    Ok(())
+}
+
+fn push_f32(buf: &mut Vec<u8>, v: f32) {
+    let bits = v.to_bits();
+    buf.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+}
+
+fn push_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+}
+
+fn bounds(values: &[[f32; 3]]) -> ([f32; 3], [f32; 3]) {
+    let mut min = [::std::f32::MAX; 3];
+    let mut max = [::std::f32::MIN; 3];
+    for v in values {
+        for k in 0..3 {
+            min[k] = min[k].min(v[k]);
+            max[k] = max[k].max(v[k]);
+        }
+    }
+    (min, max)
+}
+
+/// Write the scene as glTF 2.0, a `.gltf` file with the scene description
+/// and a `.bin` file next to it with the vertex data. Every mesh is stored
+/// once and instanced through the scene nodes.
+// Escape a string to go between quotes in JSON. Mesh names come from the
+// scatter rules, which anyone can edit.
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn write_gltf<P: AsRef<Path>>(path: P, objects: &[ExportObject]) -> Result<(), String> {
+    const ARRAY_BUFFER: u32 = 34962;
+    const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+    const FLOAT: u32 = 5126;
+    const UNSIGNED_INT: u32 = 5125;
+
+    let path = path.as_ref();
+    let bin_path = path.with_extension("bin");
+    let bin_name = match bin_path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(format!("Invalid export path {}", path.display())),
+    };
+
+    let mut bin = Vec::new();
+    let mut views = Vec::new();
+    let mut accessors = Vec::new();
+    let mut meshes = Vec::new();
+    let mut nodes = Vec::new();
+
+    for object in objects {
+        let mesh = &object.mesh;
+        if mesh.indices.is_empty() || object.instances.is_empty() {
+            continue;
+        }
+
+        let first_accessor = accessors.len();
+
+        for attribute in &[&mesh.positions, &mesh.normals, &mesh.colors] {
+            let start = bin.len();
+            for v in attribute.iter() {
+                for k in 0..3 {
+                    push_f32(&mut bin, v[k]);
+                }
+            }
+            views.push(format!(r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#,
+                               start,
+                               bin.len() - start,
+                               ARRAY_BUFFER));
+        }
+
+        let start = bin.len();
+        for &i in &mesh.indices {
+            push_u32(&mut bin, i);
+        }
+        views.push(format!(r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#,
+                           start,
+                           bin.len() - start,
+                           ELEMENT_ARRAY_BUFFER));
+
+        // The accessors line up with the views created above
+        let (min, max) = bounds(&mesh.positions);
+        accessors.push(format!(r#"{{"bufferView":{},"componentType":{},"count":{},"type":"VEC3","min":[{},{},{}],"max":[{},{},{}]}}"#,
+                               first_accessor,
+                               FLOAT,
+                               mesh.positions.len(),
+                               min[0], min[1], min[2],
+                               max[0], max[1], max[2]));
+        accessors.push(format!(r#"{{"bufferView":{},"componentType":{},"count":{},"type":"VEC3"}}"#,
+                               first_accessor + 1,
+                               FLOAT,
+                               mesh.normals.len()));
+        accessors.push(format!(r#"{{"bufferView":{},"componentType":{},"count":{},"type":"VEC3"}}"#,
+                               first_accessor + 2,
+                               FLOAT,
+                               mesh.colors.len()));
+        accessors.push(format!(r#"{{"bufferView":{},"componentType":{},"count":{},"type":"SCALAR"}}"#,
+                               first_accessor + 3,
+                               UNSIGNED_INT,
+                               mesh.indices.len()));
+
+        meshes.push(format!(r#"{{"name":"{}","primitives":[{{"attributes":{{"POSITION":{},"NORMAL":{},"COLOR_0":{}}},"indices":{}}}]}}"#,
+                            json_escape(&mesh.name),
+                            first_accessor,
+                            first_accessor + 1,
+                            first_accessor + 2,
+                            first_accessor + 3));
+
+        let mesh_index = meshes.len() - 1;
+        for (n, t) in object.instances.iter().enumerate() {
+            let half = 0.5 * t.rotation_y;
+            nodes.push(format!(r#"{{"name":"{}_{}","mesh":{},"translation":[{},{},{}],"rotation":[0,{},0,{}],"scale":[{},{},{}]}}"#,
+                               json_escape(&mesh.name),
+                               n,
+                               mesh_index,
+                               t.translation[0], t.translation[1], t.translation[2],
+                               half.sin(), half.cos(),
+                               t.scale, t.scale, t.scale));
+        }
+    }
+
+    let node_indices: Vec<String> = (0..nodes.len()).map(|i| i.to_string()).collect();
+
+    let json = format!(r#"{{
+  "asset": {{"version": "2.0", "generator": "DAT205"}},
+  "scene": 0,
+  "scenes": [{{"nodes": [{}]}}],
+  "nodes": [{}],
+  "meshes": [{}],
+  "accessors": [{}],
+  "bufferViews": [{}],
+  "buffers": [{{"uri": "{}", "byteLength": {}}}]
+}}
+"#,
+                       node_indices.join(","),
+                       nodes.join(",\n    "),
+                       meshes.join(",\n    "),
+                       accessors.join(",\n    "),
+                       views.join(",\n    "),
+                       bin_name,
+                       bin.len());
+
+    let mut out = try!(create(&bin_path));
+    try!(out.write_all(&bin).map_err(|e| write_err(&bin_path, e)));
+
+    let mut out = try!(create(path));
+    try!(out.write_all(json.as_bytes()).map_err(|e| write_err(path, e)));
+
+    info!(target: "DAT205", "Wrote {} and {}", path.display(), bin_path.display());
+    Ok(())
+}
+
+/// Write the scene in the format matching the extension of `path`, glTF for
+/// `.gltf` and OBJ for anything else.
+pub fn export_scene<P: AsRef<Path>>(path: P, objects: &[ExportObject]) -> Result<(), String> {
+    let is_gltf = path.as_ref()
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase() == "gltf")
+        .unwrap_or(false);
+
+    if is_gltf {
+        write_gltf(path, objects)
+    } else {
+        write_obj(path, objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_escape() {
+        assert_eq!(json_escape("pine"), "pine");
+        assert_eq!(json_escape("a \"b\" \\ c"), "a \\\"b\\\" \\\\ c");
+        assert_eq!(json_escape("tab\there\u{1}"), "tab\\there\\u0001");
+    }
+}
@@ -0,0 +1,358 @@
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use image;
+use image::ImageDecoder;
+use na::{Point3, Vector3};
+use noise::perlin2;
+use noise;
+
+pub const DEFAULT_RESOLUTION: usize = 257;
+pub const DEFAULT_HORIZONTAL_SCALE: f32 = 100.0;
+pub const DEFAULT_VERTICAL_SCALE: f32 = 100.0;
+
+/// Regular grid of terrain heights in world units. The grid is centered on
+/// the origin and samples are stored row by row along the x axis, which is
+/// the same order genmesh uses for the shared vertices of a `Plane`.
+#[derive(Debug, Clone)]
+pub struct Heightmap {
+    width: usize,
+    depth: usize,
+    cell_size: f32,
+    horizontal_scale: f32,
+    vertical_scale: f32,
+    heights: Vec<f32>,
+    seed: Option<u32>,
+}
+
+impl Heightmap {
+    /// Sample `perlin2` over [-1, 1] in both directions, the way the terrain
+    /// has always been generated.
+    pub fn from_noise(seed: u32,
+                      resolution: usize,
+                      horizontal_scale: f32,
+                      vertical_scale: f32)
+                      -> Heightmap {
+        Heightmap::from_noise_sized(seed,
+                                    resolution,
+                                    resolution,
+                                    horizontal_scale,
+                                    vertical_scale)
+    }
+
+    /// Like `from_noise` for a grid that is not square. The longest side
+    /// spans [-1, 1] in noise space.
+    pub fn from_noise_sized(seed: u32,
+                            width: usize,
+                            depth: usize,
+                            horizontal_scale: f32,
+                            vertical_scale: f32)
+                            -> Heightmap {
+        let table = noise::PermutationTable::new(seed);
+        let step = 2.0 / (width.max(depth) - 1) as f32;
+
+        let mut heights = Vec::with_capacity(width * depth);
+        for iz in 0..depth {
+            for ix in 0..width {
+                let x = (ix as f32 - 0.5 * (width - 1) as f32) * step;
+                let z = (iz as f32 - 0.5 * (depth - 1) as f32) * step;
+                heights.push(vertical_scale * perlin2(&table, &[x, z]));
+            }
+        }
+
+        let mut hm = Heightmap::from_heights(width, depth, horizontal_scale, vertical_scale, heights);
+        hm.seed = Some(seed);
+        hm
+    }
+
+    /// Load an 8 or 16 bit grayscale image. Black maps to `-vertical_scale`
+    /// and white to `vertical_scale`, and the longest side of the image spans
+    /// [-horizontal_scale, horizontal_scale].
+    pub fn from_image<P: AsRef<Path>>(path: P,
+                                      horizontal_scale: f32,
+                                      vertical_scale: f32)
+                                      -> Result<Heightmap, String> {
+        let disp = path.as_ref().display();
+
+        let (width, depth, samples) = match read_png_16(path.as_ref()) {
+            Some(res) => res,
+            None => {
+                let img = match image::open(path.as_ref()) {
+                    Ok(img) => img.to_luma(),
+                    Err(reason) => {
+                        return Err(format!("Could not load heightmap {}: {}", disp, reason))
+                    }
+                };
+                let (w, d) = img.dimensions();
+                let samples = img.into_raw().iter().map(|&p| p as f32 / 255.0).collect();
+                (w as usize, d as usize, samples)
+            }
+        };
+
+        if width < 2 || depth < 2 {
+            return Err(format!("Heightmap {} must be at least 2x2 pixels", disp));
+        }
+
+        let heights = samples.iter().map(|s: &f32| (2.0 * s - 1.0) * vertical_scale).collect();
+
+        info!(target: "DAT205", "Loaded {}x{} heightmap from {}", width, depth, disp);
+
+        Ok(Heightmap::from_heights(width, depth, horizontal_scale, vertical_scale, heights))
+    }
+
+    pub fn from_heights(width: usize,
+                        depth: usize,
+                        horizontal_scale: f32,
+                        vertical_scale: f32,
+                        heights: Vec<f32>)
+                        -> Heightmap {
+        assert!(width >= 2 && depth >= 2, "A heightmap needs at least 2x2 samples");
+        assert_eq!(heights.len(), width * depth);
+
+        Heightmap {
+            width: width,
+            depth: depth,
+            cell_size: 2.0 * horizontal_scale / (width.max(depth) - 1) as f32,
+            horizontal_scale: horizontal_scale,
+            vertical_scale: vertical_scale,
+            heights: heights,
+            seed: None,
+        }
+    }
+
+    /// The noise seed the heights were generated from, if any.
+    pub fn seed(&self) -> Option<u32> {
+        self.seed
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    pub fn horizontal_scale(&self) -> f32 {
+        self.horizontal_scale
+    }
+
+    pub fn vertical_scale(&self) -> f32 {
+        self.vertical_scale
+    }
+
+    pub fn heights(&self) -> &[f32] {
+        &self.heights
+    }
+
+    pub fn heights_mut(&mut self) -> &mut [f32] {
+        &mut self.heights
+    }
+
+    pub fn height(&self, ix: usize, iz: usize) -> f32 {
+        self.heights[iz * self.width + ix]
+    }
+
+    pub fn set_height(&mut self, ix: usize, iz: usize, h: f32) {
+        self.heights[iz * self.width + ix] = h;
+    }
+
+    /// World space position of a grid sample.
+    pub fn position(&self, ix: usize, iz: usize) -> [f32; 3] {
+        [(ix as f32 - 0.5 * (self.width - 1) as f32) * self.cell_size,
+         self.height(ix, iz),
+         (iz as f32 - 0.5 * (self.depth - 1) as f32) * self.cell_size]
+    }
+
+    /// Continuous grid coordinates of a world space position.
+    pub fn to_grid(&self, x: f32, z: f32) -> (f32, f32) {
+        (x / self.cell_size + 0.5 * (self.width - 1) as f32,
+         z / self.cell_size + 0.5 * (self.depth - 1) as f32)
+    }
+
+    /// Bilinearly interpolated height at a world space position. Positions
+    /// outside the grid are clamped to the border.
+    pub fn sample(&self, x: f32, z: f32) -> f32 {
+        let (gx, gz) = self.to_grid(x, z);
+        self.sample_grid(gx, gz)
+    }
+
+    pub fn sample_grid(&self, gx: f32, gz: f32) -> f32 {
+        let gx = gx.max(0.0).min((self.width - 1) as f32);
+        let gz = gz.max(0.0).min((self.depth - 1) as f32);
+
+        let x0 = (gx.floor() as usize).min(self.width - 2);
+        let z0 = (gz.floor() as usize).min(self.depth - 2);
+        let tx = gx - x0 as f32;
+        let tz = gz - z0 as f32;
+
+        let h00 = self.height(x0, z0);
+        let h10 = self.height(x0 + 1, z0);
+        let h01 = self.height(x0, z0 + 1);
+        let h11 = self.height(x0 + 1, z0 + 1);
+
+        let a = h00 + (h10 - h00) * tx;
+        let b = h01 + (h11 - h01) * tx;
+        a + (b - a) * tz
+    }
+
+    /// First point where a ray hits the surface, found by marching half a
+    /// cell at a time and refining the crossing with a few bisection steps.
+    pub fn raycast(&self,
+                   origin: Point3<f32>,
+                   dir: Vector3<f32>,
+                   max_dist: f32)
+                   -> Option<Point3<f32>> {
+        let half_w = 0.5 * (self.width - 1) as f32 * self.cell_size;
+        let half_d = 0.5 * (self.depth - 1) as f32 * self.cell_size;
+        let step = 0.5 * self.cell_size;
+
+        let above = |t: f32| {
+            let p = origin + dir * t;
+            p.y - self.sample(p.x, p.z)
+        };
+
+        let mut t0 = 0.0;
+        let mut t = step;
+        while t < max_dist {
+            let p = origin + dir * t;
+            if p.x.abs() <= half_w && p.z.abs() <= half_d && above(t) < 0.0 {
+                let mut t1 = t;
+                for _ in 0..8 {
+                    let mid = 0.5 * (t0 + t1);
+                    if above(mid) < 0.0 {
+                        t1 = mid;
+                    } else {
+                        t0 = mid;
+                    }
+                }
+                return Some(origin + dir * t1);
+            }
+            t0 = t;
+            t += step;
+        }
+
+        None
+    }
+
+    /// Surface normal from central differences of the neighbouring samples.
+    pub fn normal(&self, ix: usize, iz: usize) -> [f32; 3] {
+        let x0 = if ix > 0 { ix - 1 } else { ix };
+        let x1 = if ix + 1 < self.width { ix + 1 } else { ix };
+        let z0 = if iz > 0 { iz - 1 } else { iz };
+        let z1 = if iz + 1 < self.depth { iz + 1 } else { iz };
+
+        let dhdx = (self.height(x1, iz) - self.height(x0, iz)) /
+                   ((x1 - x0) as f32 * self.cell_size);
+        let dhdz = (self.height(ix, z1) - self.height(ix, z0)) /
+                   ((z1 - z0) as f32 * self.cell_size);
+
+        Vector3::new(-dhdx, 1.0, -dhdz).normalize().into()
+    }
+
+    /// Write the heights as a 16 bit grayscale PNG using the same mapping as
+    /// `from_image`, so the result can be loaded back in with the same scale.
+    pub fn save_png_16<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let mut clipped = false;
+        let mut data = Vec::with_capacity(self.heights.len() * 2);
+
+        for h in &self.heights {
+            let s = 0.5 * (h / self.vertical_scale + 1.0);
+            if s < 0.0 || s > 1.0 {
+                clipped = true;
+            }
+            let v = (s.max(0.0).min(1.0) * 65535.0).round() as u16;
+            // PNG stores 16 bit samples big endian
+            data.push((v >> 8) as u8);
+            data.push((v & 0xff) as u8);
+        }
+
+        if clipped {
+            warn!(target: "DAT205", "Heights outside of the vertical scale were clipped");
+        }
+
+        write_png(path.as_ref(), &data, self.width, self.depth, image::Gray(16))
+    }
+
+    /// Write the surface normals as an RGB PNG. Normals are stored in
+    /// tangent space with z up, i.e. red is world x, green world z and blue
+    /// world y.
+    pub fn save_normal_map<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let mut data = Vec::with_capacity(self.heights.len() * 3);
+
+        for iz in 0..self.depth {
+            for ix in 0..self.width {
+                let n = self.normal(ix, iz);
+                for c in &[n[0], n[2], n[1]] {
+                    data.push(((c * 0.5 + 0.5) * 255.0).round() as u8);
+                }
+            }
+        }
+
+        write_png(path.as_ref(), &data, self.width, self.depth, image::RGB(8))
+    }
+}
+
+// `image::open` only hands out 8 bit buffers, so 16 bit grayscale PNGs are
+// decoded by hand to keep their full precision.
+fn read_png_16(path: &Path) -> Option<(usize, usize, Vec<f32>)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+
+    let mut decoder = image::png::PNGDecoder::new(file);
+
+    match decoder.colortype() {
+        Ok(image::Gray(16)) => {}
+        _ => return None,
+    }
+
+    let (w, d) = match decoder.dimensions() {
+        Ok(dim) => dim,
+        Err(_) => return None,
+    };
+
+    match decoder.read_image() {
+        Ok(image::DecodingResult::U8(bytes)) => {
+            let samples = bytes.chunks(2)
+                .map(|b| ((b[0] as u16) << 8 | b[1] as u16) as f32 / 65535.0)
+                .collect();
+            Some((w as usize, d as usize, samples))
+        }
+        Ok(image::DecodingResult::U16(words)) => {
+            let samples = words.iter().map(|&v| v as f32 / 65535.0).collect();
+            Some((w as usize, d as usize, samples))
+        }
+        Err(_) => None,
+    }
+}
+
+fn write_png(path: &Path,
+             data: &[u8],
+             width: usize,
+             depth: usize,
+             color: image::ColorType)
+             -> Result<(), String> {
+    let disp = path.display();
+
+    let file = match File::create(path) {
+        Ok(file) => file,
+        Err(reason) => return Err(format!("Could not create file {}: {}", disp, reason)),
+    };
+
+    match image::png::PNGEncoder::new(BufWriter::new(file))
+        .encode(data, width as u32, depth as u32, color) {
+        Ok(_) => {
+            info!(target: "DAT205", "Wrote {}", disp);
+            Ok(())
+        }
+        Err(reason) => Err(format!("Could not write {}: {}", disp, reason)),
+    }
+}
@@ -0,0 +1,374 @@
+
+use std::f32::consts::PI;
+
+use rendering::clusters::MAX_LIGHTS;
+use rendering::deferred::{LightInfo, SpotInfo, SunLocals};
+use rendering::shadows;
+use support::config::Config;
+
+// Smallest radius of a point light, the falloff divides by it
+const MIN_RADIUS: f32 = 0.01;
+
+// Metallic and roughness BRDF, see `with_brdf`.
+const BRDF_GLSL: &'static [u8] = b"
+    const float PI = 3.14159265;
+
+    // Trowbridge-Reitz distribution of the microfacet normals, with the
+    // usual alpha = roughness^2 remapping
+    float distribution_ggx(float n_dot_h, float roughness) {
+        float a = roughness * roughness;
+        float a_sq = a * a;
+        float d = n_dot_h * n_dot_h * (a_sq - 1.0) + 1.0;
+        return a_sq / (PI * d * d);
+    }
+
+    // Smith shadowing and masking with the Schlick approximation, k remapped
+    // for lights that are points
+    float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+        float r = roughness + 1.0;
+        float k = r * r / 8.0;
+        float gv = n_dot_v / (n_dot_v * (1.0 - k) + k);
+        float gl = n_dot_l / (n_dot_l * (1.0 - k) + k);
+        return gv * gl;
+    }
+
+    vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+        return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+    }
+
+    // Reflectance at normal incidence, 4% for dielectrics and the albedo
+    // tinted reflection of metals
+    vec3 base_reflectance(vec3 albedo, float metallic) {
+        return mix(vec3(0.04), albedo, metallic);
+    }
+
+    // Lambert diffuse plus Cook-Torrance specular, times the cosine term.
+    // Light reflected by the specular lobe is taken from the diffuse part
+    // and metals have no diffuse part, so no more light leaves than arrives.
+    vec3 brdf(vec3 n, vec3 to_cam, vec3 to_light, vec3 albedo, float roughness, float metallic) {
+        float n_dot_l = dot(n, to_light);
+        if (n_dot_l <= 0.0) {
+            return vec3(0.0);
+        }
+        float n_dot_v = max(dot(n, to_cam), 1.0e-4);
+        vec3 h = normalize(to_cam + to_light);
+
+        // Perfect mirrors would turn the highlight of a point into nothing
+        float r = clamp(roughness, 0.04, 1.0);
+        vec3 f = fresnel_schlick(max(dot(h, to_cam), 0.0), base_reflectance(albedo, metallic));
+        vec3 specular = distribution_ggx(max(dot(n, h), 0.0), r) *
+                        geometry_smith(n_dot_v, n_dot_l, r) * f / (4.0 * n_dot_v * n_dot_l);
+        vec3 kd = (vec3(1.0) - f) * (1.0 - metallic);
+
+        return (kd * albedo / PI + specular) * n_dot_l;
+    }
+";
+
+// Shading of a single point light, see `with_point_lights`.
+const POINT_LIGHT_GLSL: &'static [u8] = b"
+    // 0 is linear, 1 inverse square and 2 windowed inverse square. All of
+    // them reach zero at the radius, where the light volume ends.
+    float attenuation(int falloff, float dist_sq, float radius) {
+        float r_sq = radius * radius;
+        if (falloff == 1) {
+            float edge = 1.0 / (1.0 + r_sq);
+            return max(0.0, (1.0 / (1.0 + dist_sq) - edge) / (1.0 - edge));
+        } else if (falloff == 2) {
+            float x = dist_sq / r_sq;
+            float window = clamp(1.0 - x * x, 0.0, 1.0);
+            return window * window / (1.0 + dist_sq);
+        }
+        return max(0.0, 1.0 - dist_sq / r_sq);
+    }
+
+    vec3 point_light(vec3 pos, vec3 n, vec3 albedo, vec2 material, vec3 cam_pos,
+                     vec3 light, vec4 color, float radius, int falloff, int slot) {
+        float dist_sq = dot(light - pos, light - pos);
+        float scale = color.a * attenuation(falloff, dist_sq, radius);
+        if (scale <= 0.0) {
+            return vec3(0.0);
+        }
+
+        vec3 to_light = normalize(light - pos);
+        vec3 to_cam   = normalize(cam_pos - pos);
+
+        scale *= point_shadow(slot, pos, n, light, radius);
+        return scale * color.rgb * brdf(n, to_cam, to_light, albedo, material.x, material.y);
+    }
+";
+
+/// Add the BRDF to a fragment shader, right after its version line. It
+/// declares `brdf(n, to_cam, to_light, albedo, roughness, metallic)`, the
+/// fraction of the illuminance from a light that is reflected towards the
+/// camera, along with `fresnel_schlick` and `base_reflectance`.
+pub fn with_brdf(shader: &[u8]) -> Vec<u8> {
+    shadows::insert_after_version(shader, BRDF_GLSL)
+}
+
+/// Add point light shading to a fragment shader. It declares
+/// `point_light(pos, n, albedo, material, cam_pos, light_pos, color,
+/// radius, falloff, slot)`, the light reflected towards the camera, where
+/// `material` holds roughness and metallic. Everything
+/// `shadows::with_point_shadows` needs is bound as well.
+pub fn with_point_lights(shader: &[u8]) -> Vec<u8> {
+    let shader = shadows::insert_after_version(shader, POINT_LIGHT_GLSL);
+    with_brdf(&shadows::with_point_shadows(&shader))
+}
+
+/// How the light of a point light fades out towards its radius.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Falloff {
+    // 1 - d^2 / r^2
+    Linear,
+    // 1 / (1 + d^2), shifted so it reaches zero at the radius
+    InverseSquare,
+    // 1 / (1 + d^2) faded out by (1 - (d / r)^4)^2
+    Windowed,
+}
+
+impl Falloff {
+    pub fn from_name(name: &str) -> Option<Falloff> {
+        match name {
+            "linear" => Some(Falloff::Linear),
+            "inverse_square" => Some(Falloff::InverseSquare),
+            "windowed" => Some(Falloff::Windowed),
+            _ => None,
+        }
+    }
+
+    // Matches the branches of the light shader
+    fn index(&self) -> f32 {
+        match *self {
+            Falloff::Linear => 0.0,
+            Falloff::InverseSquare => 1.0,
+            Falloff::Windowed => 2.0,
+        }
+    }
+}
+
+/// Intensities of all lights are the illuminance on a surface facing the
+/// light, before any falloff. A white diffuse surface lit with an intensity
+/// of pi reflects all of the light's color.
+#[derive(Debug, Clone)]
+pub struct PointLight {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub radius: f32,
+    pub falloff: Falloff,
+}
+
+impl PointLight {
+    /// The light as laid out in the light texture, see `LightClusters`. The
+    /// falloff divides by the radius, so it is kept above zero.
+    pub fn info(&self) -> LightInfo {
+        let p = self.position;
+        let c = self.color;
+        LightInfo {
+            pos: [p[0], p[1], p[2], self.radius.max(MIN_RADIUS)],
+            color: [c[0], c[1], c[2], self.intensity],
+            falloff: [self.falloff.index(), -1.0, 0.0, 0.0],
+        }
+    }
+}
+
+/// Light from far away that reaches everything from the same direction.
+#[derive(Debug, Clone)]
+pub struct DirectionalLight {
+    // Towards the light, normalized
+    pub direction: [f32; 3],
+    pub color: [f32; 3],
+    pub intensity: f32,
+}
+
+impl DirectionalLight {
+    /// Point the light along `dir`, which may have any length but zero.
+    pub fn set_direction(&mut self, dir: [f32; 3]) -> Result<(), String> {
+        let len = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt();
+        if len < 1.0e-6 {
+            return Err("The sun direction can not be zero".to_owned());
+        }
+        self.direction = [dir[0] / len, dir[1] / len, dir[2] / len];
+        Ok(())
+    }
+
+    pub fn locals(&self, cam_pos: [f32; 3]) -> SunLocals {
+        let (d, c) = (self.direction, self.color);
+        SunLocals {
+            direction: [d[0], d[1], d[2], 0.0],
+            color: [c[0], c[1], c[2], self.intensity],
+            cam_pos: [cam_pos[0], cam_pos[1], cam_pos[2], 1.0],
+        }
+    }
+}
+
+/// A cone of light. Full brightness inside the inner angle, fading out
+/// towards the outer angle and the range. Angles are in degrees from the
+/// axis of the cone.
+#[derive(Debug, Clone)]
+pub struct SpotLight {
+    pub position: [f32; 3],
+    // Along the axis of the cone, normalized
+    pub direction: [f32; 3],
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+}
+
+impl SpotLight {
+    /// The light as laid out in the instance buffer of the cone volumes.
+    pub fn info(&self) -> SpotInfo {
+        let (p, d, c) = (self.position, self.direction, self.color);
+        // The cone volume can not be wider than a half space
+        let outer = self.outer_angle.max(0.1).min(89.0).to_radians();
+        // Equal angles would leave the smoothstep between them undefined
+        let inner = self.inner_angle.max(0.0).to_radians().min(outer - 1.0e-3);
+        SpotInfo {
+            pos: [p[0], p[1], p[2], self.range],
+            direction: [d[0], d[1], d[2], outer.cos()],
+            color: [c[0], c[1], c[2], self.intensity],
+            cone: [inner.cos(), outer.tan(), 0.0, 0.0],
+        }
+    }
+}
+
+fn palette(config: &Config, key: &str) -> Vec<[f32; 3]> {
+    match config.get_floats(key) {
+        Some(ref c) if !c.is_empty() && c.len() % 3 == 0 => {
+            c.chunks(3).map(|c| [c[0], c[1], c[2]]).collect()
+        }
+        Some(_) => {
+            warn!(target: "DAT205", "Expected a list of colors for {}", key);
+            vec![[1.0, 1.0, 1.0]]
+        }
+        None => vec![[1.0, 1.0, 1.0]],
+    }
+}
+
+// A list of numbers for `key`, or `default` if there is none.
+fn values(config: &Config, key: &str, default: f32) -> Vec<f32> {
+    match config.get_floats(key) {
+        Some(ref v) if !v.is_empty() => v.clone(),
+        Some(_) => {
+            warn!(target: "DAT205", "Expected a list of numbers for {}", key);
+            vec![default]
+        }
+        None => vec![default],
+    }
+}
+
+/// Look of the point lights. Every attribute is a list and light `i` takes
+/// entry `i` of each, starting over at the end of a list, so lights can
+/// differ in radius, intensity and falloff as well as color.
+#[derive(Debug, Clone)]
+pub struct LightSettings {
+    pub count: usize,
+    pub radii: Vec<f32>,
+    pub intensities: Vec<f32>,
+    pub falloffs: Vec<Falloff>,
+    pub colors: Vec<[f32; 3]>,
+}
+
+impl LightSettings {
+    pub fn from_config(config: &Config) -> LightSettings {
+        let falloffs = config.get_str("light.falloff")
+            .unwrap_or("linear")
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|name| {
+                Falloff::from_name(name).unwrap_or_else(|| {
+                    warn!(target: "DAT205", "Unknown light falloff {}", name);
+                    Falloff::Linear
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let radii = values(config, "light.radius", 10.0);
+        if radii.iter().any(|&r| r < MIN_RADIUS) {
+            warn!(target: "DAT205", "Point light radii are at least {}", MIN_RADIUS);
+        }
+
+        let count: usize = config.get("light.count", 250);
+        if count > MAX_LIGHTS {
+            warn!(target: "DAT205", "At most {} point lights are supported", MAX_LIGHTS);
+        }
+
+        LightSettings {
+            count: count.min(MAX_LIGHTS),
+            radii: radii.iter().map(|&r| r.max(MIN_RADIUS)).collect(),
+            intensities: values(config, "light.intensity", PI),
+            falloffs: if falloffs.is_empty() { vec![Falloff::Linear] } else { falloffs },
+            colors: palette(config, "light.colors"),
+        }
+    }
+
+    /// `count` lights at the origin, positioned every frame by the caller.
+    pub fn lights(&self, count: usize) -> Vec<PointLight> {
+        (0..count)
+            .map(|i| {
+                PointLight {
+                    position: [0.0, 0.0, 0.0],
+                    color: self.colors[i % self.colors.len()],
+                    intensity: self.intensities[i % self.intensities.len()],
+                    radius: self.radii[i % self.radii.len()],
+                    falloff: self.falloffs[i % self.falloffs.len()],
+                }
+            })
+            .collect()
+    }
+}
+
+/// The sun from the `sun.*` settings.
+pub fn sun_from_config(config: &Config) -> DirectionalLight {
+    let mut sun = DirectionalLight {
+        direction: [0.0, 1.0, 0.0],
+        color: [1.0, 1.0, 1.0],
+        intensity: config.get("sun.intensity", 0.0),
+    };
+
+    if let Some(c) = config.get_floats("sun.color") {
+        if c.len() == 3 {
+            sun.color = [c[0], c[1], c[2]];
+        } else {
+            warn!(target: "DAT205", "Expected a color for sun.color");
+        }
+    }
+    if let Some(d) = config.get_floats("sun.direction") {
+        let res = if d.len() == 3 {
+            sun.set_direction([d[0], d[1], d[2]])
+        } else {
+            Err("Expected a direction for sun.direction".to_owned())
+        };
+        if let Err(e) = res {
+            warn!(target: "DAT205", "{}", e);
+        }
+    }
+
+    sun
+}
+
+/// Spot lights from the `spot.*` settings, at the origin pointing down
+/// until the caller places them.
+pub fn spots_from_config(config: &Config, max: usize) -> Vec<SpotLight> {
+    let colors = palette(config, "spot.colors");
+    let count: usize = config.get("spot.count", 0);
+    if count > max {
+        warn!(target: "DAT205", "At most {} spot lights are supported", max);
+    }
+
+    (0..count.min(max))
+        .map(|i| {
+            SpotLight {
+                position: [0.0, 0.0, 0.0],
+                direction: [0.0, -1.0, 0.0],
+                color: colors[i % colors.len()],
+                intensity: config.get("spot.intensity", PI),
+                range: config.get("spot.range", 40.0),
+                inner_angle: config.get("spot.inner_angle", 15.0),
+                outer_angle: config.get("spot.outer_angle", 25.0),
+            }
+        })
+        .collect()
+}
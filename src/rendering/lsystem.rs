@@ -0,0 +1,294 @@
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use genmesh::{Polygon, Quad, Triangulate};
+use genmesh::generators::Cylinder;
+use na::{Point3, Rotation3, Vector3};
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use rendering::export::{write_obj, ExportObject, ExportTransform};
+use rendering::scatter::{export_mesh, flat_mesh, ScatterVertex};
+use support::config::Config;
+
+// Expanded strings longer than this stop growing, a few extra iterations
+// would otherwise take all memory
+const MAX_SYMBOLS: usize = 500000;
+
+/// A tree grammar and how its turtle draws. Angles are in degrees.
+///
+/// Symbols: `F` draws a branch segment and `f` moves without drawing. `+`
+/// and `-` turn left and right, `&` and `^` pitch down and up, `\` and `/`
+/// roll, `|` turns around. `[` and `]` push and pop the turtle. `!` makes
+/// the branches thinner, `"` makes them shorter and `L` adds a leaf card.
+/// Every other symbol is only used by the rules.
+#[derive(Debug, Clone)]
+pub struct TreePreset {
+    pub name: String,
+    pub axiom: String,
+    // Alternatives of each rule, picked at random with equal chance
+    pub rules: HashMap<char, Vec<String>>,
+    pub iterations: usize,
+    pub angle: f32,
+    // Random change added to every turn
+    pub angle_jitter: f32,
+    pub length: f32,
+    pub length_scale: f32,
+    pub radius: f32,
+    pub radius_scale: f32,
+    // Thickness at the end of a segment relative to its start
+    pub taper: f32,
+    pub sides: usize,
+    pub leaf_size: f32,
+    pub trunk_color: [f32; 3],
+    pub leaf_color: [f32; 3],
+}
+
+impl TreePreset {
+    /// Read a preset. Rules are written as `rule.X = alternative, ...`
+    /// where `X` is the symbol that gets replaced.
+    pub fn from_config(name: &str, config: &Config) -> Result<TreePreset, String> {
+        let color = |key: &str, default: [f32; 3]| match config.get_floats(key) {
+            Some(ref c) if c.len() == 3 => [c[0], c[1], c[2]],
+            _ => default,
+        };
+
+        let axiom = match config.get_str("axiom") {
+            Some(axiom) if !axiom.is_empty() => axiom.to_owned(),
+            _ => return Err(format!("Tree preset {} has no axiom", name)),
+        };
+
+        let mut rules = HashMap::new();
+        for key in config.keys() {
+            if !key.starts_with("rule.") {
+                continue;
+            }
+
+            let mut symbol = key["rule.".len()..].chars();
+            let symbol = match (symbol.next(), symbol.next()) {
+                (Some(s), None) => s,
+                _ => return Err(format!("Invalid rule {} in tree preset {}", key, name)),
+            };
+
+            let alternatives: Vec<String> = config.get_str(key)
+                .unwrap_or("")
+                .split(',')
+                .map(|s| s.split_whitespace().collect())
+                .collect();
+            rules.insert(symbol, alternatives);
+        }
+
+        Ok(TreePreset {
+            name: name.to_owned(),
+            axiom: axiom.split_whitespace().collect(),
+            rules: rules,
+            iterations: config.get("iterations", 4),
+            angle: config.get("angle", 25.0),
+            angle_jitter: config.get("angle_jitter", 0.0),
+            length: config.get("length", 1.0),
+            length_scale: config.get("length_scale", 0.8),
+            radius: config.get("radius", 0.1),
+            radius_scale: config.get("radius_scale", 0.7),
+            taper: config.get("taper", 0.95),
+            sides: config.get("sides", 6),
+            leaf_size: config.get("leaf_size", 0.5),
+            trunk_color: color("trunk_color", [0.2421, 0.1406, 0.1406]),
+            leaf_color: color("leaf_color", [0.08, 0.35, 0.15]),
+        })
+    }
+
+    /// Load a preset file, named after the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TreePreset, String> {
+        let path = path.as_ref();
+        let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("tree");
+        Config::load(path).and_then(|config| TreePreset::from_config(name, &config))
+    }
+
+    /// Apply the rules to the axiom.
+    pub fn expand<G: Rng>(&self, rng: &mut G) -> String {
+        let mut current = self.axiom.clone();
+
+        for i in 0..self.iterations {
+            let mut next = String::with_capacity(current.len() * 2);
+            for c in current.chars() {
+                match self.rules.get(&c) {
+                    Some(alternatives) if !alternatives.is_empty() => {
+                        let pick = rng.gen_range(0, alternatives.len());
+                        next.push_str(&alternatives[pick]);
+                    }
+                    _ => next.push(c),
+                }
+            }
+
+            if next.len() > MAX_SYMBOLS {
+                warn!(target: "DAT205",
+                      "Tree {} stopped growing after {} of {} iterations",
+                      self.name,
+                      i,
+                      self.iterations);
+                break;
+            }
+            current = next;
+        }
+
+        current
+    }
+
+    /// Grow the tree with a seed, the same seed always gives the same
+    /// tree. The trunk starts at the origin and grows along y.
+    pub fn generate(&self, seed: u32) -> Vec<ScatterVertex> {
+        let mut rng = XorShiftRng::from_seed([seed, 0x6a09_e667, 0xbb67_ae85, 0x3c6e_f372]);
+        let symbols = self.expand(&mut rng);
+        interpret(self, &symbols, &mut rng)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Turtle {
+    pos: Point3<f32>,
+    heading: Vector3<f32>,
+    left: Vector3<f32>,
+    up: Vector3<f32>,
+    length: f32,
+    radius: f32,
+}
+
+impl Turtle {
+    fn rotate(&mut self, axis: Vector3<f32>, angle: f32) {
+        let rot = Rotation3::new(axis * angle);
+        self.heading = (rot * self.heading).normalize();
+        self.left = (rot * self.left).normalize();
+        self.up = (rot * self.up).normalize();
+    }
+}
+
+// A tapered cylinder from the turtle position along its heading. The
+// genmesh cylinder runs along z with a radius of one.
+fn branch(turtle: &Turtle, end_radius: f32, sides: usize, color: [f32; 3]) -> Vec<ScatterVertex> {
+    let t = *turtle;
+    flat_mesh(Cylinder::new(sides.max(3)).triangulate(), color, |(x, y, z)| {
+        let s = 0.5 * (z + 1.0);
+        let r = t.radius + (end_radius - t.radius) * s;
+        t.pos + t.heading * (t.length * s) + (t.up * x + t.left * y) * r
+    })
+}
+
+// A square card hanging off the branch, visible from both sides.
+fn leaf(turtle: &Turtle, size: f32, color: [f32; 3]) -> Vec<ScatterVertex> {
+    let corner = |side: f32, along: f32| {
+        let p = turtle.pos + turtle.left * (0.5 * side * size) + turtle.heading * (along * size);
+        (p.x, p.y, p.z)
+    };
+    let (a, b, c, d) = (corner(-1.0, 0.0), corner(1.0, 0.0), corner(1.0, 1.0), corner(-1.0, 1.0));
+
+    let cards = vec![Polygon::PolyQuad(Quad::new(a, b, c, d)),
+                     Polygon::PolyQuad(Quad::new(d, c, b, a))];
+    flat_mesh(cards.into_iter().triangulate(), color, |(x, y, z)| Point3::new(x, y, z))
+}
+
+fn interpret<G: Rng>(preset: &TreePreset, symbols: &str, rng: &mut G) -> Vec<ScatterVertex> {
+    let mut turtle = Turtle {
+        pos: Point3::origin(),
+        heading: Vector3::y(),
+        left: Vector3::x(),
+        up: -Vector3::z(),
+        length: preset.length,
+        radius: preset.radius,
+    };
+    let mut stack = Vec::new();
+    let mut vertices = Vec::new();
+
+    let angle = preset.angle.to_radians();
+    let jitter = preset.angle_jitter.to_radians();
+
+    for c in symbols.chars() {
+        let turn = angle + if jitter > 0.0 { rng.gen_range(-jitter, jitter) } else { 0.0 };
+
+        match c {
+            'F' => {
+                let end_radius = turtle.radius * preset.taper;
+                vertices.extend(branch(&turtle, end_radius, preset.sides, preset.trunk_color));
+                turtle.pos = turtle.pos + turtle.heading * turtle.length;
+                turtle.radius = end_radius;
+            }
+            'f' => turtle.pos = turtle.pos + turtle.heading * turtle.length,
+            '+' => turtle.rotate(turtle.up, turn),
+            '-' => turtle.rotate(turtle.up, -turn),
+            '&' => turtle.rotate(turtle.left, turn),
+            '^' => turtle.rotate(turtle.left, -turn),
+            '\\' => turtle.rotate(turtle.heading, turn),
+            '/' => turtle.rotate(turtle.heading, -turn),
+            '|' => turtle.rotate(turtle.up, ::std::f32::consts::PI),
+            '[' => stack.push(turtle),
+            ']' => {
+                if let Some(t) = stack.pop() {
+                    turtle = t;
+                }
+            }
+            '!' => turtle.radius *= preset.radius_scale,
+            '"' => turtle.length *= preset.length_scale,
+            'L' => vertices.extend(leaf(&turtle, preset.leaf_size, preset.leaf_color)),
+            _ => {}
+        }
+    }
+
+    vertices
+}
+
+/// Grow a tree from a preset file and write it to an OBJ file.
+pub fn export_tree<P, Q>(preset: P, path: Q, seed: u32) -> Result<(), String>
+    where P: AsRef<Path>,
+          Q: AsRef<Path>
+{
+    let preset = try!(TreePreset::load(preset));
+    let mesh = preset.generate(seed);
+
+    info!(target: "DAT205",
+          "Exporting tree {} with seed {}, {} triangles",
+          preset.name,
+          seed,
+          mesh.len() / 3);
+
+    write_obj(path,
+              &[ExportObject {
+                    mesh: export_mesh(&preset.name, &mesh),
+                    instances: vec![ExportTransform::identity()],
+                }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, XorShiftRng};
+    use support::config::Config;
+
+    fn preset(content: &str) -> TreePreset {
+        TreePreset::from_config("test", &Config::parse(content)).unwrap()
+    }
+
+    fn rng(seed: u32) -> XorShiftRng {
+        XorShiftRng::from_seed([seed, 1, 2, 3])
+    }
+
+    #[test]
+    fn test_expand_rules() {
+        let algae = preset("axiom = A\nrule.A = A B\nrule.B = A\niterations = 4\n");
+        assert_eq!(algae.expand(&mut rng(1)), "ABAABABA");
+    }
+
+    #[test]
+    fn test_expand_deterministic() {
+        let bush = preset("axiom = F\nrule.F = F [ + F ] F, F [ - F ] F, F F\niterations = 5\n");
+        assert_eq!(bush.expand(&mut rng(3)), bush.expand(&mut rng(3)));
+        assert!(bush.expand(&mut rng(3)) != bush.expand(&mut rng(4)));
+    }
+
+    #[test]
+    fn test_generate_deterministic() {
+        let tree = preset("axiom = F\nrule.F = F [ + F L ] [ - F L ] F\niterations = 3\n\
+                           angle_jitter = 10\n");
+        let positions = |seed| tree.generate(seed).iter().map(|v| v.pos).collect::<Vec<_>>();
+        assert!(!positions(5).is_empty());
+        assert_eq!(positions(5), positions(5));
+    }
+}
@@ -3,5 +3,24 @@ pub mod colors;
 pub mod camera;
 pub mod renderer;
 pub mod terrain;
+pub mod heightmap;
+pub mod erosion;
+pub mod splatting;
+pub mod volume;
+pub mod planet;
+pub mod lsystem;
+pub mod biome;
+pub mod occlusion;
+pub mod lights;
+pub mod clusters;
+pub mod displacement;
+pub mod water;
+pub mod sculpt;
+pub mod roads;
+pub mod scatter;
+pub mod export;
+pub mod shadows;
+pub mod bloom;
+pub mod tonemap;
 pub mod deferred;
 pub mod skybox;
@@ -0,0 +1,106 @@
+
+use std::f32::consts::PI;
+
+use rendering::heightmap::Heightmap;
+use rendering::sculpt::GridRect;
+use support::config::Config;
+
+/// Settings of the horizon bake. Both terms look for the highest horizon
+/// in a number of directions around each sample, ambient occlusion only
+/// close by and sky visibility over a longer distance.
+#[derive(Debug, Clone)]
+pub struct OcclusionSettings {
+    pub enabled: bool,
+    pub directions: usize,
+    pub radius: f32,
+    pub sky_radius: f32,
+}
+
+impl OcclusionSettings {
+    pub fn from_config(config: &Config) -> OcclusionSettings {
+        OcclusionSettings {
+            enabled: config.get("occlusion.enabled", true),
+            directions: config.get("occlusion.directions", 16),
+            radius: config.get("occlusion.radius", 10.0),
+            sky_radius: config.get("occlusion.sky_radius", 80.0),
+        }
+    }
+}
+
+/// Unoccluded ambient occlusion and sky visibility.
+pub const UNOCCLUDED: [f32; 2] = [1.0, 1.0];
+
+// Ambient occlusion and sky visibility of a single sample. The light that
+// gets past a horizon at elevation `h` is cos^2(h) of a cosine weighted
+// hemisphere, which is averaged over all directions.
+fn sample(hm: &Heightmap, settings: &OcclusionSettings, ix: usize, iz: usize) -> [f32; 2] {
+    let p = hm.position(ix, iz);
+    let first_step = hm.cell_size();
+
+    let mut ao = 0.0;
+    let mut sky = 0.0;
+
+    for k in 0..settings.directions {
+        let angle = 2.0 * PI * k as f32 / settings.directions as f32;
+        let (dx, dz) = (angle.cos(), angle.sin());
+
+        let mut near: f32 = 0.0;
+        let mut far: f32 = 0.0;
+
+        // The steps grow with the distance, far away terrain only needs a
+        // coarse look
+        let mut t = first_step;
+        while t < settings.sky_radius {
+            let h = hm.sample(p[0] + dx * t, p[2] + dz * t);
+            let slope = (h - p[1]) / t;
+            if t <= settings.radius {
+                near = near.max(slope);
+            }
+            far = far.max(slope);
+            t += first_step.max(0.1 * t);
+        }
+
+        // cos^2(atan(s)) = 1 / (1 + s^2)
+        ao += 1.0 / (1.0 + near * near);
+        sky += 1.0 / (1.0 + far * far);
+    }
+
+    let n = settings.directions.max(1) as f32;
+    [ao / n, sky / n]
+}
+
+/// Ambient occlusion and sky visibility for every sample of the heightmap,
+/// in the same order as the heights.
+pub fn bake(hm: &Heightmap, settings: &OcclusionSettings) -> Vec<[f32; 2]> {
+    if !settings.enabled || settings.directions == 0 {
+        return vec![UNOCCLUDED; hm.width() * hm.depth()];
+    }
+
+    info!(target: "DAT205", "Baking terrain occlusion...");
+
+    let mut result = Vec::with_capacity(hm.width() * hm.depth());
+    for iz in 0..hm.depth() {
+        for ix in 0..hm.width() {
+            result.push(sample(hm, settings, ix, iz));
+        }
+    }
+    result
+}
+
+/// Bake again inside a part of the heightmap after it has been edited.
+/// Samples further away that can see the edit keep their old values until
+/// the next full bake.
+pub fn bake_region(hm: &Heightmap,
+                   settings: &OcclusionSettings,
+                   rect: GridRect,
+                   occlusion: &mut [[f32; 2]]) {
+    if !settings.enabled || settings.directions == 0 {
+        return;
+    }
+
+    for iz in rect.z0..rect.z1 + 1 {
+        for ix in rect.x0..rect.x1 + 1 {
+            occlusion[iz * hm.width() + ix] = sample(hm, settings, ix, iz);
+        }
+    }
+}
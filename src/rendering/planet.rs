@@ -0,0 +1,182 @@
+
+use na::{Point3, Vector3};
+use noise::perlin3;
+use noise;
+
+use rendering::deferred::{TerrainChunk, TerrainVertex};
+use rendering::occlusion;
+use rendering::splatting::TerrainLayers;
+use support::config::Config;
+
+#[derive(Debug, Clone)]
+pub struct PlanetSettings {
+    pub seed: u32,
+    pub radius: f32,
+    // Largest displacement above or below the radius
+    pub amplitude: f32,
+    // Quads along each side of a cube face
+    pub resolution: usize,
+    pub noise_scale: f32,
+    pub octaves: usize,
+}
+
+impl PlanetSettings {
+    pub fn from_config(config: &Config, default_seed: u32) -> PlanetSettings {
+        PlanetSettings {
+            seed: config.get("planet.seed", default_seed),
+            radius: config.get("planet.radius", 100.0),
+            amplitude: config.get("planet.amplitude", 12.0),
+            resolution: config.get("planet.resolution", 128),
+            noise_scale: config.get("planet.noise_scale", 1.5),
+            octaves: config.get("planet.octaves", 5),
+        }
+    }
+}
+
+/// A planet centered on the origin, made from a cube whose faces are
+/// projected onto a sphere and displaced along the radius by 3D noise.
+/// Everything is a function of the direction from the center, so the faces
+/// line up at the seams.
+pub struct Planet {
+    settings: PlanetSettings,
+    table: noise::PermutationTable,
+}
+
+// Map a point on the unit cube onto the unit sphere. Spreads the vertices
+// more evenly than normalizing.
+fn spherify(p: [f32; 3]) -> Vector3<f32> {
+    let (x2, y2, z2) = (p[0] * p[0], p[1] * p[1], p[2] * p[2]);
+    Vector3::new(p[0] * (1.0 - y2 / 2.0 - z2 / 2.0 + y2 * z2 / 3.0).sqrt(),
+                 p[1] * (1.0 - z2 / 2.0 - x2 / 2.0 + z2 * x2 / 3.0).sqrt(),
+                 p[2] * (1.0 - x2 / 2.0 - y2 / 2.0 + x2 * y2 / 3.0).sqrt())
+        .normalize()
+}
+
+impl Planet {
+    pub fn new(settings: PlanetSettings) -> Planet {
+        Planet {
+            table: noise::PermutationTable::new(settings.seed),
+            settings: settings,
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.settings.radius
+    }
+
+    /// Noise in about [-1, 1] for a direction from the center.
+    fn elevation(&self, dir: &Vector3<f32>) -> f32 {
+        let mut e = 0.0;
+        let mut amplitude = 0.5;
+        let mut f = self.settings.noise_scale;
+        for _ in 0..self.settings.octaves {
+            e += amplitude * perlin3(&self.table, &[f * dir.x, f * dir.y, f * dir.z]);
+            amplitude *= 0.5;
+            f *= 2.0;
+        }
+        2.0 * e
+    }
+
+    /// Distance from the center to the surface in a direction.
+    pub fn surface_radius(&self, dir: &Vector3<f32>) -> f32 {
+        self.settings.radius + self.settings.amplitude * self.elevation(dir)
+    }
+
+    pub fn surface_point(&self, dir: &Vector3<f32>) -> Point3<f32> {
+        let dir = dir.normalize();
+        Point3::from_coordinates(dir * self.surface_radius(&dir))
+    }
+
+    // Normal from the surface around a direction. The tangents only decide
+    // where the surface is sampled, so the result does not depend on which
+    // face the vertex belongs to.
+    fn normal(&self, dir: &Vector3<f32>) -> Vector3<f32> {
+        let reference = if dir.y.abs() < 0.99 { Vector3::y() } else { Vector3::x() };
+        let t1 = dir.cross(&reference).normalize();
+        let t2 = dir.cross(&t1);
+        let e = 0.5 / self.settings.resolution as f32;
+
+        let du = self.surface_point(&(dir + t1 * e)) - self.surface_point(&(dir - t1 * e));
+        let dv = self.surface_point(&(dir + t2 * e)) - self.surface_point(&(dir - t2 * e));
+
+        match du.cross(&dv).try_normalize(1.0e-8) {
+            Some(n) => n,
+            None => *dir,
+        }
+    }
+
+    fn vertex(&self, dir: &Vector3<f32>, layers: &TerrainLayers) -> TerrainVertex {
+        let pos = self.surface_point(dir);
+        let normal = self.normal(dir);
+        let slope = normal.dot(dir).max(-1.0).min(1.0).acos().to_degrees();
+        let height = 0.5 * (self.elevation(dir) + 1.0);
+        // Layer noise and textures are looked up by world position
+        let weights = layers.weights(pos.x, pos.z, height, slope);
+
+        TerrainVertex {
+            pos: [pos.x, pos.y, pos.z],
+            normal: [normal.x, normal.y, normal.z],
+            color: layers.color(&weights),
+            weights: weights,
+            occlusion: occlusion::UNOCCLUDED,
+        }
+    }
+
+    /// One chunk per cube face.
+    pub fn mesh(&self, layers: &TerrainLayers) -> Vec<TerrainChunk> {
+        let n = self.settings.resolution.max(1);
+        let row = n + 1;
+
+        info!(target: "DAT205", "Building planet with {} quads per face...", n * n);
+
+        let mut chunks = Vec::with_capacity(6);
+
+        for face in 0..6 {
+            let axis = face / 2;
+            let sign = if face % 2 == 0 { 1 } else { -1 };
+            let (b, c) = ((axis + 1) % 3, (axis + 2) % 3);
+
+            // Points are taken from an integer lattice over [-n, n], so
+            // vertices on the shared edges of two faces come out bit for bit
+            // the same
+            let mut vertices = Vec::with_capacity(row * row);
+            for j in 0..row {
+                for i in 0..row {
+                    let mut lattice = [0isize; 3];
+                    lattice[axis] = sign * n as isize;
+                    lattice[b] = 2 * i as isize - n as isize;
+                    lattice[c] = 2 * j as isize - n as isize;
+
+                    let p = [lattice[0] as f32 / n as f32,
+                             lattice[1] as f32 / n as f32,
+                             lattice[2] as f32 / n as f32];
+                    vertices.push(self.vertex(&spherify(p), layers));
+                }
+            }
+
+            let mut indices = Vec::with_capacity(n * n * 6);
+            for j in 0..n {
+                for i in 0..n {
+                    let v00 = (j * row + i) as u32;
+                    let v10 = v00 + 1;
+                    let v01 = v00 + row as u32;
+                    let v11 = v01 + 1;
+
+                    // Counter clockwise seen from outside
+                    if sign > 0 {
+                        indices.extend_from_slice(&[v00, v10, v11, v00, v11, v01]);
+                    } else {
+                        indices.extend_from_slice(&[v00, v11, v10, v00, v01, v11]);
+                    }
+                }
+            }
+
+            chunks.push(TerrainChunk {
+                vertices: vertices,
+                indices: indices,
+            });
+        }
+
+        chunks
+    }
+}
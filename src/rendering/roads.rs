@@ -0,0 +1,598 @@
+
+use gfx;
+use gfx::Bundle;
+use gfx::traits::FactoryExt;
+use glutin;
+use na::Vector3;
+
+use rendering::camera::Camera;
+use rendering::deferred::{with_normal_encoding, AlbedoFormat, Depth, MaterialFormat,
+                          NormalFormat};
+use rendering::heightmap::Heightmap;
+use rendering::sculpt::GridRect;
+use support::config::Config;
+
+gfx_defines!{
+    vertex RoadVertex {
+        pos: [f32; 3] = "a_Pos",
+        normal: [f32; 3] = "a_Normal",
+        uv: [f32; 2] = "a_Uv",
+    }
+
+    constant RoadLocals {
+        view_proj: [[f32; 4]; 4] = "u_ViewProj",
+        color: [f32; 4] = "u_RoadColor",
+        edge_color: [f32; 4] = "u_EdgeColor",
+        // Roughness, metallic, unused
+        material: [f32; 4] = "u_RoadMaterial",
+    }
+
+    pipeline road {
+        vbuf: gfx::VertexBuffer<RoadVertex> = (),
+        locals: gfx::ConstantBuffer<RoadLocals> = "RoadLocals",
+        out_normal: gfx::RenderTarget<NormalFormat> = "Target0",
+        out_color: gfx::RenderTarget<AlbedoFormat> = "Target1",
+        out_material: gfx::RenderTarget<MaterialFormat> = "Target2",
+        out_depth: gfx::DepthTarget<Depth> =
+            gfx::preset::depth::LESS_EQUAL_WRITE,
+    }
+}
+
+const ROAD_VERTEX_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform RoadLocals {
+        mat4 u_ViewProj;
+        vec4 u_RoadColor;
+        vec4 u_EdgeColor;
+        vec4 u_RoadMaterial;
+    };
+
+    in vec3 a_Pos;
+    in vec3 a_Normal;
+    in vec2 a_Uv;
+
+    out vec3 v_FragPos;
+    out vec3 v_Normal;
+    out vec2 v_Uv;
+
+    void main() {
+        v_FragPos = a_Pos;
+        v_Normal = a_Normal;
+        v_Uv = a_Uv;
+        gl_Position = u_ViewProj * vec4(a_Pos, 1.0);
+    }
+";
+
+// Worn edges and a little grain along the surface, so the road does not
+// look like a flat stripe of paint.
+const ROAD_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform RoadLocals {
+        mat4 u_ViewProj;
+        vec4 u_RoadColor;
+        vec4 u_EdgeColor;
+        vec4 u_RoadMaterial;
+    };
+
+    in vec3 v_FragPos;
+    in vec3 v_Normal;
+    in vec2 v_Uv;
+
+    out vec4 Target0;
+    out vec4 Target1;
+    out vec4 Target2;
+
+    float hash(vec2 p) {
+        return fract(sin(dot(p, vec2(12.9898, 78.233))) * 43758.5453);
+    }
+
+    void main() {
+        float edge = smoothstep(0.3, 0.5, abs(v_Uv.x - 0.5));
+        float grain = 0.9 + 0.2 * hash(floor(v_FragPos.xz * 8.0));
+        vec3 color = mix(u_RoadColor.rgb, u_EdgeColor.rgb, edge) * grain;
+
+        Target0 = vec4(encode_normal(normalize(v_Normal)), 1.0, 1.0);
+        Target1 = vec4(color, 1.0);
+        Target2 = u_RoadMaterial;
+    }
+";
+
+// Vertices across the ribbon. More than two so it bends with the terrain in
+// the falloff zone instead of cutting through it.
+const ACROSS: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct RoadSettings {
+    pub width: f32,
+    // Distance beyond the edge of the road over which the terrain blends
+    // back to its own height
+    pub falloff: f32,
+    // How far the ribbon floats above the terrain, to avoid z-fighting
+    pub lift: f32,
+    pub color: [f32; 4],
+    pub edge_color: [f32; 4],
+    pub roughness: f32,
+    pub metallic: f32,
+    pub max_vertices: usize,
+}
+
+impl RoadSettings {
+    pub fn from_config(config: &Config) -> RoadSettings {
+        let color = |key: &str, default: [f32; 4]| {
+            match config.get_floats(key) {
+                Some(ref c) if c.len() == 3 => [c[0], c[1], c[2], 1.0],
+                Some(_) => {
+                    warn!(target: "DAT205", "Expected a color for {}", key);
+                    default
+                }
+                None => default,
+            }
+        };
+
+        RoadSettings {
+            width: config.get("road.width", 4.0),
+            falloff: config.get("road.falloff", 4.0),
+            lift: config.get("road.lift", 0.05),
+            color: color("road.color", [0.3, 0.28, 0.25, 1.0]),
+            edge_color: color("road.edge_color", [0.4, 0.35, 0.25, 1.0]),
+            roughness: config.get("road.roughness", 0.85),
+            metallic: config.get("road.metallic", 0.0),
+            max_vertices: config.get("road.max_vertices", 65536),
+        }
+    }
+}
+
+/// A path through a list of control points on the xz plane. The center line
+/// is a Catmull-Rom spline, so it passes through every point.
+#[derive(Debug, Clone)]
+pub struct Road {
+    pub points: Vec<[f32; 2]>,
+    pub width: f32,
+    pub falloff: f32,
+}
+
+fn catmull_rom(p0: [f32; 2], p1: [f32; 2], p2: [f32; 2], p3: [f32; 2], t: f32) -> [f32; 2] {
+    let (t2, t3) = (t * t, t * t * t);
+    let mut r = [0.0; 2];
+    for k in 0..2 {
+        r[k] = 0.5 *
+               (2.0 * p1[k] + (p2[k] - p0[k]) * t +
+                (2.0 * p0[k] - 5.0 * p1[k] + 4.0 * p2[k] - p3[k]) * t2 +
+                (3.0 * p1[k] - p0[k] - 3.0 * p2[k] + p3[k]) * t3);
+    }
+    r
+}
+
+fn distance(a: [f32; 2], b: [f32; 2]) -> f32 {
+    ((b[0] - a[0]) * (b[0] - a[0]) + (b[1] - a[1]) * (b[1] - a[1])).sqrt()
+}
+
+fn smoothstep(e0: f32, e1: f32, x: f32) -> f32 {
+    let t = ((x - e0) / (e1 - e0)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Normal at any point of the terrain, the same central differences as
+// `Heightmap::normal` but on interpolated heights.
+fn surface_normal(hm: &Heightmap, x: f32, z: f32) -> [f32; 3] {
+    let e = hm.cell_size();
+    let dhdx = (hm.sample(x + e, z) - hm.sample(x - e, z)) / (2.0 * e);
+    let dhdz = (hm.sample(x, z + e) - hm.sample(x, z - e)) / (2.0 * e);
+    Vector3::new(-dhdx, 1.0, -dhdz).normalize().into()
+}
+
+impl Road {
+    pub fn new(width: f32, falloff: f32) -> Road {
+        Road {
+            points: Vec::new(),
+            width: width,
+            falloff: falloff,
+        }
+    }
+
+    /// Points along the center line, about `spacing` apart.
+    pub fn center_line(&self, spacing: f32) -> Vec<[f32; 2]> {
+        let n = self.points.len();
+        if n < 2 {
+            return self.points.clone();
+        }
+
+        // The end points are repeated to give the first and last segments
+        // a tangent
+        let p = |i: isize| self.points[i.max(0).min(n as isize - 1) as usize];
+
+        let mut line = vec![self.points[0]];
+        for i in 0..n as isize - 1 {
+            let steps = (distance(p(i), p(i + 1)) / spacing).ceil().max(1.0) as usize;
+            for s in 1..steps + 1 {
+                let t = s as f32 / steps as f32;
+                line.push(catmull_rom(p(i - 1), p(i), p(i + 1), p(i + 2), t));
+            }
+        }
+        line
+    }
+
+    /// Flatten the terrain under the road. The road surface follows the
+    /// terrain along the center line, smoothed so it has no bumps, and the
+    /// terrain beside it blends back over the falloff distance. Returns the
+    /// samples that changed.
+    pub fn flatten(&self, hm: &mut Heightmap) -> Option<GridRect> {
+        let line = self.center_line(0.5 * hm.cell_size());
+        if line.len() < 2 {
+            return None;
+        }
+
+        // Box filter the heights along the line, over about a road width
+        let mut targets: Vec<f32> = line.iter().map(|c| hm.sample(c[0], c[1])).collect();
+        let radius = (self.width / (0.5 * hm.cell_size())).ceil() as usize;
+        for _ in 0..3 {
+            targets = (0..targets.len())
+                .map(|i| {
+                    let lo = i.saturating_sub(radius);
+                    let hi = (i + radius + 1).min(targets.len());
+                    targets[lo..hi].iter().fold(0.0, |a, b| a + b) / (hi - lo) as f32
+                })
+                .collect();
+        }
+
+        let half = 0.5 * self.width;
+        let reach = half + self.falloff;
+
+        let min = line.iter().fold([::std::f32::MAX; 2], |m, c| [m[0].min(c[0]), m[1].min(c[1])]);
+        let max = line.iter().fold([::std::f32::MIN; 2], |m, c| [m[0].max(c[0]), m[1].max(c[1])]);
+        let (gx0, gz0) = hm.to_grid(min[0] - reach, min[1] - reach);
+        let (gx1, gz1) = hm.to_grid(max[0] + reach, max[1] + reach);
+        let rect = GridRect {
+            x0: gx0.floor().max(0.0) as usize,
+            z0: gz0.floor().max(0.0) as usize,
+            x1: (gx1.ceil().max(0.0) as usize).min(hm.width() - 1),
+            z1: (gz1.ceil().max(0.0) as usize).min(hm.depth() - 1),
+        };
+        if rect.x0 > rect.x1 || rect.z0 > rect.z1 {
+            return None;
+        }
+
+        // Closest point on the line for every sample in reach, found one
+        // segment at a time so only the samples near each segment are tested
+        let rw = rect.x1 - rect.x0 + 1;
+        let mut closest = vec![(::std::f32::MAX, 0.0); rw * (rect.z1 - rect.z0 + 1)];
+        let cells = reach / hm.cell_size();
+
+        for i in 0..line.len() - 1 {
+            let (a, b) = (line[i], line[i + 1]);
+            let ab = [b[0] - a[0], b[1] - a[1]];
+            let len_sq = (ab[0] * ab[0] + ab[1] * ab[1]).max(1.0e-8);
+
+            let (ax, az) = hm.to_grid(a[0], a[1]);
+            let (bx, bz) = hm.to_grid(b[0], b[1]);
+            let x0 = (ax.min(bx) - cells).floor().max(rect.x0 as f32) as usize;
+            let z0 = (az.min(bz) - cells).floor().max(rect.z0 as f32) as usize;
+            let x1 = ((ax.max(bx) + cells).ceil().max(0.0) as usize).min(rect.x1);
+            let z1 = ((az.max(bz) + cells).ceil().max(0.0) as usize).min(rect.z1);
+
+            for iz in z0..z1 + 1 {
+                for ix in x0..x1 + 1 {
+                    let p = hm.position(ix, iz);
+                    let ap = [p[0] - a[0], p[2] - a[1]];
+                    let t = ((ap[0] * ab[0] + ap[1] * ab[1]) / len_sq).max(0.0).min(1.0);
+                    let d = distance([a[0] + t * ab[0], a[1] + t * ab[1]], [p[0], p[2]]);
+
+                    let c = &mut closest[(iz - rect.z0) * rw + ix - rect.x0];
+                    if d < c.0 {
+                        *c = (d, targets[i] + t * (targets[i + 1] - targets[i]));
+                    }
+                }
+            }
+        }
+
+        for iz in rect.z0..rect.z1 + 1 {
+            for ix in rect.x0..rect.x1 + 1 {
+                let (d, target) = closest[(iz - rect.z0) * rw + ix - rect.x0];
+                if d >= reach {
+                    continue;
+                }
+                let w = 1.0 - smoothstep(half, reach, d);
+                let h = hm.height(ix, iz);
+                hm.set_height(ix, iz, h + (target - h) * w);
+            }
+        }
+
+        Some(rect)
+    }
+
+    /// Ribbon along the center line, draped over the terrain.
+    pub fn mesh(&self, hm: &Heightmap, lift: f32) -> (Vec<RoadVertex>, Vec<u32>) {
+        let line = self.center_line(hm.cell_size());
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        if line.len() < 2 {
+            return (vertices, indices);
+        }
+
+        let mut along = 0.0;
+        for i in 0..line.len() {
+            let prev = line[i.saturating_sub(1)];
+            let next = line[(i + 1).min(line.len() - 1)];
+            let dir = [next[0] - prev[0], next[1] - prev[1]];
+            let len = (dir[0] * dir[0] + dir[1] * dir[1]).sqrt().max(1.0e-6);
+            let side = [-dir[1] / len, dir[0] / len];
+
+            if i > 0 {
+                along += distance(line[i - 1], line[i]);
+            }
+
+            for j in 0..ACROSS {
+                let u = j as f32 / (ACROSS - 1) as f32;
+                let offset = (u - 0.5) * self.width;
+                let x = line[i][0] + side[0] * offset;
+                let z = line[i][1] + side[1] * offset;
+                vertices.push(RoadVertex {
+                    pos: [x, hm.sample(x, z) + lift, z],
+                    normal: surface_normal(hm, x, z),
+                    uv: [u, along / self.width],
+                });
+            }
+        }
+
+        for i in 0..line.len() - 1 {
+            for j in 0..ACROSS - 1 {
+                let v00 = (i * ACROSS + j) as u32;
+                let v01 = v00 + 1;
+                let v10 = v00 + ACROSS as u32;
+                let v11 = v10 + 1;
+                indices.extend_from_slice(&[v00, v10, v11, v00, v11, v01]);
+            }
+        }
+
+        (vertices, indices)
+    }
+
+    /// One line of the terrain edits file, `road <width> <falloff> x z ...`.
+    pub fn to_line(&self) -> String {
+        let mut line = format!("road {} {}", self.width, self.falloff);
+        for p in &self.points {
+            line.push_str(&format!(" {} {}", p[0], p[1]));
+        }
+        line
+    }
+
+    /// Parse the tokens of a line written by `to_line`, without the leading
+    /// `road`. The width has to be positive and the falloff can not be
+    /// negative.
+    pub fn from_tokens(tokens: &[&str]) -> Option<Road> {
+        let values: Result<Vec<f32>, _> = tokens.iter().map(|t| t.parse::<f32>()).collect();
+        match values {
+            Ok(ref v) if v.len() >= 2 && v.len() % 2 == 0 && v[0] > 0.0 && v[1] >= 0.0 => {
+                Some(Road {
+                    width: v[0],
+                    falloff: v[1],
+                    points: v[2..].chunks(2).map(|p| [p[0], p[1]]).collect(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Roads on the terrain, and the one being laid out with the mouse. Every
+/// left click adds a control point and return finishes the road, the right
+/// mouse button is left to the camera.
+pub struct Roads<R: gfx::Resources> {
+    settings: RoadSettings,
+    roads: Vec<Road>,
+    placing: Option<Road>,
+    cursor: (f32, f32),
+    clicked: bool,
+    finish: bool,
+    dirty: bool,
+    bundle: Bundle<R, road::Data<R>>,
+    ibuf: gfx::handle::Buffer<R, u32>,
+}
+
+impl<R: gfx::Resources> Roads<R> {
+    pub fn new<F: gfx::Factory<R>>(factory: &mut F,
+                                   settings: RoadSettings,
+                                   out_normal: gfx::handle::RenderTargetView<R, NormalFormat>,
+                                   out_color: gfx::handle::RenderTargetView<R, AlbedoFormat>,
+                                   out_material: gfx::handle::RenderTargetView<R, MaterialFormat>,
+                                   out_depth: gfx::handle::DepthStencilView<R, Depth>)
+                                   -> Self {
+        let pso = factory.create_pipeline_simple(ROAD_VERTEX_SHADER,
+                                    &with_normal_encoding(ROAD_FRAGMENT_SHADER),
+                                    road::new())
+            .unwrap();
+
+        // All roads share one buffer, rebuilt whenever a road or the terrain
+        // under it changes
+        let vbuf = factory.create_buffer(settings.max_vertices,
+                           gfx::buffer::Role::Vertex,
+                           gfx::memory::Usage::Dynamic,
+                           gfx::Bind::empty())
+            .unwrap();
+        let ibuf = factory.create_buffer(6 * settings.max_vertices,
+                           gfx::buffer::Role::Index,
+                           gfx::memory::Usage::Dynamic,
+                           gfx::Bind::empty())
+            .unwrap();
+
+        let slice = gfx::Slice {
+            start: 0,
+            end: 0,
+            base_vertex: 0,
+            instances: None,
+            buffer: gfx::IndexBuffer::Index32(ibuf.clone()),
+        };
+
+        let data = road::Data {
+            vbuf: vbuf,
+            locals: factory.create_constant_buffer(1),
+            out_normal: out_normal,
+            out_color: out_color,
+            out_material: out_material,
+            out_depth: out_depth,
+        };
+
+        Roads {
+            settings: settings,
+            roads: Vec::new(),
+            placing: None,
+            cursor: (0.0, 0.0),
+            clicked: false,
+            finish: false,
+            dirty: false,
+            bundle: Bundle::new(slice, pso, data),
+            ibuf: ibuf,
+        }
+    }
+
+    pub fn roads(&self) -> &[Road] {
+        &self.roads
+    }
+
+    /// Replace all roads, e.g. with the ones from a terrain edits file. The
+    /// terrain is expected to be flattened under them already.
+    pub fn set_roads(&mut self, roads: Vec<Road>) {
+        self.roads = roads;
+        self.placing = None;
+        self.dirty = true;
+    }
+
+    pub fn is_placing(&self) -> bool {
+        self.placing.is_some()
+    }
+
+    /// Start laying out a new road, with the configured width if None.
+    pub fn begin(&mut self, width: Option<f32>) {
+        let width = width.unwrap_or(self.settings.width).max(0.1);
+        self.placing = Some(Road::new(width, self.settings.falloff));
+        self.dirty = true;
+        info!(target: "DAT205", "Placing a road, left click to add points, return to finish");
+    }
+
+    /// Finish the road being laid out. Returns it if it has enough points to
+    /// be a road, the terrain is left to the caller to flatten.
+    pub fn end(&mut self) -> Option<Road> {
+        self.dirty = true;
+        match self.placing.take() {
+            Some(road) => {
+                if road.points.len() < 2 {
+                    info!(target: "DAT205", "A road needs at least two points");
+                    return None;
+                }
+                info!(target: "DAT205", "Added a road with {} points", road.points.len());
+                self.roads.push(road.clone());
+                Some(road)
+            }
+            None => None,
+        }
+    }
+
+    /// Remove all roads. The terrain keeps its flattened shape.
+    pub fn clear(&mut self) {
+        self.roads.clear();
+        self.placing = None;
+        self.dirty = true;
+    }
+
+    /// The ribbons follow the terrain, so they have to be rebuilt after it
+    /// changes.
+    pub fn terrain_changed(&mut self) {
+        self.dirty = !self.roads.is_empty() || self.placing.is_some();
+    }
+
+    pub fn process_input(&mut self, event: &glutin::Event) {
+        use glutin::{ElementState, Event, MouseButton, VirtualKeyCode};
+
+        match event {
+            &Event::MouseMoved(x, y) => {
+                self.cursor = (x as f32, y as f32);
+            }
+            &Event::MouseInput(ElementState::Pressed, MouseButton::Left) => {
+                self.clicked = true;
+            }
+            &Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Return)) => {
+                self.finish = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Add the point under the cursor to the road being laid out. Returns a
+    /// road when return has finished it.
+    pub fn update(&mut self,
+                  hm: &Heightmap,
+                  cam: &Camera,
+                  viewport: (f32, f32))
+                  -> Option<Road> {
+        if self.clicked && self.placing.is_some() {
+            let (origin, dir) =
+                cam.screen_ray(self.cursor.0, self.cursor.1, viewport.0, viewport.1);
+            if let Some(hit) = hm.raycast(origin, dir, 10000.0) {
+                if let Some(ref mut road) = self.placing {
+                    road.points.push([hit.x, hit.z]);
+                }
+                self.dirty = true;
+            }
+        }
+        self.clicked = false;
+
+        if self.finish {
+            self.finish = false;
+            return self.end();
+        }
+        None
+    }
+
+    pub fn render<C: gfx::CommandBuffer<R>>(&mut self,
+                                            encoder: &mut gfx::Encoder<R, C>,
+                                            hm: &Heightmap,
+                                            view_proj: [[f32; 4]; 4]) {
+        if self.dirty {
+            self.rebuild(encoder, hm);
+        }
+
+        if self.bundle.slice.end == 0 {
+            return;
+        }
+
+        let locals = RoadLocals {
+            view_proj: view_proj,
+            color: self.settings.color,
+            edge_color: self.settings.edge_color,
+            material: [self.settings.roughness, self.settings.metallic, 0.0, 0.0],
+        };
+        encoder.update_constant_buffer(&self.bundle.data.locals, &locals);
+        self.bundle.encode(encoder);
+    }
+
+    fn rebuild<C: gfx::CommandBuffer<R>>(&mut self,
+                                         encoder: &mut gfx::Encoder<R, C>,
+                                         hm: &Heightmap) {
+        self.dirty = false;
+
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for road in self.roads.iter().chain(self.placing.iter()) {
+            let (v, i) = road.mesh(hm, self.settings.lift);
+            if vertices.len() + v.len() > self.settings.max_vertices {
+                warn!(target: "DAT205",
+                      "Roads need more than {} vertices, increase road.max_vertices",
+                      self.settings.max_vertices);
+                break;
+            }
+            let base = vertices.len() as u32;
+            indices.extend(i.iter().map(|i| i + base));
+            vertices.extend(v);
+        }
+
+        if !vertices.is_empty() {
+            encoder.update_buffer(&self.bundle.data.vbuf, &vertices, 0).unwrap();
+            encoder.update_buffer(&self.ibuf, &indices, 0).unwrap();
+        }
+        self.bundle.slice.end = indices.len() as u32;
+    }
+}
@@ -0,0 +1,571 @@
+
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use gfx;
+use gfx::Bundle;
+use gfx::traits::FactoryExt;
+use genmesh::{Polygon, Triangle, Triangulate};
+use genmesh::generators::{Cylinder, SphereUV};
+use na::{Point3, Vector3};
+use noise::perlin2;
+use noise;
+use obj;
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use rendering::biome::BiomeMap;
+use rendering::deferred::{terrain_range, with_normal_encoding, AlbedoFormat, Depth,
+                          MaterialFormat, NormalFormat};
+use rendering::export::{ExportMesh, ExportObject, ExportTransform};
+use rendering::heightmap::Heightmap;
+use rendering::lsystem::TreePreset;
+use support::config::Config;
+
+gfx_defines!{
+    vertex ScatterVertex {
+        pos: [f32; 3] = "a_Pos",
+        normal: [f32; 3] = "a_Normal",
+        color: [f32; 3] = "a_Color",
+    }
+
+    vertex ScatterInstance {
+        translate: [f32; 4] = "a_Translate",
+        rotation: [f32; 2] = "a_Rotation",
+    }
+
+    constant ScatterLocals {
+        view_proj: [[f32; 4]; 4] = "u_ViewProj",
+    }
+
+    constant ScatterMaterial {
+        // Roughness, metallic, unused
+        material: [f32; 4] = "u_Material",
+    }
+
+    pipeline scatter {
+        vbuf: gfx::VertexBuffer<ScatterVertex> = (),
+        instances: gfx::InstanceBuffer<ScatterInstance> = (),
+        locals: gfx::ConstantBuffer<ScatterLocals> = "ScatterLocals",
+        material: gfx::ConstantBuffer<ScatterMaterial> = "ScatterMaterial",
+        out_normal: gfx::RenderTarget<NormalFormat> = "Target0",
+        out_color: gfx::RenderTarget<AlbedoFormat> = "Target1",
+        out_material: gfx::RenderTarget<MaterialFormat> = "Target2",
+        out_depth: gfx::DepthTarget<Depth> =
+            gfx::preset::depth::LESS_EQUAL_WRITE,
+    }
+}
+
+const SCATTER_VERTEX_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform ScatterLocals {
+        mat4 u_ViewProj;
+    };
+
+    in vec3 a_Pos;
+    in vec3 a_Normal;
+    in vec3 a_Color;
+
+    // xyz is the position on the terrain, w the uniform scale
+    in vec4 a_Translate;
+    // Cosine and sine of the rotation around the y axis
+    in vec2 a_Rotation;
+
+    out vec3 v_FragPos;
+    out vec3 v_Normal;
+    out vec3 v_Color;
+
+    void main() {
+        mat3 rot = mat3(a_Rotation.x, 0.0, -a_Rotation.y,
+                        0.0,          1.0, 0.0,
+                        a_Rotation.y, 0.0, a_Rotation.x);
+
+        v_FragPos = rot * (a_Pos * a_Translate.w) + a_Translate.xyz;
+        v_Normal = rot * a_Normal;
+        v_Color = a_Color;
+        gl_Position = u_ViewProj * vec4(v_FragPos, 1.0);
+    }
+";
+
+const SCATTER_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform ScatterMaterial {
+        vec4 u_Material;
+    };
+
+    in vec3 v_Normal;
+    in vec3 v_Color;
+
+    out vec4 Target0;
+    out vec4 Target1;
+    out vec4 Target2;
+
+    void main() {
+        Target0 = vec4(encode_normal(normalize(v_Normal)), 1.0, 1.0);
+        Target1 = vec4(v_Color, 1.0);
+        Target2 = u_Material;
+    }
+";
+
+/// Where and how densely a mesh is placed. Heights are normalized between
+/// the lowest and highest point of the terrain and slopes are in degrees,
+/// like the terrain material layers.
+#[derive(Debug, Clone)]
+pub struct ScatterRule {
+    pub name: String,
+    pub seed: u32,
+    pub spacing: f32,
+    pub density: f32,
+    pub height: (f32, f32),
+    pub slope: (f32, f32),
+    pub noise_scale: f32,
+    pub noise_threshold: f32,
+    pub scale: (f32, f32),
+    pub draw_distance: f32,
+    pub max_instances: usize,
+    pub roughness: f32,
+    pub metallic: f32,
+    pub mesh: Vec<ScatterVertex>,
+}
+
+/// Flat shaded triangles, y up. genmesh builds its primitives around the
+/// z axis, so they are rotated by `transform` first.
+pub fn flat_mesh<I, F>(triangles: I, color: [f32; 3], transform: F) -> Vec<ScatterVertex>
+    where I: Iterator<Item = Triangle<(f32, f32, f32)>>,
+          F: Fn((f32, f32, f32)) -> Point3<f32>
+{
+    let mut vertices = Vec::new();
+
+    for t in triangles {
+        let (a, b, c) = (transform(t.x), transform(t.y), transform(t.z));
+        let n = match (b - a).cross(&(c - a)).try_normalize(1.0e-8) {
+            Some(n) => n,
+            None => continue,
+        };
+
+        for p in &[a, b, c] {
+            vertices.push(ScatterVertex {
+                pos: [p.x, p.y, p.z],
+                normal: [n.x, n.y, n.z],
+                color: color,
+            });
+        }
+    }
+
+    vertices
+}
+
+fn tree_mesh(color: [f32; 3]) -> Vec<ScatterVertex> {
+    let trunk_color = [0.2421, 0.1406, 0.1406];
+
+    let trunk = flat_mesh(Cylinder::new(6).triangulate(), trunk_color, |(x, y, z)| {
+        Point3::new(0.15 * x, 0.75 * (z + 1.0), 0.15 * y)
+    });
+
+    // A cylinder with its top ring pinched into a point makes a cone
+    let canopy = flat_mesh(Cylinder::new(8).triangulate(), color, |(x, y, z)| {
+        if z > 0.0 {
+            Point3::new(0.0, 5.0, 0.0)
+        } else {
+            Point3::new(1.2 * x, 1.2, 1.2 * y)
+        }
+    });
+
+    trunk.into_iter().chain(canopy.into_iter()).collect()
+}
+
+fn rock_mesh(color: [f32; 3], seed: u32) -> Vec<ScatterVertex> {
+    let table = noise::PermutationTable::new(seed);
+    flat_mesh(SphereUV::new(7, 5).triangulate(), color, |(x, y, z)| {
+        let r = 1.0 + 0.35 * perlin2(&table, &[2.0 * x + z, 2.0 * y - z]);
+        Point3::new(r * x, 0.6 * r * z, r * y)
+    })
+}
+
+fn grass_mesh(color: [f32; 3]) -> Vec<ScatterVertex> {
+    // Three crossed blades, each one a quad that is visible from both sides
+    let blades = (0..3).flat_map(|i| {
+        let a = i as f32 * PI / 3.0;
+        let (dx, dz) = (0.5 * a.cos(), 0.5 * a.sin());
+        let quad = [(-dx, 0.0, -dz), (dx, 0.0, dz), (0.6 * dx, 0.8, 0.6 * dz), (-0.6 * dx, 0.8, -0.6 * dz)];
+        vec![Polygon::PolyQuad(::genmesh::Quad::new(quad[0], quad[1], quad[2], quad[3])),
+             Polygon::PolyQuad(::genmesh::Quad::new(quad[3], quad[2], quad[1], quad[0]))]
+    });
+
+    flat_mesh(blades.triangulate(), color, |(x, y, z)| Point3::new(x, y, z))
+}
+
+fn load_model(path: &Path, color: [f32; 3]) -> Result<Vec<ScatterVertex>, String> {
+    let input = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(reason) => return Err(format!("Could not open model {}: {}", path.display(), reason)),
+    };
+
+    let model: obj::Obj = match obj::load_obj(input) {
+        Ok(model) => model,
+        Err(reason) => {
+            return Err(format!("Could not load model {}: {:?}", path.display(), reason))
+        }
+    };
+
+    Ok(model.indices
+        .iter()
+        .map(|&i| {
+            let v = &model.vertices[i as usize];
+            ScatterVertex {
+                pos: v.position,
+                normal: v.normal,
+                color: color,
+            }
+        })
+        .collect())
+}
+
+/// Unindexed scatter triangles as a mesh that can be exported.
+pub fn export_mesh(name: &str, mesh: &[ScatterVertex]) -> ExportMesh {
+    ExportMesh {
+        name: name.to_owned(),
+        positions: mesh.iter().map(|v| v.pos).collect(),
+        normals: mesh.iter().map(|v| v.normal).collect(),
+        colors: mesh.iter().map(|v| v.color).collect(),
+        indices: (0..mesh.len() as u32).collect(),
+    }
+}
+
+/// Read the scatter rules from a config. `rules` lists the rule names and
+/// every other key is prefixed with the name of its rule. `mesh` selects a
+/// built in mesh (tree, rock or grass), `model` loads an OBJ file and
+/// `lsystem` grows a tree from a preset instead, both relative to
+/// `asset_dir`. `roughness` and `metallic` set the material of the mesh.
+/// Ranges given as `max, min` are swapped around.
+pub fn load_rules(config: &Config, asset_dir: &Path) -> Vec<ScatterRule> {
+    let pair = |key: String, default: (f32, f32)| match config.get_floats(&key) {
+        Some(ref v) if v.len() == 2 => (v[0].min(v[1]), v[0].max(v[1])),
+        _ => default,
+    };
+
+    config.get_str("rules")
+        .unwrap_or("")
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .enumerate()
+        .filter_map(|(i, name)| {
+            let key = |k: &str| format!("{}.{}", name, k);
+
+            let color = match config.get_floats(&key("color")) {
+                Some(ref c) if c.len() == 3 => [c[0], c[1], c[2]],
+                _ => [1.0, 1.0, 1.0],
+            };
+            let seed = config.get(&key("seed"), i as u32 + 1);
+
+            let model = config.get_str(&key("model"));
+            let lsystem = config.get_str(&key("lsystem"));
+
+            let mesh = match (model, lsystem, config.get_str(&key("mesh"))) {
+                (Some(path), _, _) => load_model(&asset_dir.join(path), color),
+                (None, Some(path), _) => {
+                    TreePreset::load(asset_dir.join(path)).map(|preset| preset.generate(seed))
+                }
+                (None, None, Some("tree")) => Ok(tree_mesh(color)),
+                (None, None, Some("rock")) => Ok(rock_mesh(color, seed)),
+                (None, None, Some("grass")) => Ok(grass_mesh(color)),
+                (None, None, other) => {
+                    Err(format!("Unknown scatter mesh {:?} for {}", other, name))
+                }
+            };
+
+            match mesh {
+                Ok(mesh) => {
+                    Some(ScatterRule {
+                        name: name.to_owned(),
+                        seed: seed,
+                        spacing: config.get(&key("spacing"), 5.0),
+                        density: config.get(&key("density"), 1.0),
+                        height: pair(key("height"), (-1.0, 2.0)),
+                        slope: pair(key("slope"), (-1.0, 91.0)),
+                        noise_scale: config.get(&key("noise_scale"), 0.02),
+                        noise_threshold: config.get(&key("noise_threshold"), -1.0),
+                        scale: pair(key("scale"), (1.0, 1.0)),
+                        draw_distance: config.get(&key("draw_distance"), 200.0),
+                        max_instances: config.get(&key("max_instances"), 20000),
+                        roughness: config.get(&key("roughness"), 0.8),
+                        metallic: config.get(&key("metallic"), 0.0),
+                        mesh: mesh,
+                    })
+                }
+                Err(e) => {
+                    error!(target: "DAT205", "{}", e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Bridson's Poisson disk sampling over [-half_w, half_w] x [-half_d,
+/// half_d]. No two points are closer than `r`.
+fn poisson_disk<G: Rng>(rng: &mut G, half_w: f32, half_d: f32, r: f32) -> Vec<(f32, f32)> {
+    let cell = r / 2.0f32.sqrt();
+    let gw = (2.0 * half_w / cell).ceil() as usize + 1;
+    let gd = (2.0 * half_d / cell).ceil() as usize + 1;
+    let mut grid: Vec<Option<usize>> = vec![None; gw * gd];
+
+    let to_cell = |p: (f32, f32)| {
+        (((p.0 + half_w) / cell) as usize, ((p.1 + half_d) / cell) as usize)
+    };
+
+    let mut points = Vec::new();
+    let mut active = Vec::new();
+
+    let first = (rng.gen_range(-half_w, half_w), rng.gen_range(-half_d, half_d));
+    let (cx, cz) = to_cell(first);
+    grid[cz * gw + cx] = Some(0);
+    points.push(first);
+    active.push(0);
+
+    while !active.is_empty() {
+        let a = rng.gen_range(0, active.len());
+        let origin = points[active[a]];
+        let mut found = false;
+
+        for _ in 0..30 {
+            let angle = rng.gen_range(0.0, 2.0 * PI);
+            let dist = rng.gen_range(r, 2.0 * r);
+            let p = (origin.0 + dist * angle.cos(), origin.1 + dist * angle.sin());
+
+            if p.0 < -half_w || p.0 >= half_w || p.1 < -half_d || p.1 >= half_d {
+                continue;
+            }
+
+            let (px, pz) = to_cell(p);
+            let mut free = true;
+            'search: for nz in pz.saturating_sub(2)..(pz + 3).min(gd) {
+                for nx in px.saturating_sub(2)..(px + 3).min(gw) {
+                    if let Some(j) = grid[nz * gw + nx] {
+                        let q = points[j];
+                        if (q.0 - p.0) * (q.0 - p.0) + (q.1 - p.1) * (q.1 - p.1) < r * r {
+                            free = false;
+                            break 'search;
+                        }
+                    }
+                }
+            }
+
+            if free {
+                grid[pz * gw + px] = Some(points.len());
+                active.push(points.len());
+                points.push(p);
+                found = true;
+                break;
+            }
+        }
+
+        if !found {
+            active.swap_remove(a);
+        }
+    }
+
+    points
+}
+
+/// Place the instances of one rule on the terrain. The result only depends
+/// on the rule, the heightmap and the biomes, which scale the density.
+pub fn place(rule: &ScatterRule,
+             hm: &Heightmap,
+             biomes: Option<&BiomeMap>)
+             -> Vec<ScatterInstance> {
+    let mut rng = XorShiftRng::from_seed([rule.seed, 0x2545_f491, 0x9e37_79b9, 0x7f4a_7c15]);
+    let mask = noise::PermutationTable::new(rule.seed);
+
+    let (min, range) = terrain_range(hm);
+
+    let half_w = 0.5 * (hm.width() - 1) as f32 * hm.cell_size();
+    let half_d = 0.5 * (hm.depth() - 1) as f32 * hm.cell_size();
+
+    let mut instances = Vec::new();
+
+    for (x, z) in poisson_disk(&mut rng, half_w, half_d, rule.spacing.max(0.1)) {
+        // Always draw the random numbers, so rejecting one point does not
+        // shift the ones after it
+        let keep = rng.next_f32();
+        let scale = rng.gen_range(rule.scale.0, rule.scale.1 + 1.0e-4);
+        let angle = rng.gen_range(0.0, 2.0 * PI);
+
+        let y = hm.sample(x, z);
+        let h = (y - min) / range;
+
+        let (gx, gz) = hm.to_grid(x, z);
+        let n = hm.normal((gx.round() as usize).min(hm.width() - 1),
+                          (gz.round() as usize).min(hm.depth() - 1));
+        let slope = n[1].max(-1.0).min(1.0).acos().to_degrees();
+
+        let m = perlin2(&mask, &[rule.noise_scale * x, rule.noise_scale * z]);
+
+        let density = match biomes {
+            Some(biomes) => rule.density * biomes.vegetation_density(x, z, &rule.name),
+            None => rule.density,
+        };
+
+        if h < rule.height.0 || h > rule.height.1 || slope < rule.slope.0 ||
+           slope > rule.slope.1 || m < rule.noise_threshold || keep > density {
+            continue;
+        }
+
+        if instances.len() >= rule.max_instances {
+            warn!(target: "DAT205", "Too many {} instances, limit is {}", rule.name, rule.max_instances);
+            break;
+        }
+
+        instances.push(ScatterInstance {
+            translate: [x, y, z, scale],
+            rotation: [angle.cos(), angle.sin()],
+        });
+    }
+
+    instances
+}
+
+struct ScatterType<R: gfx::Resources> {
+    rule: ScatterRule,
+    instances: Vec<ScatterInstance>,
+    bundle: Bundle<R, scatter::Data<R>>,
+}
+
+pub struct Scatter<R: gfx::Resources> {
+    types: Vec<ScatterType<R>>,
+    visible: Vec<ScatterInstance>,
+}
+
+impl<R: gfx::Resources> Scatter<R> {
+    /// The instances are placed once the terrain is uploaded, see `replace`.
+    pub fn new<F: gfx::Factory<R>>(factory: &mut F,
+                                   rules: Vec<ScatterRule>,
+                                   out_normal: gfx::handle::RenderTargetView<R, NormalFormat>,
+                                   out_color: gfx::handle::RenderTargetView<R, AlbedoFormat>,
+                                   out_material: gfx::handle::RenderTargetView<R, MaterialFormat>,
+                                   out_depth: gfx::handle::DepthStencilView<R, Depth>)
+                                   -> Self {
+        let pso = factory.create_pipeline_simple(SCATTER_VERTEX_SHADER,
+                                    &with_normal_encoding(SCATTER_FRAGMENT_SHADER),
+                                    scatter::new())
+            .unwrap();
+        let locals = factory.create_constant_buffer(1);
+
+        let types = rules.into_iter()
+            .map(|rule| {
+                let (vbuf, mut slice) = factory.create_vertex_buffer_with_slice(&rule.mesh, ());
+                slice.instances = Some((0, 0));
+
+                let instance_buf = factory.create_buffer(rule.max_instances,
+                                   gfx::buffer::Role::Vertex,
+                                   gfx::memory::Usage::Dynamic,
+                                   gfx::Bind::empty())
+                    .unwrap();
+
+                let data = scatter::Data {
+                    vbuf: vbuf,
+                    instances: instance_buf,
+                    locals: locals.clone(),
+                    material: factory.create_constant_buffer(1),
+                    out_normal: out_normal.clone(),
+                    out_color: out_color.clone(),
+                    out_material: out_material.clone(),
+                    out_depth: out_depth.clone(),
+                };
+
+                ScatterType {
+                    rule: rule,
+                    instances: Vec::new(),
+                    bundle: Bundle::new(slice, pso.clone(), data),
+                }
+            })
+            .collect();
+
+        Scatter {
+            types: types,
+            visible: Vec::new(),
+        }
+    }
+
+    /// Place everything again, e.g. after the terrain has been eroded.
+    pub fn replace(&mut self, hm: &Heightmap, biomes: Option<&BiomeMap>) {
+        info!(target: "DAT205", "Scattering objects...");
+        for t in self.types.iter_mut() {
+            t.instances = place(&t.rule, hm, biomes);
+            info!(target: "DAT205", "Placed {} {}", t.instances.len(), t.rule.name);
+        }
+    }
+
+    /// Meshes and placements of everything scattered, for exporting.
+    pub fn export_objects(&self) -> Vec<ExportObject> {
+        self.types
+            .iter()
+            .map(|t| {
+                ExportObject {
+                    mesh: export_mesh(&t.rule.name, &t.rule.mesh),
+                    instances: t.instances
+                        .iter()
+                        .map(|inst| {
+                            ExportTransform {
+                                translation: [inst.translate[0], inst.translate[1], inst.translate[2]],
+                                rotation_y: inst.rotation[1].atan2(inst.rotation[0]),
+                                scale: inst.translate[3],
+                            }
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Move the instances back onto the surface after it has been edited.
+    pub fn snap_to_surface(&mut self, hm: &Heightmap) {
+        for t in self.types.iter_mut() {
+            for inst in t.instances.iter_mut() {
+                inst.translate[1] = hm.sample(inst.translate[0], inst.translate[2]);
+            }
+        }
+    }
+
+    pub fn render<C: gfx::CommandBuffer<R>>(&mut self,
+                                            encoder: &mut gfx::Encoder<R, C>,
+                                            view_proj: [[f32; 4]; 4],
+                                            eye: Point3<f32>) {
+        let mut locals_updated = false;
+
+        for t in self.types.iter_mut() {
+            let max_sq = t.rule.draw_distance * t.rule.draw_distance;
+
+            self.visible.clear();
+            self.visible.extend(t.instances.iter().cloned().filter(|inst| {
+                let d = Vector3::new(inst.translate[0] - eye.x,
+                                     inst.translate[1] - eye.y,
+                                     inst.translate[2] - eye.z);
+                d.dot(&d) < max_sq
+            }));
+
+            if self.visible.is_empty() {
+                continue;
+            }
+
+            if !locals_updated {
+                encoder.update_constant_buffer(&t.bundle.data.locals,
+                                               &ScatterLocals { view_proj: view_proj });
+                locals_updated = true;
+            }
+
+            let material = ScatterMaterial {
+                material: [t.rule.roughness, t.rule.metallic, 0.0, 0.0],
+            };
+            encoder.update_constant_buffer(&t.bundle.data.material, &material);
+            encoder.update_buffer(&t.bundle.data.instances, &self.visible, 0).unwrap();
+            t.bundle.slice.instances = Some((self.visible.len() as gfx::InstanceCount, 0));
+            t.bundle.encode(encoder);
+        }
+    }
+}
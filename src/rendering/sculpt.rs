@@ -0,0 +1,513 @@
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+use glutin;
+use noise::perlin2;
+use noise;
+
+use rendering::camera::Camera;
+use rendering::heightmap::Heightmap;
+use rendering::roads::Road;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrushTool {
+    Raise,
+    Lower,
+    Smooth,
+    Flatten,
+    Noise,
+}
+
+impl BrushTool {
+    pub fn from_name(name: &str) -> Option<BrushTool> {
+        match name {
+            "raise" => Some(BrushTool::Raise),
+            "lower" => Some(BrushTool::Lower),
+            "smooth" => Some(BrushTool::Smooth),
+            "flatten" => Some(BrushTool::Flatten),
+            "noise" => Some(BrushTool::Noise),
+            _ => None,
+        }
+    }
+}
+
+/// Inclusive range of heightmap samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GridRect {
+    pub x0: usize,
+    pub z0: usize,
+    pub x1: usize,
+    pub z1: usize,
+}
+
+impl GridRect {
+    /// Every sample of a heightmap.
+    pub fn all(hm: &Heightmap) -> GridRect {
+        GridRect {
+            x0: 0,
+            z0: 0,
+            x1: hm.width() - 1,
+            z1: hm.depth() - 1,
+        }
+    }
+
+    pub fn union(&self, other: &GridRect) -> GridRect {
+        GridRect {
+            x0: self.x0.min(other.x0),
+            z0: self.z0.min(other.z0),
+            x1: self.x1.max(other.x1),
+            z1: self.z1.max(other.z1),
+        }
+    }
+
+    /// Grow by `n` samples in every direction, staying inside the grid.
+    pub fn grow(&self, n: usize, hm: &Heightmap) -> GridRect {
+        GridRect {
+            x0: self.x0.saturating_sub(n),
+            z0: self.z0.saturating_sub(n),
+            x1: (self.x1 + n).min(hm.width() - 1),
+            z1: (self.z1 + n).min(hm.depth() - 1),
+        }
+    }
+}
+
+// Heights touched by one press-drag-release of the mouse, kept so the
+// stroke can be undone and redone.
+struct Stroke {
+    // Sample index, height before and height after the stroke
+    changes: Vec<(usize, f32, f32)>,
+    bounds: GridRect,
+}
+
+pub struct Sculptor {
+    tool: Option<BrushTool>,
+    radius: f32,
+    strength: f32,
+    falloff: f32,
+    noise_seed: noise::PermutationTable,
+
+    cursor: (f32, f32),
+    painting: bool,
+    ctrl_down: bool,
+    flatten_height: f32,
+
+    // Original heights of the samples touched by the stroke in progress
+    stroke: Option<(HashMap<usize, f32>, GridRect)>,
+    undo: Vec<Stroke>,
+    redo: Vec<Stroke>,
+    // Queued undo (true) and redo (false) requests
+    pending_history: Vec<bool>,
+}
+
+impl Sculptor {
+    pub fn new() -> Sculptor {
+        Sculptor {
+            tool: None,
+            radius: 10.0,
+            strength: 20.0,
+            falloff: 0.5,
+            noise_seed: noise::PermutationTable::new(0),
+
+            cursor: (0.0, 0.0),
+            painting: false,
+            ctrl_down: false,
+            flatten_height: 0.0,
+
+            stroke: None,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            pending_history: Vec::new(),
+        }
+    }
+
+    pub fn set_tool(&mut self, tool: Option<BrushTool>) {
+        self.tool = tool;
+    }
+
+    pub fn set_radius(&mut self, radius: f32) {
+        self.radius = radius.max(0.1);
+    }
+
+    /// Height change per second at the center of the brush.
+    pub fn set_strength(&mut self, strength: f32) {
+        self.strength = strength;
+    }
+
+    /// Fraction of the radius over which the brush fades out.
+    pub fn set_falloff(&mut self, falloff: f32) {
+        self.falloff = falloff.max(0.0).min(1.0);
+    }
+
+    pub fn undo(&mut self) {
+        self.pending_history.push(true);
+    }
+
+    pub fn redo(&mut self) {
+        self.pending_history.push(false);
+    }
+
+    /// Whether an undo or redo is waiting for the next `update`.
+    pub fn has_pending_history(&self) -> bool {
+        !self.pending_history.is_empty()
+    }
+
+    /// Forget all strokes, used when the terrain is replaced.
+    pub fn clear_history(&mut self) {
+        self.stroke = None;
+        self.undo.clear();
+        self.redo.clear();
+        self.pending_history.clear();
+    }
+
+    pub fn process_input(&mut self, event: &glutin::Event) {
+        use glutin::{ElementState, Event, MouseButton, VirtualKeyCode};
+
+        match event {
+            &Event::MouseMoved(x, y) => {
+                self.cursor = (x as f32, y as f32);
+            }
+            &Event::MouseInput(ElementState::Pressed, MouseButton::Left) => {
+                self.painting = true;
+            }
+            &Event::MouseInput(ElementState::Released, MouseButton::Left) => {
+                self.painting = false;
+            }
+            &Event::KeyboardInput(state, _, Some(VirtualKeyCode::LControl)) |
+            &Event::KeyboardInput(state, _, Some(VirtualKeyCode::RControl)) |
+            &Event::KeyboardInput(state, _, Some(VirtualKeyCode::LWin)) |
+            &Event::KeyboardInput(state, _, Some(VirtualKeyCode::RWin)) => {
+                self.ctrl_down = state == ElementState::Pressed;
+            }
+            &Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Z)) => {
+                if self.ctrl_down {
+                    self.undo();
+                }
+            }
+            &Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Y)) => {
+                if self.ctrl_down {
+                    self.redo();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Apply the brush under the cursor and any pending undo or redo. Returns
+    /// the samples whose heights changed.
+    pub fn update(&mut self,
+                  hm: &mut Heightmap,
+                  cam: &Camera,
+                  viewport: (f32, f32),
+                  dt: f32)
+                  -> Option<GridRect> {
+        let mut dirty = self.apply_history(hm);
+
+        if !self.painting || self.tool.is_none() {
+            self.end_stroke(hm);
+            return dirty;
+        }
+
+        let (origin, dir) = cam.screen_ray(self.cursor.0, self.cursor.1, viewport.0, viewport.1);
+        let hit = match hm.raycast(origin, dir, 10000.0) {
+            Some(hit) => hit,
+            None => return dirty,
+        };
+
+        if self.stroke.is_none() {
+            self.flatten_height = hit.y;
+        }
+
+        if let Some(rect) = self.apply_brush(hm, hit.x, hit.z, dt) {
+            dirty = Some(match dirty {
+                Some(d) => d.union(&rect),
+                None => rect,
+            });
+        }
+
+        dirty
+    }
+
+    fn end_stroke(&mut self, hm: &Heightmap) {
+        if let Some((before, bounds)) = self.stroke.take() {
+            let changes = before.into_iter()
+                .map(|(i, h)| (i, h, hm.heights()[i]))
+                .collect();
+
+            self.undo.push(Stroke {
+                changes: changes,
+                bounds: bounds,
+            });
+            self.redo.clear();
+        }
+    }
+
+    fn apply_history(&mut self, hm: &mut Heightmap) -> Option<GridRect> {
+        let mut dirty: Option<GridRect> = None;
+
+        // A stroke still in progress is finished first, or it would record
+        // the undone heights as its result
+        if !self.pending_history.is_empty() {
+            self.end_stroke(hm);
+        }
+
+        for undo in self.pending_history.drain(..).collect::<Vec<_>>() {
+            let stroke = if undo { self.undo.pop() } else { self.redo.pop() };
+
+            let stroke = match stroke {
+                Some(stroke) => stroke,
+                None => {
+                    info!(target: "DAT205", "Nothing to {}", if undo { "undo" } else { "redo" });
+                    continue;
+                }
+            };
+
+            for &(i, before, after) in &stroke.changes {
+                hm.heights_mut()[i] = if undo { before } else { after };
+            }
+
+            dirty = Some(match dirty {
+                Some(d) => d.union(&stroke.bounds),
+                None => stroke.bounds,
+            });
+
+            if undo {
+                self.redo.push(stroke);
+            } else {
+                self.undo.push(stroke);
+            }
+        }
+
+        dirty
+    }
+
+    // Brush weight at a distance from its center.
+    fn weight(&self, dist: f32) -> f32 {
+        if dist >= self.radius {
+            return 0.0;
+        }
+        let inner = self.radius * (1.0 - self.falloff);
+        if dist <= inner {
+            return 1.0;
+        }
+        let t = (self.radius - dist) / (self.radius - inner);
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    fn apply_brush(&mut self, hm: &mut Heightmap, x: f32, z: f32, dt: f32) -> Option<GridRect> {
+        let tool = match self.tool {
+            Some(tool) => tool,
+            None => return None,
+        };
+
+        let (gx, gz) = hm.to_grid(x, z);
+        let r = self.radius / hm.cell_size();
+
+        let rect = GridRect {
+            x0: (gx - r).floor().max(0.0) as usize,
+            z0: (gz - r).floor().max(0.0) as usize,
+            x1: ((gx + r).ceil().max(0.0) as usize).min(hm.width() - 1),
+            z1: ((gz + r).ceil().max(0.0) as usize).min(hm.depth() - 1),
+        };
+
+        if rect.x0 > rect.x1 || rect.z0 > rect.z1 {
+            return None;
+        }
+
+        let amount = self.strength * dt;
+        let (w, d) = (hm.width(), hm.depth());
+
+        // Smoothing reads the neighbours, so compute every new height from
+        // the unmodified terrain before writing any of them.
+        let mut changes = Vec::new();
+        for iz in rect.z0..rect.z1 + 1 {
+            for ix in rect.x0..rect.x1 + 1 {
+                let p = hm.position(ix, iz);
+                let dist = ((p[0] - x) * (p[0] - x) + (p[2] - z) * (p[2] - z)).sqrt();
+                let weight = self.weight(dist);
+                if weight <= 0.0 {
+                    continue;
+                }
+
+                let h = p[1];
+                let new_h = match tool {
+                    BrushTool::Raise => h + amount * weight,
+                    BrushTool::Lower => h - amount * weight,
+                    BrushTool::Smooth => {
+                        let mut sum = 0.0;
+                        let mut n = 0.0;
+                        for oz in iz.saturating_sub(1)..(iz + 2).min(d) {
+                            for ox in ix.saturating_sub(1)..(ix + 2).min(w) {
+                                sum += hm.height(ox, oz);
+                                n += 1.0;
+                            }
+                        }
+                        h + (sum / n - h) * (0.1 * amount * weight).min(1.0)
+                    }
+                    BrushTool::Flatten => {
+                        h + (self.flatten_height - h) * (0.1 * amount * weight).min(1.0)
+                    }
+                    BrushTool::Noise => {
+                        let n = perlin2(&self.noise_seed, &[0.1 * p[0], 0.1 * p[2]]);
+                        h + amount * weight * n
+                    }
+                };
+
+                changes.push((iz * w + ix, h, new_h));
+            }
+        }
+
+        let (mut before, bounds) = self.stroke.take().unwrap_or((HashMap::new(), rect));
+        for &(i, h, new_h) in &changes {
+            before.entry(i).or_insert(h);
+            hm.heights_mut()[i] = new_h;
+        }
+        self.stroke = Some((before, bounds.union(&rect)));
+
+        Some(rect)
+    }
+}
+
+/// Difference between an edited heightmap and the terrain it was generated
+/// as, stored together with the seed so the edits can be reapplied. Roads
+/// are kept as well, the terrain they flattened is part of the deltas.
+#[derive(Debug, Clone)]
+pub struct TerrainEdits {
+    pub seed: Option<u32>,
+    pub width: usize,
+    pub depth: usize,
+    pub deltas: Vec<(usize, f32)>,
+    pub roads: Vec<Road>,
+}
+
+impl TerrainEdits {
+    pub fn diff(base: &[f32], current: &Heightmap, roads: &[Road]) -> TerrainEdits {
+        let deltas = base.iter()
+            .zip(current.heights().iter())
+            .enumerate()
+            .filter(|&(_, (b, c))| (c - b).abs() > 1.0e-4)
+            .map(|(i, (b, c))| (i, c - b))
+            .collect();
+
+        TerrainEdits {
+            seed: current.seed(),
+            width: current.width(),
+            depth: current.depth(),
+            deltas: deltas,
+            roads: roads.to_vec(),
+        }
+    }
+
+    pub fn apply(&self, hm: &mut Heightmap) -> Result<(), String> {
+        if hm.width() != self.width || hm.depth() != self.depth {
+            return Err(format!("Terrain edits are for a {}x{} terrain, not {}x{}",
+                               self.width,
+                               self.depth,
+                               hm.width(),
+                               hm.depth()));
+        }
+        if hm.seed() != self.seed {
+            return Err(format!("Terrain edits are for seed {:?}, not {:?}", self.seed, hm.seed()));
+        }
+
+        for &(i, d) in &self.deltas {
+            hm.heights_mut()[i] += d;
+        }
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let disp = path.as_ref().display();
+
+        let file = match File::create(path.as_ref()) {
+            Ok(file) => file,
+            Err(reason) => return Err(format!("Could not create file {}: {}", disp, reason)),
+        };
+        let mut out = BufWriter::new(file);
+
+        let mut content = String::new();
+        content.push_str("# Terrain edits\n");
+        if let Some(seed) = self.seed {
+            content.push_str(&format!("seed {}\n", seed));
+        }
+        content.push_str(&format!("size {} {}\n", self.width, self.depth));
+        for road in &self.roads {
+            content.push_str(&road.to_line());
+            content.push('\n');
+        }
+        for &(i, d) in &self.deltas {
+            content.push_str(&format!("{} {}\n", i, d));
+        }
+
+        match out.write_all(content.as_bytes()) {
+            Ok(_) => {
+                info!(target: "DAT205", "Saved {} terrain edits to {}", self.deltas.len(), disp);
+                Ok(())
+            }
+            Err(reason) => Err(format!("Could not write {}: {}", disp, reason)),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TerrainEdits, String> {
+        let disp = path.as_ref().display();
+
+        let mut file = match File::open(path.as_ref()) {
+            Err(reason) => return Err(format!("Could not open file {}: {}", disp, reason)),
+            Ok(file) => file,
+        };
+
+        let mut content = String::new();
+        if let Err(reason) = file.read_to_string(&mut content) {
+            return Err(format!("Could not read file {}: {}", disp, reason));
+        }
+
+        let mut edits = TerrainEdits {
+            seed: None,
+            width: 0,
+            depth: 0,
+            deltas: Vec::new(),
+            roads: Vec::new(),
+        };
+
+        for (n, line) in content.lines().enumerate() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() || tokens[0].starts_with('#') {
+                continue;
+            }
+
+            let parsed = match (tokens[0], tokens.len()) {
+                ("seed", 2) => tokens[1].parse().ok().map(|s| edits.seed = Some(s)),
+                ("size", 3) => {
+                    match (tokens[1].parse(), tokens[2].parse()) {
+                        (Ok(w), Ok(d)) => {
+                            edits.width = w;
+                            edits.depth = d;
+                            Some(())
+                        }
+                        _ => None,
+                    }
+                }
+                ("road", _) => Road::from_tokens(&tokens[1..]).map(|r| edits.roads.push(r)),
+                (_, 2) => {
+                    match (tokens[0].parse::<usize>(), tokens[1].parse::<f32>()) {
+                        (Ok(i), Ok(d)) if i < edits.width * edits.depth => {
+                            edits.deltas.push((i, d));
+                            Some(())
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+
+            if parsed.is_none() {
+                return Err(format!("{}:{}: invalid line '{}'", disp, n + 1, line));
+            }
+        }
+
+        Ok(edits)
+    }
+}
@@ -0,0 +1,795 @@
+
+use gfx;
+use gfx::{format, texture};
+use gfx::format::Depth;
+use gfx::traits::FactoryExt;
+use std::f32::consts::FRAC_PI_2;
+
+use na::{Isometry3, Matrix4, Orthographic3, Perspective3, Point3, Vector3, Vector4};
+
+use rendering::camera::Camera;
+use rendering::deferred::TerrainVertex;
+use rendering::lights::PointLight;
+use support::config::Config;
+
+/// Most cascades the lighting shader can pick from.
+pub const MAX_CASCADES: usize = 4;
+
+/// Most point lights with shadows. Each takes six layers of a depth array
+/// and GL 3.2 only guarantees 256 layers.
+pub const MAX_POINT_SHADOWS: usize = 42;
+
+gfx_defines!{
+    constant ShadowPassLocals {
+        view_proj: [[f32; 4]; 4] = "u_LightViewProj",
+    }
+
+    constant ShadowLocals {
+        cascade0: [[f32; 4]; 4] = "u_Cascade0",
+        cascade1: [[f32; 4]; 4] = "u_Cascade1",
+        cascade2: [[f32; 4]; 4] = "u_Cascade2",
+        cascade3: [[f32; 4]; 4] = "u_Cascade3",
+        // Depth bias of each cascade
+        bias: [f32; 4] = "u_ShadowBias",
+        // Cascade count, size of a texel, PCF radius in texels, debug view
+        params: [f32; 4] = "u_ShadowParams",
+    }
+
+    pipeline shadow {
+        vbuf: gfx::VertexBuffer<TerrainVertex> = (),
+        locals: gfx::ConstantBuffer<ShadowPassLocals> = "ShadowPassLocals",
+        out_depth: gfx::DepthTarget<Depth> =
+            gfx::preset::depth::LESS_EQUAL_WRITE,
+    }
+
+    constant PointShadowPassLocals {
+        view_proj: [[f32; 4]; 4] = "u_FaceViewProj",
+        light: [f32; 4] = "u_LightPosRadius",
+    }
+
+    constant PointShadowLocals {
+        face0: [[f32; 4]; 4] = "u_Face0",
+        face1: [[f32; 4]; 4] = "u_Face1",
+        face2: [[f32; 4]; 4] = "u_Face2",
+        face3: [[f32; 4]; 4] = "u_Face3",
+        face4: [[f32; 4]; 4] = "u_Face4",
+        face5: [[f32; 4]; 4] = "u_Face5",
+        // Bias, slope bias, size of a texel, PCF radius in texels
+        params: [f32; 4] = "u_PointShadowParams",
+    }
+
+    pipeline point_shadow {
+        vbuf: gfx::VertexBuffer<TerrainVertex> = (),
+        locals: gfx::ConstantBuffer<PointShadowPassLocals> = "PointShadowPassLocals",
+        out_depth: gfx::DepthTarget<Depth> =
+            gfx::preset::depth::LESS_EQUAL_WRITE,
+    }
+}
+
+const SHADOW_VERTEX_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform ShadowPassLocals {
+        mat4 u_LightViewProj;
+    };
+
+    in vec3 a_Pos;
+
+    void main() {
+        gl_Position = u_LightViewProj * vec4(a_Pos, 1.0);
+    }
+";
+
+const SHADOW_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    void main() {}
+";
+
+// Positions are relative to the light and divided by its radius, so one
+// set of face matrices serves every light. The depth is the distance to the
+// light instead of the depth along the face.
+const POINT_SHADOW_VERTEX_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform PointShadowPassLocals {
+        mat4 u_FaceViewProj;
+        vec4 u_LightPosRadius;
+    };
+
+    in vec3 a_Pos;
+
+    out vec3 v_Offset;
+
+    void main() {
+        v_Offset = (a_Pos - u_LightPosRadius.xyz) / u_LightPosRadius.w;
+        gl_Position = u_FaceViewProj * vec4(v_Offset, 1.0);
+    }
+";
+
+const POINT_SHADOW_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    in vec3 v_Offset;
+
+    void main() {
+        gl_FragDepth = length(v_Offset);
+    }
+";
+
+// Point light shadow lookup, see `with_point_shadows`.
+const POINT_SHADOW_GLSL: &'static [u8] = b"
+    layout(std140)
+    uniform PointShadowLocals {
+        mat4 u_Face0;
+        mat4 u_Face1;
+        mat4 u_Face2;
+        mat4 u_Face3;
+        mat4 u_Face4;
+        mat4 u_Face5;
+        vec4 u_PointShadowParams;
+    };
+
+    uniform sampler2DArrayShadow t_PointShadow;
+
+    mat4 face_matrix(int i) {
+        if (i == 0) return u_Face0;
+        if (i == 1) return u_Face1;
+        if (i == 2) return u_Face2;
+        if (i == 3) return u_Face3;
+        if (i == 4) return u_Face4;
+        return u_Face5;
+    }
+
+    float point_shadow(int slot, vec3 pos, vec3 n, vec3 light, float radius) {
+        if (slot < 0) {
+            return 1.0;
+        }
+
+        // The face the offset points through, in the order +x -x +y -y +z -z
+        vec3 d = (pos - light) / radius;
+        vec3 a = abs(d);
+        int face;
+        if (a.x >= a.y && a.x >= a.z) {
+            face = d.x > 0.0 ? 0 : 1;
+        } else if (a.y >= a.z) {
+            face = d.y > 0.0 ? 2 : 3;
+        } else {
+            face = d.z > 0.0 ? 4 : 5;
+        }
+
+        vec4 p = face_matrix(face) * vec4(d, 1.0);
+        vec2 uv = p.xy / p.w * 0.5 + 0.5;
+
+        // Constant bias against acne, plus more where the light grazes the
+        // surface. Too much lifts shadows off their casters.
+        float ndl = clamp(dot(n, -normalize(d)), 0.05, 1.0);
+        float slope = sqrt(1.0 - ndl * ndl) / ndl;
+        float bias = (u_PointShadowParams.x + u_PointShadowParams.y * slope) / radius;
+        float depth = length(d) - bias;
+
+        float texel = u_PointShadowParams.z;
+        float layer = float(slot * 6 + face);
+        int r = int(u_PointShadowParams.w);
+        float lit = 0.0;
+        for (int y = -r; y <= r; y++) {
+            for (int x = -r; x <= r; x++) {
+                // Samples stay on their face, the seams are not blended
+                vec2 o = clamp(uv + vec2(x, y) * texel, vec2(0.5 * texel), vec2(1.0 - 0.5 * texel));
+                lit += texture(t_PointShadow, vec4(o, layer, depth));
+            }
+        }
+        return lit / float((2 * r + 1) * (2 * r + 1));
+    }
+";
+
+// Shadow lookup for the lighting shaders, see `with_shadows`.
+const SHADOW_GLSL: &'static [u8] = b"
+    layout(std140)
+    uniform ShadowLocals {
+        mat4 u_Cascade0;
+        mat4 u_Cascade1;
+        mat4 u_Cascade2;
+        mat4 u_Cascade3;
+        vec4 u_ShadowBias;
+        vec4 u_ShadowParams;
+    };
+
+    uniform sampler2DArrayShadow t_Shadow;
+
+    mat4 cascade_matrix(int i) {
+        if (i == 0) return u_Cascade0;
+        if (i == 1) return u_Cascade1;
+        if (i == 2) return u_Cascade2;
+        return u_Cascade3;
+    }
+
+    // The first cascade that covers the position, or -1 outside of all
+    int find_cascade(vec3 pos, out vec3 coord) {
+        for (int i = 0; i < int(u_ShadowParams.x); i++) {
+            vec4 p = cascade_matrix(i) * vec4(pos, 1.0);
+            coord = p.xyz * 0.5 + 0.5;
+            float margin = u_ShadowParams.y * (u_ShadowParams.z + 1.0);
+            if (all(greaterThan(coord.xy, vec2(margin))) &&
+                all(lessThan(coord.xy, vec2(1.0 - margin))) &&
+                coord.z < 1.0) {
+                return i;
+            }
+        }
+        return -1;
+    }
+
+    float shadow(vec3 pos, vec3 n, vec3 to_light) {
+        vec3 coord;
+        int i = find_cascade(pos, coord);
+        if (i < 0) {
+            return 1.0;
+        }
+
+        // Steep surfaces need more bias
+        float ndl = clamp(dot(n, to_light), 0.0, 1.0);
+        float depth = coord.z - u_ShadowBias[i] * (1.0 + 2.0 * (1.0 - ndl));
+
+        int r = int(u_ShadowParams.z);
+        float lit = 0.0;
+        for (int y = -r; y <= r; y++) {
+            for (int x = -r; x <= r; x++) {
+                vec2 uv = coord.xy + vec2(x, y) * u_ShadowParams.y;
+                lit += texture(t_Shadow, vec4(uv, float(i), depth));
+            }
+        }
+        return lit / float((2 * r + 1) * (2 * r + 1));
+    }
+
+    vec3 cascade_tint(vec3 pos) {
+        if (u_ShadowParams.w < 0.5) {
+            return vec3(1.0);
+        }
+        vec3 coord;
+        int i = find_cascade(pos, coord);
+        if (i == 0) return vec3(1.0, 0.4, 0.4);
+        if (i == 1) return vec3(0.4, 1.0, 0.4);
+        if (i == 2) return vec3(0.4, 0.4, 1.0);
+        if (i == 3) return vec3(1.0, 1.0, 0.4);
+        return vec3(1.0);
+    }
+";
+
+/// Add the shadow lookup to a fragment shader, right after its version line.
+/// It declares `shadow(pos, n, to_light)`, the fraction of the light that
+/// reaches a world position, and `cascade_tint(pos)` for the debug view. The
+/// shader then needs `t_Shadow` and the `ShadowLocals` block bound.
+pub fn with_shadows(shader: &[u8]) -> Vec<u8> {
+    insert_after_version(shader, SHADOW_GLSL)
+}
+
+/// Add the point light shadow lookup to a fragment shader. It declares
+/// `point_shadow(slot, pos, n, light_pos, radius)`, the fraction of the
+/// light that reaches a world position, which is one for a slot below
+/// zero. The shader then needs `t_PointShadow` and the `PointShadowLocals`
+/// block bound.
+pub fn with_point_shadows(shader: &[u8]) -> Vec<u8> {
+    insert_after_version(shader, POINT_SHADOW_GLSL)
+}
+
+/// Paste GLSL into a shader right after its version line.
+pub fn insert_after_version(shader: &[u8], glsl: &[u8]) -> Vec<u8> {
+    let version = shader.windows(8).position(|w| w == b"#version").unwrap_or(0);
+    let split = shader[version..]
+        .iter()
+        .position(|&c| c == b'\n')
+        .map(|i| version + i + 1)
+        .unwrap_or(shader.len());
+
+    let mut result = Vec::with_capacity(shader.len() + glsl.len());
+    result.extend_from_slice(&shader[..split]);
+    result.extend_from_slice(glsl);
+    result.extend_from_slice(&shader[split..]);
+    result
+}
+
+#[derive(Debug, Clone)]
+pub struct ShadowSettings {
+    pub enabled: bool,
+    pub cascades: usize,
+    pub resolution: u16,
+    // Shadows end this far from the camera
+    pub distance: f32,
+    // Blend between even (0) and logarithmic (1) cascade splits
+    pub split_lambda: f32,
+    // How far behind a cascade terrain can be and still cast into it
+    pub caster_distance: f32,
+    // In world units along the light direction
+    pub bias: f32,
+    pub pcf_radius: u32,
+}
+
+impl ShadowSettings {
+    pub fn from_config(config: &Config) -> ShadowSettings {
+        ShadowSettings {
+            enabled: config.get("shadow.enabled", true),
+            cascades: config.get::<usize>("shadow.cascades", 4).max(1).min(MAX_CASCADES),
+            resolution: config.get("shadow.resolution", 2048),
+            distance: config.get("shadow.distance", 300.0),
+            split_lambda: config.get("shadow.split_lambda", 0.75),
+            caster_distance: config.get("shadow.caster_distance", 300.0),
+            bias: config.get("shadow.bias", 0.15),
+            pcf_radius: config.get("shadow.pcf_radius", 1),
+        }
+    }
+}
+
+/// Far end of each cascade as a distance along the view direction, blending
+/// even and logarithmic splits.
+pub fn cascade_splits(near: f32, far: f32, count: usize, lambda: f32) -> Vec<f32> {
+    (1..count + 1)
+        .map(|i| {
+            let t = i as f32 / count as f32;
+            let log = near * (far / near).powf(t);
+            let even = near + (far - near) * t;
+            lambda * log + (1.0 - lambda) * even
+        })
+        .collect()
+}
+
+// Light space projection of a slice of the view frustum between two view
+// distances. The slice is fit by a sphere, so the projection does not change
+// size while the camera turns, and moved in whole texels, so it does not
+// shimmer while the camera moves.
+fn fit_cascade(cam: &Camera,
+               to_light: &Vector3<f32>,
+               near: f32,
+               far: f32,
+               settings: &ShadowSettings)
+               -> (Matrix4<f32>, f32) {
+    let (cam_near, cam_far) = cam.near_far();
+    let inv = cam.get_inv_view_proj();
+
+    let unproject = |x: f32, y: f32, z: f32| {
+        let p = inv * Vector4::new(x, y, z, 1.0);
+        Vector3::new(p.x / p.w, p.y / p.w, p.z / p.w)
+    };
+
+    // Corners of the slice, along the edges of the whole frustum
+    let mut corners = Vec::with_capacity(8);
+    for &(x, y) in &[(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+        let (a, b) = (unproject(x, y, -1.0), unproject(x, y, 1.0));
+        for &d in &[near, far] {
+            corners.push(a + (b - a) * ((d - cam_near) / (cam_far - cam_near)));
+        }
+    }
+
+    let center = corners.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, c| acc + *c) / 8.0;
+    let radius = corners.iter().map(|c| (*c - center).norm()).fold(0.0, f32::max);
+    // Rounded up so the size stays the same from frame to frame
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let up = if to_light.y.abs() < 0.99 { Vector3::y() } else { Vector3::x() };
+    let target = Point3::from_coordinates(center);
+    let eye = target + to_light * (radius + settings.caster_distance);
+    let view = Isometry3::look_at_rh(&eye, &target, &up).to_homogeneous();
+
+    let depth = 2.0 * radius + settings.caster_distance;
+    let ortho = Orthographic3::new(-radius, radius, -radius, radius, 0.0, depth);
+    let mut proj = *ortho.as_matrix();
+
+    // Snap the world origin to a texel
+    let half = settings.resolution as f32 / 2.0;
+    let origin = proj * view * Vector4::new(0.0, 0.0, 0.0, 1.0);
+    let (ox, oy) = (origin.x * half, origin.y * half);
+    proj[(0, 3)] += (ox.round() - ox) / half;
+    proj[(1, 3)] += (oy.round() - oy) / half;
+
+    // Depth in the map goes from 0 to 1 over the whole range
+    (proj * view, settings.bias / depth)
+}
+
+type DepthArray<R> = (Vec<gfx::handle::DepthStencilView<R, Depth>>,
+                      gfx::handle::ShaderResourceView<R, f32>,
+                      gfx::handle::Sampler<R>);
+
+// A square depth texture with `layers` layers, a target for each layer and
+// a comparison sampler to read it with.
+fn depth_array<R, F>(factory: &mut F, size: u16, layers: usize) -> DepthArray<R>
+    where R: gfx::Resources,
+          F: gfx::Factory<R>
+{
+    use gfx::memory::{Usage, DEPTH_STENCIL, SHADER_RESOURCE};
+
+    let layers = layers as texture::Layer;
+    let kind = texture::Kind::D2Array(size, size, layers, texture::AaMode::Single);
+    let tex = factory.create_texture::<format::D24>(kind,
+                                 1,
+                                 DEPTH_STENCIL | SHADER_RESOURCE,
+                                 Usage::Data,
+                                 Some(format::ChannelType::Unorm))
+        .unwrap();
+
+    let targets = (0..layers)
+        .map(|layer| {
+            factory.view_texture_as_depth_stencil::<Depth>(&tex,
+                                                 0,
+                                                 Some(layer),
+                                                 texture::DepthStencilFlags::empty())
+                .unwrap()
+        })
+        .collect();
+    let resource =
+        factory.view_texture_as_shader_resource::<Depth>(&tex, (0, 0), format::Swizzle::new())
+            .unwrap();
+
+    // Compares against the stored depth, bilinear filtering gives a little
+    // extra softness for free
+    let mut info = texture::SamplerInfo::new(texture::FilterMethod::Bilinear,
+                                             texture::WrapMode::Clamp);
+    info.comparison = Some(gfx::state::Comparison::LessEqual);
+
+    (targets, resource, factory.create_sampler(info))
+}
+
+/// Cascaded shadow maps of the sun, one layer of a depth array texture per
+/// cascade. Only the terrain casts shadows.
+pub struct ShadowMaps<R: gfx::Resources> {
+    settings: ShadowSettings,
+    pso: gfx::PipelineState<R, shadow::Meta>,
+    pass_locals: gfx::handle::Buffer<R, ShadowPassLocals>,
+    targets: Vec<gfx::handle::DepthStencilView<R, Depth>>,
+    resource: gfx::handle::ShaderResourceView<R, f32>,
+    sampler: gfx::handle::Sampler<R>,
+    cascades: Vec<Matrix4<f32>>,
+    bias: Vec<f32>,
+    debug: bool,
+}
+
+impl<R: gfx::Resources> ShadowMaps<R> {
+    pub fn new<F: gfx::Factory<R>>(factory: &mut F, settings: ShadowSettings) -> Self {
+        let size = settings.resolution;
+        let (targets, resource, sampler) = depth_array(factory, size, MAX_CASCADES);
+
+        let shaders = factory.create_shader_set(SHADOW_VERTEX_SHADER, SHADOW_FRAGMENT_SHADER)
+            .unwrap();
+        // No culling, a low sun sees the terrain from below at its edges
+        let pso = factory.create_pipeline_state(&shaders,
+                                   gfx::Primitive::TriangleList,
+                                   gfx::state::Rasterizer::new_fill(),
+                                   shadow::new())
+            .unwrap();
+
+        info!(target: "DAT205",
+              "Shadow maps use {:.1} MB",
+              (MAX_CASCADES * size as usize * size as usize * 4) as f32 / (1024.0 * 1024.0));
+
+        ShadowMaps {
+            settings: settings,
+            pso: pso,
+            pass_locals: factory.create_constant_buffer(1),
+            targets: targets,
+            resource: resource,
+            sampler: sampler,
+            cascades: Vec::new(),
+            bias: Vec::new(),
+            debug: false,
+        }
+    }
+
+    pub fn toggle_debug(&mut self) {
+        self.debug = !self.debug;
+    }
+
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// The cascades as bound to the lighting pass.
+    pub fn texture(&self) -> (gfx::handle::ShaderResourceView<R, f32>, gfx::handle::Sampler<R>) {
+        (self.resource.clone(), self.sampler.clone())
+    }
+
+    /// Fit the cascades to the camera. `to_light` points towards the sun.
+    pub fn update(&mut self, cam: &Camera, to_light: [f32; 3]) {
+        self.cascades.clear();
+        self.bias.clear();
+
+        if !self.settings.enabled {
+            return;
+        }
+
+        let to_light = Vector3::new(to_light[0], to_light[1], to_light[2]);
+        let (near, _) = cam.near_far();
+        let splits = cascade_splits(near,
+                                    self.settings.distance,
+                                    self.settings.cascades,
+                                    self.settings.split_lambda);
+
+        let mut start = near;
+        for far in splits {
+            let (m, bias) = fit_cascade(cam, &to_light, start, far, &self.settings);
+            self.cascades.push(m);
+            self.bias.push(bias);
+            start = far;
+        }
+    }
+
+    /// Render the depth of the terrain meshes into every cascade.
+    pub fn render<C>(&self,
+                     encoder: &mut gfx::Encoder<R, C>,
+                     meshes: &[(&gfx::handle::Buffer<R, TerrainVertex>, &gfx::Slice<R>)])
+        where C: gfx::CommandBuffer<R>
+    {
+        for (m, target) in self.cascades.iter().zip(self.targets.iter()) {
+            encoder.clear_depth(target, 1.0);
+            encoder.update_constant_buffer(&self.pass_locals,
+                                           &ShadowPassLocals { view_proj: (*m).into() });
+
+            for &(vbuf, slice) in meshes {
+                let data = shadow::Data {
+                    vbuf: vbuf.clone(),
+                    locals: self.pass_locals.clone(),
+                    out_depth: target.clone(),
+                };
+                encoder.draw(slice, &self.pso, &data);
+            }
+        }
+    }
+
+    pub fn locals(&self) -> ShadowLocals {
+        let matrix = |i: usize| -> [[f32; 4]; 4] {
+            self.cascades.get(i).cloned().unwrap_or(Matrix4::identity()).into()
+        };
+        let bias = |i: usize| self.bias.get(i).cloned().unwrap_or(0.0);
+
+        ShadowLocals {
+            cascade0: matrix(0),
+            cascade1: matrix(1),
+            cascade2: matrix(2),
+            cascade3: matrix(3),
+            bias: [bias(0), bias(1), bias(2), bias(3)],
+            params: [self.cascades.len() as f32,
+                     1.0 / self.settings.resolution as f32,
+                     self.settings.pcf_radius as f32,
+                     if self.debug { 1.0 } else { 0.0 }],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PointShadowSettings {
+    // Most point lights with shadows in a frame
+    pub budget: usize,
+    pub resolution: u16,
+    // In world units along the light direction
+    pub bias: f32,
+    pub slope_bias: f32,
+    pub pcf_radius: u32,
+}
+
+impl PointShadowSettings {
+    pub fn from_config(config: &Config) -> PointShadowSettings {
+        let budget: usize = config.get("point_shadow.budget", 4);
+        if budget > MAX_POINT_SHADOWS {
+            warn!(target: "DAT205",
+                  "At most {} point lights can have shadows",
+                  MAX_POINT_SHADOWS);
+        }
+
+        PointShadowSettings {
+            budget: budget.min(MAX_POINT_SHADOWS),
+            resolution: config.get("point_shadow.resolution", 512),
+            bias: config.get("point_shadow.bias", 0.05),
+            slope_bias: config.get("point_shadow.slope_bias", 0.05),
+            pcf_radius: config.get("point_shadow.pcf_radius", 1),
+        }
+    }
+}
+
+// Look directions and up vectors of the cube faces, in the order the
+// lookup picks them
+const FACES: [([f32; 3], [f32; 3]); 6] = [([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+                                         ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+                                         ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+                                         ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+                                         ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+                                         ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0])];
+
+/// Cube shadow maps for the point lights that matter most in a frame. Each
+/// light in the budget gets six layers of a depth array texture, one per
+/// cube face, since cube map arrays are not in GL 3.2. Only the terrain
+/// casts shadows.
+pub struct PointShadows<R: gfx::Resources> {
+    settings: PointShadowSettings,
+    pso: gfx::PipelineState<R, point_shadow::Meta>,
+    pass_locals: gfx::handle::Buffer<R, PointShadowPassLocals>,
+    targets: Vec<gfx::handle::DepthStencilView<R, Depth>>,
+    resource: gfx::handle::ShaderResourceView<R, f32>,
+    sampler: gfx::handle::Sampler<R>,
+    faces: Vec<Matrix4<f32>>,
+    // Position and radius of the light in each slot
+    selected: Vec<[f32; 4]>,
+}
+
+impl<R: gfx::Resources> PointShadows<R> {
+    pub fn new<F: gfx::Factory<R>>(factory: &mut F, settings: PointShadowSettings) -> Self {
+        let size = settings.resolution;
+        // Keep one slot around so there is always a texture to bind
+        let (targets, resource, sampler) = depth_array(factory, size, 6 * settings.budget.max(1));
+
+        let shaders = factory.create_shader_set(POINT_SHADOW_VERTEX_SHADER,
+                               POINT_SHADOW_FRAGMENT_SHADER)
+            .unwrap();
+        let pso = factory.create_pipeline_state(&shaders,
+                                   gfx::Primitive::TriangleList,
+                                   gfx::state::Rasterizer::new_fill(),
+                                   point_shadow::new())
+            .unwrap();
+
+        // Lights are scaled to a radius of one, so the far plane is at one
+        let proj = *Perspective3::new(1.0, FRAC_PI_2, 0.01, 1.0).as_matrix();
+        let faces = FACES.iter()
+            .map(|&(dir, up)| {
+                let view = Isometry3::look_at_rh(&Point3::origin(),
+                                                 &Point3::new(dir[0], dir[1], dir[2]),
+                                                 &Vector3::new(up[0], up[1], up[2]));
+                proj * view.to_homogeneous()
+            })
+            .collect();
+
+        info!(target: "DAT205",
+              "Point light shadow maps use {:.1} MB",
+              (targets.len() * size as usize * size as usize * 4) as f32 / (1024.0 * 1024.0));
+
+        PointShadows {
+            settings: settings,
+            pso: pso,
+            pass_locals: factory.create_constant_buffer(1),
+            targets: targets,
+            resource: resource,
+            sampler: sampler,
+            faces: faces,
+            selected: Vec::new(),
+        }
+    }
+
+    /// The shadow maps as bound to the point light pass.
+    pub fn texture(&self) -> (gfx::handle::ShaderResourceView<R, f32>, gfx::handle::Sampler<R>) {
+        (self.resource.clone(), self.sampler.clone())
+    }
+
+    /// Pick the lights that get shadows this frame, by how large they are
+    /// compared to their distance from the camera. Lights behind the camera
+    /// are skipped. Returns the indices of the picked lights, in slot order.
+    pub fn select(&mut self,
+                  lights: &[PointLight],
+                  eye: &Point3<f32>,
+                  forward: &Vector3<f32>)
+                  -> Vec<usize> {
+        let mut candidates: Vec<(f32, usize)> = lights.iter()
+            .enumerate()
+            .filter(|&(_, l)| l.intensity > 0.0 && l.radius > 0.0)
+            .filter_map(|(i, l)| {
+                let p = Point3::new(l.position[0], l.position[1], l.position[2]);
+                let to_light = p - eye;
+                if to_light.dot(forward) < -l.radius {
+                    return None;
+                }
+                let dist = to_light.norm().max(1.0);
+                Some((l.radius / dist, i))
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(::std::cmp::Ordering::Equal));
+        candidates.truncate(self.settings.budget);
+
+        self.selected = candidates.iter()
+            .map(|&(_, i)| {
+                let p = lights[i].position;
+                [p[0], p[1], p[2], lights[i].radius]
+            })
+            .collect();
+        candidates.iter().map(|&(_, i)| i).collect()
+    }
+
+    /// Render the depth of the terrain meshes around every selected light.
+    pub fn render<C>(&self,
+                     encoder: &mut gfx::Encoder<R, C>,
+                     meshes: &[(&gfx::handle::Buffer<R, TerrainVertex>, &gfx::Slice<R>)])
+        where C: gfx::CommandBuffer<R>
+    {
+        for (slot, light) in self.selected.iter().enumerate() {
+            for (face, m) in self.faces.iter().enumerate() {
+                let target = &self.targets[6 * slot + face];
+                encoder.clear_depth(target, 1.0);
+                encoder.update_constant_buffer(&self.pass_locals,
+                                               &PointShadowPassLocals {
+                                                   view_proj: (*m).into(),
+                                                   light: *light,
+                                               });
+
+                for &(vbuf, slice) in meshes {
+                    let data = point_shadow::Data {
+                        vbuf: vbuf.clone(),
+                        locals: self.pass_locals.clone(),
+                        out_depth: target.clone(),
+                    };
+                    encoder.draw(slice, &self.pso, &data);
+                }
+            }
+        }
+    }
+
+    pub fn locals(&self) -> PointShadowLocals {
+        let face = |i: usize| -> [[f32; 4]; 4] { self.faces[i].into() };
+
+        PointShadowLocals {
+            face0: face(0),
+            face1: face(1),
+            face2: face(2),
+            face3: face(3),
+            face4: face(4),
+            face5: face(5),
+            params: [self.settings.bias,
+                     self.settings.slope_bias,
+                     1.0 / self.settings.resolution as f32,
+                     self.settings.pcf_radius as f32],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alewife;
+    use core::event;
+
+    fn camera(eye: Point3<f32>) -> Camera {
+        let mut bus = alewife::Publisher::<event::EventID, event::Event>::new();
+        let sub = bus.add_subscriber(&[event::EventID::EntityEvent]);
+        let mut cam = Camera::new(1.0, 0.75, eye, sub);
+        cam.look_at(eye, eye + Vector3::new(100.0, -20.0, 40.0));
+        cam
+    }
+
+    #[test]
+    fn test_cascade_splits_monotonic() {
+        for &lambda in &[0.0, 0.5, 0.75, 1.0] {
+            let splits = cascade_splits(0.1, 300.0, 4, lambda);
+            assert_eq!(splits.len(), 4);
+            assert!(splits.windows(2).all(|s| s[0] < s[1]), "{:?}", splits);
+            assert!(splits[0] > 0.1);
+        }
+    }
+
+    #[test]
+    fn test_cascade_splits_end_at_far() {
+        for &lambda in &[0.0, 0.5, 1.0] {
+            let splits = cascade_splits(0.5, 250.0, 3, lambda);
+            assert!((splits[2] - 250.0).abs() < 1.0e-3, "{:?}", splits);
+        }
+        // Starting from the near plane, a single cascade covers everything
+        assert!((cascade_splits(0.5, 250.0, 1, 0.75)[0] - 250.0).abs() < 1.0e-3);
+        let even = cascade_splits(1.0, 101.0, 4, 0.0);
+        assert!((even[0] - 26.0).abs() < 1.0e-3, "{:?}", even);
+    }
+
+    // Moving the camera less than a texel moves everything in the map by
+    // whole texels, so the edges of shadows do not crawl
+    #[test]
+    fn test_cascade_snaps_to_texels() {
+        let settings = ShadowSettings::from_config(&Config::new());
+        let to_light = Vector3::new(0.3, 0.8, 0.2).normalize();
+        let half = settings.resolution as f32 / 2.0;
+
+        let eye = Point3::new(10.0, 30.0, -5.0);
+        let (a, _) = fit_cascade(&camera(eye), &to_light, 1.0, 40.0, &settings);
+        for &offset in &[0.01, 0.037, 0.11] {
+            let moved = eye + Vector3::new(offset, 0.0, 0.5 * offset);
+            let (b, _) = fit_cascade(&camera(moved), &to_light, 1.0, 40.0, &settings);
+
+            for p in &[Vector4::new(0.0, 0.0, 0.0, 1.0), Vector4::new(12.5, 3.0, -40.25, 1.0)] {
+                let shift = (b * *p - a * *p) * half;
+                assert!((shift.x - shift.x.round()).abs() < 0.02, "{} {:?}", offset, shift);
+                assert!((shift.y - shift.y.round()).abs() < 0.02, "{} {:?}", offset, shift);
+            }
+        }
+    }
+}
@@ -3,7 +3,7 @@ use gfx;
 use gfx_core;
 use gfx::{Bundle, texture};
 use gfx::format::Rgba8;
-use rendering::deferred::GFormat;
+use rendering::deferred::AlbedoFormat;
 
 use image;
 
@@ -23,7 +23,7 @@ gfx_defines!{
         vbuf: gfx::VertexBuffer<Vertex> = (),
         cubemap: gfx::TextureSampler<[f32; 4]> = "t_Cubemap",
         locals: gfx::ConstantBuffer<Locals> = "Locals",
-        out: gfx::RenderTarget<GFormat> = "Target0",
+        out: gfx::RenderTarget<AlbedoFormat> = "Target0",
     }
 }
 
@@ -118,7 +118,7 @@ pub struct Skybox<R: gfx::Resources> {
 
 impl<R: gfx::Resources> Skybox<R> {
     pub fn new<F: gfx::Factory<R>>(factory: &mut F,
-                                   main_color: gfx_core::handle::RenderTargetView<R, [f32; 4]>)
+                                   main_color: gfx_core::handle::RenderTargetView<R, AlbedoFormat>)
                                    -> Self {
         use gfx::traits::FactoryExt;
 
@@ -171,4 +171,16 @@ impl<R: gfx::Resources> Skybox<R> {
         encoder.update_constant_buffer(&self.res.data.locals, &locals);
         self.res.encode(encoder);
     }
+
+    /// Render into a different target than the one the skybox was created
+    /// with.
+    pub fn render_to<C: gfx::CommandBuffer<R>>(&mut self,
+                                               encoder: &mut gfx::Encoder<R, C>,
+                                               target: &gfx::handle::RenderTargetView<R, AlbedoFormat>,
+                                               inv_proj: [[f32; 4]; 4],
+                                               view: [[f32; 4]; 4]) {
+        let out = ::std::mem::replace(&mut self.res.data.out, target.clone());
+        self.render(encoder, inv_proj, view);
+        self.res.data.out = out;
+    }
 }
@@ -0,0 +1,250 @@
+
+use std::f32::consts::PI;
+use std::path::Path;
+
+use gfx;
+use gfx::texture;
+use gfx::format::Srgba8;
+use image;
+use image::imageops;
+use noise::{perlin2, perlin4};
+use noise;
+
+use support::config::Config;
+
+/// The terrain shader blends at most this many layers per vertex.
+pub const MAX_LAYERS: usize = 4;
+
+const LAYER_TEXTURE_SIZE: u32 = 256;
+
+/// A terrain material layer. Heights are normalized to [0, 1] between the
+/// lowest and highest point of the terrain, slopes are in degrees.
+#[derive(Debug, Clone)]
+pub struct TerrainLayer {
+    pub name: String,
+    pub color: [f32; 3],
+    pub tiling: f32,
+    pub roughness: f32,
+    pub metallic: f32,
+    pub height: (f32, f32),
+    pub height_blend: f32,
+    pub slope: (f32, f32),
+    pub slope_blend: f32,
+    pub noise: f32,
+    texture: Vec<u8>,
+}
+
+pub struct TerrainLayers {
+    layers: Vec<TerrainLayer>,
+    seed: noise::PermutationTable,
+}
+
+fn smoothstep(e0: f32, e1: f32, x: f32) -> f32 {
+    let t = ((x - e0) / (e1 - e0)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// 1 inside [lo, hi], fading to 0 over `blend` on either side.
+fn band(x: f32, range: (f32, f32), blend: f32) -> f32 {
+    let blend = blend.max(1.0e-4);
+    smoothstep(range.0 - blend, range.0 + blend, x) *
+    (1.0 - smoothstep(range.1 - blend, range.1 + blend, x))
+}
+
+// Grayscale detail texture around 0.85 that tiles seamlessly, made by walking
+// a torus through 4D noise.
+fn procedural_texture(index: u32) -> Vec<u8> {
+    let seed = noise::PermutationTable::new(index + 1);
+    let size = LAYER_TEXTURE_SIZE as usize;
+    let mut data = Vec::with_capacity(size * size * 4);
+
+    for y in 0..size {
+        for x in 0..size {
+            let a = 2.0 * PI * x as f32 / size as f32;
+            let b = 2.0 * PI * y as f32 / size as f32;
+
+            let mut n = 0.0;
+            let mut amplitude = 0.5;
+            let mut radius = 2.0;
+            for _ in 0..4 {
+                n += amplitude *
+                     perlin4(&seed,
+                             &[radius * a.cos(), radius * a.sin(), radius * b.cos(), radius * b.sin()]);
+                amplitude *= 0.5;
+                radius *= 2.0;
+            }
+
+            // Stored as sRGB, like the textures loaded from files
+            let linear = (0.85 + 0.15 * n).max(0.0).min(1.0);
+            let v = (linear.powf(1.0 / 2.2) * 255.0) as u8;
+            data.extend_from_slice(&[v, v, v, 255]);
+        }
+    }
+
+    data
+}
+
+fn load_texture(path: &Path) -> Result<Vec<u8>, String> {
+    match image::open(path) {
+        Ok(img) => {
+            let img = imageops::resize(&img.to_rgba(),
+                                       LAYER_TEXTURE_SIZE,
+                                       LAYER_TEXTURE_SIZE,
+                                       image::FilterType::Triangle);
+            Ok(img.into_raw())
+        }
+        Err(reason) => Err(format!("Could not load texture {}: {}", path.display(), reason)),
+    }
+}
+
+impl TerrainLayers {
+    /// Read the layer definitions from a config. The `layers` key lists the
+    /// layer names in order of priority, lowest first, and every other key
+    /// is prefixed with the name of the layer it belongs to. Texture paths
+    /// are relative to `asset_dir`.
+    pub fn from_config(config: &Config, asset_dir: &Path) -> TerrainLayers {
+        let names: Vec<String> = config.get_str("layers")
+            .unwrap_or("grass")
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_owned())
+            .collect();
+
+        if names.len() > MAX_LAYERS {
+            warn!(target: "DAT205",
+                  "Only {} terrain layers are supported, ignoring {:?}",
+                  MAX_LAYERS,
+                  &names[MAX_LAYERS..]);
+        }
+
+        let pair = |key: String, default: (f32, f32)| match config.get_floats(&key) {
+            Some(ref v) if v.len() == 2 => (v[0], v[1]),
+            Some(_) => {
+                warn!(target: "DAT205", "Expected two values for {}", key);
+                default
+            }
+            None => default,
+        };
+
+        let layers = names.iter()
+            .take(MAX_LAYERS)
+            .enumerate()
+            .map(|(i, name)| {
+                let color = match config.get_floats(&format!("{}.color", name)) {
+                    Some(ref c) if c.len() == 3 => [c[0], c[1], c[2]],
+                    _ => [1.0, 1.0, 1.0],
+                };
+
+                let texture = match config.get_str(&format!("{}.texture", name)) {
+                    Some(path) => {
+                        load_texture(&asset_dir.join(path)).unwrap_or_else(|e| {
+                            error!(target: "DAT205", "{}", e);
+                            procedural_texture(i as u32)
+                        })
+                    }
+                    None => procedural_texture(i as u32),
+                };
+
+                TerrainLayer {
+                    name: name.clone(),
+                    color: color,
+                    tiling: config.get(&format!("{}.tiling", name), 0.1),
+                    roughness: config.get(&format!("{}.roughness", name), 0.8),
+                    metallic: config.get(&format!("{}.metallic", name), 0.0),
+                    height: pair(format!("{}.height", name), (-1.0, 2.0)),
+                    height_blend: config.get(&format!("{}.height_blend", name), 0.05),
+                    slope: pair(format!("{}.slope", name), (-1.0, 91.0)),
+                    slope_blend: config.get(&format!("{}.slope_blend", name), 5.0),
+                    noise: config.get(&format!("{}.noise", name), 0.0),
+                    texture: texture,
+                }
+            })
+            .collect();
+
+        TerrainLayers {
+            layers: layers,
+            seed: noise::PermutationTable::new(config.get("noise_seed", 0)),
+        }
+    }
+
+    pub fn layers(&self) -> &[TerrainLayer] {
+        &self.layers
+    }
+
+    /// Blend weights of every layer for a point on the terrain. Layers later
+    /// in the list are painted on top of earlier ones.
+    pub fn weights(&self, x: f32, z: f32, height: f32, slope: f32) -> [f32; 4] {
+        let mut weights = [0.0; MAX_LAYERS];
+        let mut remaining = 1.0;
+
+        for (i, layer) in self.layers.iter().enumerate().rev() {
+            let h = height + layer.noise * perlin2(&self.seed, &[0.05 * x, 0.05 * z]);
+            let w = band(h, layer.height, layer.height_blend) *
+                    band(slope, layer.slope, layer.slope_blend);
+            weights[i] = remaining * w;
+            remaining -= weights[i];
+        }
+
+        // Whatever is left falls through to the bottom layer
+        weights[0] += remaining;
+        weights
+    }
+
+    /// Vertex color for a set of weights, the tints of the layers mixed
+    /// together.
+    pub fn color(&self, weights: &[f32; 4]) -> [f32; 3] {
+        let mut c = [0.0; 3];
+        for (layer, w) in self.layers.iter().zip(weights.iter()) {
+            for k in 0..3 {
+                c[k] += w * layer.color[k];
+            }
+        }
+        c
+    }
+
+    pub fn tiling(&self) -> [f32; 4] {
+        self.per_layer(|l| l.tiling)
+    }
+
+    pub fn roughness(&self) -> [f32; 4] {
+        self.per_layer(|l| l.roughness)
+    }
+
+    pub fn metallic(&self) -> [f32; 4] {
+        self.per_layer(|l| l.metallic)
+    }
+
+    // One value for each layer, unused layers get 1
+    fn per_layer<F: Fn(&TerrainLayer) -> f32>(&self, value: F) -> [f32; 4] {
+        let mut t = [1.0; MAX_LAYERS];
+        for (i, layer) in self.layers.iter().enumerate() {
+            t[i] = value(layer);
+        }
+        t
+    }
+
+    /// Upload the textures of all layers into a single 2D texture array. The
+    /// textures are color, so they are read as sRGB.
+    pub fn create_texture_array<R, F>(&self,
+                                      factory: &mut F)
+                                      -> Result<gfx::handle::ShaderResourceView<R, [f32; 4]>,
+                                                String>
+        where R: gfx::Resources,
+              F: gfx::Factory<R>
+    {
+        let blank = procedural_texture(0);
+        let mut data: Vec<&[u8]> = self.layers.iter().map(|l| &l.texture[..]).collect();
+        while data.len() < MAX_LAYERS {
+            data.push(&blank[..]);
+        }
+
+        let size = LAYER_TEXTURE_SIZE as texture::Size;
+        let kind = texture::Kind::D2Array(size, size, MAX_LAYERS as texture::Layer,
+                                          texture::AaMode::Single);
+
+        match factory.create_texture_immutable_u8::<Srgba8>(kind, &data) {
+            Ok((_, view)) => Ok(view),
+            Err(_) => Err("Unable to create the terrain layer textures".to_owned()),
+        }
+    }
+}
@@ -0,0 +1,534 @@
+use gfx;
+use gfx::{Bundle, format, texture};
+use gfx::traits::FactoryExt;
+
+use support::config::Config;
+
+// Log luminance is rendered at this size, then halved down to one texel
+const LUMINANCE_SIZE: texture::Size = 256;
+
+// Log luminance times the weight of the texel, and the weight
+type LuminanceFormat = (format::R32_G32, format::Float);
+type AdaptedFormat = (format::R32, format::Float);
+
+/// Tone mapped color, ready to be anti-aliased and shown.
+pub type LdrFormat = format::Srgba8;
+
+gfx_defines!{
+    vertex ScreenVertex {
+        pos_tex: [i8; 4] = "a_PosTexCoord",
+    }
+
+    constant AdaptLocals {
+        // Key, blend factor towards the new average, min and max adapted
+        // luminance
+        adapt: [f32; 4] = "u_Adapt",
+    }
+
+    constant ToneMapLocals {
+        // Operator, exposure scale, auto exposure, key
+        tone_map: [f32; 4] = "u_ToneMap",
+        // White point, bloom intensity, unused, unused
+        params: [f32; 4] = "u_Params",
+    }
+
+    pipeline luminance {
+        vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+        tex_hdr: gfx::TextureSampler<[f32; 4]> = "t_Hdr",
+        tex_depth: gfx::TextureSampler<[f32; 4]> = "t_Depth",
+        out: gfx::RenderTarget<LuminanceFormat> = "Target0",
+    }
+
+    pipeline reduce {
+        vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+        tex_luminance: gfx::TextureSampler<[f32; 2]> = "t_Luminance",
+        out: gfx::RenderTarget<LuminanceFormat> = "Target0",
+    }
+
+    pipeline adapt {
+        vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+        locals: gfx::ConstantBuffer<AdaptLocals> = "AdaptLocals",
+        tex_luminance: gfx::TextureSampler<[f32; 2]> = "t_Luminance",
+        tex_previous: gfx::TextureSampler<f32> = "t_Previous",
+        out: gfx::RenderTarget<AdaptedFormat> = "Target0",
+    }
+
+    pipeline tonemap {
+        vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+        locals: gfx::ConstantBuffer<ToneMapLocals> = "ToneMapLocals",
+        tex_hdr: gfx::TextureSampler<[f32; 4]> = "t_Hdr",
+        tex_adapted: gfx::TextureSampler<f32> = "t_Adapted",
+        tex_bloom: gfx::TextureSampler<[f32; 4]> = "t_Bloom",
+        out: gfx::RenderTarget<LdrFormat> = "Target0",
+    }
+}
+
+/// Fullscreen triangle for the post-processing passes.
+pub const SCREEN_VERTEX_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    in ivec4 a_PosTexCoord;
+
+    out vec2 v_TexCoord;
+
+    void main() {
+        v_TexCoord = a_PosTexCoord.zw;
+        gl_Position = vec4(a_PosTexCoord.xy, 0.0, 1.0);
+    }
+";
+
+/// Vertices of the fullscreen triangle.
+pub const SCREEN_TRIANGLE: [ScreenVertex; 3] = [ScreenVertex { pos_tex: [-3, -1, -1, 0] },
+                                                ScreenVertex { pos_tex: [1, -1, 1, 0] },
+                                                ScreenVertex { pos_tex: [1, 3, 1, 2] }];
+
+// Each texel is filtered from a few pixels of the scene, the rest are
+// skipped. That is plenty for an average. The sky is left out, so a bright
+// sky does not darken the terrain under it.
+const LUMINANCE_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    uniform sampler2D t_Hdr;
+    uniform sampler2D t_Depth;
+
+    in vec2 v_TexCoord;
+
+    out vec4 Target0;
+
+    void main() {
+        vec3 color = texture(t_Hdr, v_TexCoord).rgb;
+        float luminance = dot(color, vec3(0.2126, 0.7152, 0.0722));
+        // Nothing was drawn where the depth is still cleared
+        float weight = texture(t_Depth, v_TexCoord).r < 1.0 ? 1.0 : 0.0;
+        Target0 = vec4(weight * log(max(luminance, 1.0e-4)), weight, 0.0, 1.0);
+    }
+";
+
+// Averages 2x2 texels of the level above, weights included
+const REDUCE_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    uniform sampler2D t_Luminance;
+
+    out vec4 Target0;
+
+    void main() {
+        ivec2 itc = 2 * ivec2(gl_FragCoord.xy);
+        vec2 sum = texelFetch(t_Luminance, itc, 0).rg +
+                   texelFetch(t_Luminance, itc + ivec2(1, 0), 0).rg +
+                   texelFetch(t_Luminance, itc + ivec2(0, 1), 0).rg +
+                   texelFetch(t_Luminance, itc + ivec2(1, 1), 0).rg;
+        Target0 = vec4(0.25 * sum, 0.0, 1.0);
+    }
+";
+
+// Moves the adapted luminance of the last frame towards the average of this
+// one. The average of the logs is the geometric mean, so a few very bright
+// pixels do not darken the whole image. With only sky in view there is no
+// average, and the last frame is kept.
+const ADAPT_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform AdaptLocals {
+        vec4 u_Adapt;
+    };
+
+    uniform sampler2D t_Luminance;
+    uniform sampler2D t_Previous;
+
+    out vec4 Target0;
+
+    void main() {
+        vec2 sum = texelFetch(t_Luminance, ivec2(0), 0).rg;
+        float previous = texelFetch(t_Previous, ivec2(0), 0).r;
+        bool first = u_Adapt.y >= 1.0;
+
+        // Without anything to average, the key leaves the exposure as it is
+        float average = sum.g > 0.0 ? exp(sum.r / sum.g) : (first ? u_Adapt.x : previous);
+
+        // Nothing to adapt from on the first frame
+        float adapted = first ? average : mix(previous, average, u_Adapt.y);
+        Target0 = vec4(clamp(adapted, u_Adapt.z, u_Adapt.w), 0.0, 0.0, 1.0);
+    }
+";
+
+const TONEMAP_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform ToneMapLocals {
+        vec4 u_ToneMap;
+        vec4 u_Params;
+    };
+
+    uniform sampler2D t_Hdr;
+    uniform sampler2D t_Adapted;
+    uniform sampler2D t_Bloom;
+
+    in vec2 v_TexCoord;
+
+    out vec4 Target0;
+
+    // Reinhard, extended so that the white point maps to one
+    vec3 reinhard(vec3 c, float white) {
+        return c * (1.0 + c / (white * white)) / (1.0 + c);
+    }
+
+    // Krzysztof Narkowicz's fit of the ACES filmic curve, scaled to take
+    // the same exposure as the other operators
+    vec3 aces(vec3 c) {
+        c *= 0.6;
+        return clamp((c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14), 0.0, 1.0);
+    }
+
+    // John Hable's filmic curve from Uncharted 2
+    vec3 hable(vec3 x) {
+        const float A = 0.15;
+        const float B = 0.50;
+        const float C = 0.10;
+        const float D = 0.20;
+        const float E = 0.02;
+        const float F = 0.30;
+        return (x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F) - E / F;
+    }
+
+    vec3 uncharted2(vec3 c, float white) {
+        // The curve is made for twice the exposure
+        return hable(2.0 * c) / hable(vec3(white));
+    }
+
+    void main() {
+        vec3 color = texture(t_Hdr, v_TexCoord).rgb;
+        color += u_Params.y * texture(t_Bloom, v_TexCoord).rgb;
+
+        float exposure = u_ToneMap.y;
+        if (u_ToneMap.z > 0.5) {
+            exposure *= u_ToneMap.w / texelFetch(t_Adapted, ivec2(0), 0).r;
+        }
+        color *= exposure;
+
+        int op = int(u_ToneMap.x);
+        if (op == 0) {
+            color = reinhard(color, u_Params.x);
+        } else if (op == 1) {
+            color = aces(color);
+        } else {
+            color = uncharted2(color, u_Params.x);
+        }
+
+        Target0 = vec4(color, 1.0);
+    }
+";
+
+/// How the lit scene is mapped to the range of the screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapOperator {
+    Reinhard,
+    Aces,
+    Uncharted2,
+}
+
+impl ToneMapOperator {
+    pub fn from_name(name: &str) -> Option<ToneMapOperator> {
+        match name {
+            "reinhard" => Some(ToneMapOperator::Reinhard),
+            "aces" => Some(ToneMapOperator::Aces),
+            "uncharted2" => Some(ToneMapOperator::Uncharted2),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ToneMapOperator::Reinhard => "reinhard",
+            ToneMapOperator::Aces => "aces",
+            ToneMapOperator::Uncharted2 => "uncharted2",
+        }
+    }
+
+    // Index of the operator in the shader
+    fn index(&self) -> f32 {
+        match *self {
+            ToneMapOperator::Reinhard => 0.0,
+            ToneMapOperator::Aces => 1.0,
+            ToneMapOperator::Uncharted2 => 2.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ToneMapSettings {
+    pub operator: ToneMapOperator,
+    // Exposure in stops. With auto exposure it is added on top.
+    pub exposure: f32,
+    pub auto_exposure: bool,
+    // Auto exposure maps the average luminance of the scene to this
+    pub key: f32,
+    // How quickly auto exposure follows the scene, per second
+    pub adaptation_speed: f32,
+    // Auto exposure stops adapting outside of these
+    pub min_luminance: f32,
+    pub max_luminance: f32,
+    // Smallest luminance that is mapped to white by Reinhard and Uncharted 2
+    pub white: f32,
+}
+
+impl ToneMapSettings {
+    pub fn from_config(config: &Config) -> ToneMapSettings {
+        let operator = match config.get_str("tonemap.operator") {
+            Some(name) => {
+                ToneMapOperator::from_name(name).unwrap_or_else(|| {
+                    warn!(target: "DAT205", "Unknown tone mapping operator {}", name);
+                    ToneMapOperator::Aces
+                })
+            }
+            None => ToneMapOperator::Aces,
+        };
+
+        let min_luminance = config.get::<f32>("tonemap.min_luminance", 0.03).max(1.0e-4);
+
+        ToneMapSettings {
+            operator: operator,
+            exposure: config.get("tonemap.exposure", 0.0),
+            auto_exposure: config.get("tonemap.auto_exposure", true),
+            key: config.get::<f32>("tonemap.key", 0.18).max(1.0e-3),
+            adaptation_speed: config.get::<f32>("tonemap.adaptation_speed", 1.5).max(0.0),
+            min_luminance: min_luminance,
+            max_luminance: config.get::<f32>("tonemap.max_luminance", 8.0).max(min_luminance),
+            white: config.get::<f32>("tonemap.white", 11.2).max(1.0e-3),
+        }
+    }
+}
+
+/// Maps the floating point light buffer to the screen, with exposure set by
+/// hand or adapted to the average luminance of the scene.
+///
+/// Auto exposure renders the log luminance of the scene, sky left out, into
+/// a small texture and halves it down to a single texel. The adapted luminance is
+/// kept in two 1x1 textures that swap every frame, so each frame can blend
+/// from the last one.
+pub struct ToneMapper<R: gfx::Resources> {
+    settings: ToneMapSettings,
+    luminance: Bundle<R, luminance::Data<R>>,
+    reduce: Vec<Bundle<R, reduce::Data<R>>>,
+    adapt: Bundle<R, adapt::Data<R>>,
+    adapted: Vec<(gfx::handle::ShaderResourceView<R, f32>,
+                  gfx::handle::RenderTargetView<R, AdaptedFormat>)>,
+    current: usize,
+    first_frame: bool,
+    tonemap: Bundle<R, tonemap::Data<R>>,
+    output: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+}
+
+impl<R: gfx::Resources> ToneMapper<R> {
+    /// `hdr` is the light buffer that is tone mapped until `render` is given
+    /// another one. `bloom` is added to it first, see `render`. `depth` is
+    /// the scene depth, where the sky is left out of the average luminance.
+    pub fn new<F: gfx::Factory<R>>(factory: &mut F,
+                                   settings: ToneMapSettings,
+                                   target_width: u16,
+                                   target_height: u16,
+                                   hdr: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+                                   bloom: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+                                   depth: gfx::handle::ShaderResourceView<R, [f32; 4]>)
+                                   -> Self {
+        info!(target: "DAT205", "Loading tone mapping...");
+
+        let (vbuf, slice) = factory.create_vertex_buffer_with_slice(&SCREEN_TRIANGLE, ());
+
+        let linear = factory.create_sampler(
+            texture::SamplerInfo::new(texture::FilterMethod::Bilinear, texture::WrapMode::Clamp));
+        // The luminance textures are only read with texelFetch, and depth
+        // is not filtered
+        let point = factory.create_sampler(
+            texture::SamplerInfo::new(texture::FilterMethod::Scale, texture::WrapMode::Clamp));
+
+        let (_, first_srv, first_rtv) =
+            factory.create_render_target::<LuminanceFormat>(LUMINANCE_SIZE, LUMINANCE_SIZE)
+                .unwrap();
+
+        let luminance = {
+            let pso = factory.create_pipeline_simple(SCREEN_VERTEX_SHADER,
+                                        LUMINANCE_FRAGMENT_SHADER,
+                                        luminance::new())
+                .unwrap();
+
+            let data = luminance::Data {
+                vbuf: vbuf.clone(),
+                tex_hdr: (hdr.clone(), linear.clone()),
+                tex_depth: (depth, point.clone()),
+                out: first_rtv,
+            };
+
+            Bundle::new(slice.clone(), pso, data)
+        };
+
+        let reduce_pso = factory.create_pipeline_simple(SCREEN_VERTEX_SHADER,
+                                    REDUCE_FRAGMENT_SHADER,
+                                    reduce::new())
+            .unwrap();
+
+        let mut reduce = Vec::new();
+        let mut size = LUMINANCE_SIZE;
+        let mut average = first_srv;
+        while size > 1 {
+            size /= 2;
+            let (_, srv, rtv) = factory.create_render_target::<LuminanceFormat>(size, size)
+                .unwrap();
+
+            let data = reduce::Data {
+                vbuf: vbuf.clone(),
+                tex_luminance: (average, point.clone()),
+                out: rtv,
+            };
+
+            reduce.push(Bundle::new(slice.clone(), reduce_pso.clone(), data));
+            average = srv;
+        }
+
+        let adapted: Vec<_> = (0..2)
+            .map(|_| {
+                let (_, srv, rtv) = factory.create_render_target::<AdaptedFormat>(1, 1).unwrap();
+                (srv, rtv)
+            })
+            .collect();
+
+        let adapt = {
+            let pso = factory.create_pipeline_simple(SCREEN_VERTEX_SHADER,
+                                        ADAPT_FRAGMENT_SHADER,
+                                        adapt::new())
+                .unwrap();
+
+            let data = adapt::Data {
+                vbuf: vbuf.clone(),
+                locals: factory.create_constant_buffer(1),
+                tex_luminance: (average, point.clone()),
+                tex_previous: (adapted[0].0.clone(), point.clone()),
+                out: adapted[1].1.clone(),
+            };
+
+            Bundle::new(slice.clone(), pso, data)
+        };
+
+        let (_, output, output_rtv) =
+            factory.create_render_target::<LdrFormat>(target_width, target_height).unwrap();
+
+        let tonemap = {
+            let pso = factory.create_pipeline_simple(SCREEN_VERTEX_SHADER,
+                                        TONEMAP_FRAGMENT_SHADER,
+                                        tonemap::new())
+                .unwrap();
+
+            let data = tonemap::Data {
+                vbuf: vbuf,
+                locals: factory.create_constant_buffer(1),
+                tex_hdr: (hdr, linear.clone()),
+                tex_adapted: (adapted[1].0.clone(), point),
+                tex_bloom: (bloom, linear),
+                out: output_rtv,
+            };
+
+            Bundle::new(slice, pso, data)
+        };
+
+        info!(target: "DAT205", "Done!");
+
+        ToneMapper {
+            settings: settings,
+            luminance: luminance,
+            reduce: reduce,
+            adapt: adapt,
+            adapted: adapted,
+            current: 1,
+            first_frame: true,
+            tonemap: tonemap,
+            output: output,
+        }
+    }
+
+    pub fn settings(&self) -> &ToneMapSettings {
+        &self.settings
+    }
+
+    pub fn set_operator(&mut self, operator: ToneMapOperator) {
+        self.settings.operator = operator;
+    }
+
+    /// Exposure in stops, zero leaves the scene as it is.
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.settings.exposure = exposure;
+    }
+
+    pub fn toggle_auto_exposure(&mut self) {
+        self.settings.auto_exposure = !self.settings.auto_exposure;
+        // Start from the current scene instead of whatever was adapted to
+        // when it was turned off
+        self.first_frame = true;
+    }
+
+    pub fn set_key(&mut self, key: f32) {
+        self.settings.key = key.max(1.0e-3);
+    }
+
+    pub fn set_adaptation_speed(&mut self, speed: f32) {
+        self.settings.adaptation_speed = speed.max(0.0);
+    }
+
+    /// The tone mapped scene of the last `render`.
+    pub fn output(&self) -> &gfx::handle::ShaderResourceView<R, [f32; 4]> {
+        &self.output
+    }
+
+    /// Tone map `hdr` into the output, with `bloom` times the bloom texture
+    /// added. `dt` is the time since the last frame in seconds, for auto
+    /// exposure.
+    pub fn render<C: gfx::CommandBuffer<R>>(&mut self,
+                                            encoder: &mut gfx::Encoder<R, C>,
+                                            hdr: &gfx::handle::ShaderResourceView<R, [f32; 4]>,
+                                            bloom: f32,
+                                            dt: f32) {
+        self.luminance.data.tex_hdr.0 = hdr.clone();
+        self.tonemap.data.tex_hdr.0 = hdr.clone();
+
+        if self.settings.auto_exposure {
+            self.luminance.encode(encoder);
+            for pass in &self.reduce {
+                pass.encode(encoder);
+            }
+
+            // Read the luminance of the last frame and write the other one
+            let previous = self.current;
+            self.current = 1 - self.current;
+            self.adapt.data.tex_previous.0 = self.adapted[previous].0.clone();
+            self.adapt.data.out = self.adapted[self.current].1.clone();
+            self.tonemap.data.tex_adapted.0 = self.adapted[self.current].0.clone();
+
+            let blend = if self.first_frame {
+                1.0
+            } else {
+                1.0 - (-dt * self.settings.adaptation_speed).exp()
+            };
+            self.first_frame = false;
+
+            let locals = AdaptLocals {
+                adapt: [self.settings.key,
+                        blend,
+                        self.settings.min_luminance,
+                        self.settings.max_luminance],
+            };
+            encoder.update_constant_buffer(&self.adapt.data.locals, &locals);
+            self.adapt.encode(encoder);
+        }
+
+        let locals = ToneMapLocals {
+            tone_map: [self.settings.operator.index(),
+                       2.0f32.powf(self.settings.exposure),
+                       if self.settings.auto_exposure { 1.0 } else { 0.0 },
+                       self.settings.key],
+            params: [self.settings.white, bloom, 0.0, 0.0],
+        };
+        encoder.update_constant_buffer(&self.tonemap.data.locals, &locals);
+        self.tonemap.encode(encoder);
+    }
+}
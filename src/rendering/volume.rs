@@ -0,0 +1,342 @@
+
+use na::Vector3;
+use noise::perlin3;
+use noise;
+
+use rendering::deferred::{TerrainChunk, TerrainVertex};
+use rendering::occlusion;
+use rendering::heightmap::Heightmap;
+use rendering::splatting::TerrainLayers;
+use support::config::Config;
+
+/// Parameters of the density field. Points where the density is positive
+/// are solid.
+#[derive(Debug, Clone)]
+pub struct VolumeSettings {
+    pub seed: u32,
+    // Cells along the longest horizontal side of the terrain
+    pub cells: usize,
+    pub chunk_size: usize,
+    pub noise_scale: f32,
+    pub octaves: usize,
+    // Height of the ground plane, in units of the vertical scale
+    pub ground: f32,
+    // How quickly the density falls off above the ground
+    pub gradient: f32,
+    pub caves: f32,
+    pub cave_scale: f32,
+    pub cave_width: f32,
+}
+
+impl VolumeSettings {
+    pub fn from_config(config: &Config, default_seed: u32) -> VolumeSettings {
+        VolumeSettings {
+            seed: config.get("volume.seed", default_seed),
+            cells: config.get("volume.cells", 128),
+            chunk_size: config.get("volume.chunk_size", 16),
+            noise_scale: config.get("volume.noise_scale", 0.015),
+            octaves: config.get("volume.octaves", 4),
+            ground: config.get("volume.ground", 0.0),
+            gradient: config.get("volume.gradient", 1.5),
+            caves: config.get("volume.caves", 1.0),
+            cave_scale: config.get("volume.cave_scale", 0.03),
+            cave_width: config.get("volume.cave_width", 0.08),
+        }
+    }
+}
+
+/// Terrain made from a 3D density field instead of a heightfield, so it can
+/// have overhangs, arches and caves. The field is polygonized chunk by
+/// chunk with surface nets, the simplest form of dual contouring.
+pub struct Volume {
+    settings: VolumeSettings,
+    terrain: noise::PermutationTable,
+    caves: (noise::PermutationTable, noise::PermutationTable),
+    horizontal_scale: f32,
+    vertical_scale: f32,
+    cell_size: f32,
+    origin: [f32; 3],
+    cells: [usize; 3],
+    chunks: Vec<TerrainChunk>,
+}
+
+fn smoothstep(e0: f32, e1: f32, x: f32) -> f32 {
+    let t = ((x - e0) / (e1 - e0)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// The twelve edges of a cell as pairs of corners. Corner i sits at offset
+// (i & 1, (i >> 1) & 1, (i >> 2) & 1).
+const CELL_EDGES: [(usize, usize); 12] = [(0, 1), (2, 3), (4, 5), (6, 7), (0, 2), (1, 3), (4, 6),
+                                          (5, 7), (0, 4), (1, 5), (2, 6), (3, 7)];
+
+impl Volume {
+    /// Sample and polygonize the field over the same extent as a heightmap
+    /// with the given scales.
+    pub fn generate(settings: VolumeSettings,
+                    horizontal_scale: f32,
+                    vertical_scale: f32,
+                    layers: &TerrainLayers)
+                    -> Volume {
+        let cell_size = 2.0 * horizontal_scale / settings.cells as f32;
+        let vertical_cells = (2.0 * vertical_scale / cell_size).ceil() as usize;
+
+        let mut volume = Volume {
+            terrain: noise::PermutationTable::new(settings.seed),
+            caves: (noise::PermutationTable::new(settings.seed.wrapping_add(1)),
+                    noise::PermutationTable::new(settings.seed.wrapping_add(2))),
+            horizontal_scale: horizontal_scale,
+            vertical_scale: vertical_scale,
+            cell_size: cell_size,
+            origin: [-horizontal_scale, -vertical_scale, -horizontal_scale],
+            cells: [settings.cells, vertical_cells, settings.cells],
+            chunks: Vec::new(),
+            settings: settings,
+        };
+
+        let n = volume.settings.chunk_size.max(1);
+        let counts = [(volume.cells[0] + n - 1) / n,
+                      (volume.cells[1] + n - 1) / n,
+                      (volume.cells[2] + n - 1) / n];
+
+        info!(target: "DAT205",
+              "Polygonizing {}x{}x{} volume chunks...",
+              counts[0],
+              counts[1],
+              counts[2]);
+
+        for cz in 0..counts[2] {
+            for cy in 0..counts[1] {
+                for cx in 0..counts[0] {
+                    let chunk = volume.polygonize([cx * n, cy * n, cz * n], layers);
+                    if !chunk.indices.is_empty() {
+                        volume.chunks.push(chunk);
+                    }
+                }
+            }
+        }
+
+        let triangles: usize = volume.chunks.iter().map(|c| c.indices.len() / 3).sum();
+        info!(target: "DAT205",
+              "Volume terrain has {} chunks and {} triangles",
+              volume.chunks.len(),
+              triangles);
+
+        volume
+    }
+
+    pub fn into_chunks(self) -> Vec<TerrainChunk> {
+        self.chunks
+    }
+
+    pub fn density(&self, p: [f32; 3]) -> f32 {
+        let s = &self.settings;
+        let mut d = s.gradient * (s.ground - p[1] / self.vertical_scale);
+
+        let mut amplitude = 1.0;
+        let mut f = s.noise_scale;
+        for _ in 0..s.octaves {
+            d += amplitude * perlin3(&self.terrain, &[f * p[0], f * p[1], f * p[2]]);
+            amplitude *= 0.5;
+            f *= 2.0;
+        }
+
+        if s.caves > 0.0 {
+            // Tunnels run where two noise fields are both close to zero
+            let q = [s.cave_scale * p[0], s.cave_scale * p[1], s.cave_scale * p[2]];
+            let c = perlin3(&self.caves.0, &q).abs().max(perlin3(&self.caves.1, &q).abs());
+            d -= s.caves * (1.0 - smoothstep(0.0, s.cave_width, c));
+        }
+
+        d
+    }
+
+    // Outward normal, the negated gradient of the density.
+    fn normal(&self, p: [f32; 3]) -> [f32; 3] {
+        let e = 0.5 * self.cell_size;
+        let dx = self.density([p[0] + e, p[1], p[2]]) - self.density([p[0] - e, p[1], p[2]]);
+        let dy = self.density([p[0], p[1] + e, p[2]]) - self.density([p[0], p[1] - e, p[2]]);
+        let dz = self.density([p[0], p[1], p[2] + e]) - self.density([p[0], p[1], p[2] - e]);
+
+        match Vector3::new(-dx, -dy, -dz).try_normalize(1.0e-8) {
+            Some(n) => n.into(),
+            None => [0.0, 1.0, 0.0],
+        }
+    }
+
+    fn point(&self, i: isize, j: isize, k: isize) -> [f32; 3] {
+        [self.origin[0] + i as f32 * self.cell_size,
+         self.origin[1] + j as f32 * self.cell_size,
+         self.origin[2] + k as f32 * self.cell_size]
+    }
+
+    // A chunk owns the grid edges starting inside it. Quads around those
+    // edges need the cells on the negative side too, so the field is
+    // sampled with one extra layer of points there.
+    fn polygonize(&self, start: [usize; 3], layers: &TerrainLayers) -> TerrainChunk {
+        let n = self.settings.chunk_size as isize;
+        let (sx, sy, sz) = (start[0] as isize, start[1] as isize, start[2] as isize);
+
+        // Points -1..n+1 and cells -1..n in chunk coordinates
+        let np = n + 2;
+        let nc = n + 1;
+        let p_idx = |i: isize, j: isize, k: isize| {
+            ((i + 1) + np * ((j + 1) + np * (k + 1))) as usize
+        };
+        let c_idx = |i: isize, j: isize, k: isize| {
+            ((i + 1) + nc * ((j + 1) + nc * (k + 1))) as usize
+        };
+
+        let mut samples = Vec::with_capacity((np * np * np) as usize);
+        for k in -1..n + 1 {
+            for j in -1..n + 1 {
+                for i in -1..n + 1 {
+                    samples.push(self.density(self.point(sx + i, sy + j, sz + k)));
+                }
+            }
+        }
+
+        let mut vertices = Vec::new();
+        let mut cell_vertex = vec![None; (nc * nc * nc) as usize];
+
+        for k in -1..n {
+            for j in -1..n {
+                for i in -1..n {
+                    let mut corners = [0.0; 8];
+                    let mut mask = 0;
+                    for c in 0..8 {
+                        let (di, dj, dk) = (c & 1, (c >> 1) & 1, (c >> 2) & 1);
+                        corners[c] = samples[p_idx(i + di as isize,
+                                                   j + dj as isize,
+                                                   k + dk as isize)];
+                        if corners[c] > 0.0 {
+                            mask |= 1 << c;
+                        }
+                    }
+
+                    if mask == 0 || mask == 0xff {
+                        continue;
+                    }
+
+                    // Place the vertex at the average of the edge crossings
+                    let mut sum = [0.0; 3];
+                    let mut count = 0.0;
+                    for &(a, b) in CELL_EDGES.iter() {
+                        if (corners[a] > 0.0) == (corners[b] > 0.0) {
+                            continue;
+                        }
+                        let t = corners[a] / (corners[a] - corners[b]);
+                        for axis in 0..3 {
+                            let ca = ((a >> axis) & 1) as f32;
+                            let cb = ((b >> axis) & 1) as f32;
+                            sum[axis] += ca + t * (cb - ca);
+                        }
+                        count += 1.0;
+                    }
+
+                    let pos = self.point(sx + i, sy + j, sz + k);
+                    let pos = [pos[0] + self.cell_size * sum[0] / count,
+                               pos[1] + self.cell_size * sum[1] / count,
+                               pos[2] + self.cell_size * sum[2] / count];
+
+                    cell_vertex[c_idx(i, j, k)] = Some(vertices.len() as u32);
+                    vertices.push(self.vertex(pos, layers));
+                }
+            }
+        }
+
+        let mut indices = Vec::new();
+        let unit = [[1, 0, 0], [0, 1, 0], [0, 0, 1]];
+
+        for k in 0..n {
+            for j in 0..n {
+                for i in 0..n {
+                    let p = [i, j, k];
+                    let inside = samples[p_idx(i, j, k)] > 0.0;
+
+                    for a in 0..3 {
+                        let (b, c) = ((a + 1) % 3, (a + 2) % 3);
+                        let q = [i + unit[a][0], j + unit[a][1], k + unit[a][2]];
+                        if inside == (samples[p_idx(q[0], q[1], q[2])] > 0.0) {
+                            continue;
+                        }
+
+                        // The four cells around the edge, counter clockwise
+                        // when looking down the axis
+                        let cell = |db: isize, dc: isize| {
+                            let mut m = p;
+                            m[b] -= db;
+                            m[c] -= dc;
+                            cell_vertex[c_idx(m[0], m[1], m[2])]
+                        };
+
+                        if let (Some(v00), Some(v10), Some(v11), Some(v01)) =
+                               (cell(1, 1), cell(0, 1), cell(0, 0), cell(1, 0)) {
+                            if inside {
+                                indices.extend_from_slice(&[v00, v10, v11, v00, v11, v01]);
+                            } else {
+                                indices.extend_from_slice(&[v00, v11, v10, v00, v01, v11]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        TerrainChunk {
+            vertices: vertices,
+            indices: indices,
+        }
+    }
+
+    fn vertex(&self, pos: [f32; 3], layers: &TerrainLayers) -> TerrainVertex {
+        let normal = self.normal(pos);
+        // Overhangs and cave ceilings get slopes above 90 degrees
+        let slope = normal[1].max(-1.0).min(1.0).acos().to_degrees();
+        let height = 0.5 * (pos[1] / self.vertical_scale + 1.0);
+        let weights = layers.weights(pos[0], pos[2], height, slope);
+
+        TerrainVertex {
+            pos: pos,
+            normal: normal,
+            color: layers.color(&weights),
+            weights: weights,
+            occlusion: occlusion::UNOCCLUDED,
+        }
+    }
+
+    /// Heightfield of the topmost surface, used for everything that needs a
+    /// height at a point, like lights, scattering and water.
+    pub fn surface_heightmap(&self) -> Heightmap {
+        let width = self.cells[0] + 1;
+        let depth = self.cells[2] + 1;
+        let top = self.cells[1] as isize;
+
+        let mut heights = Vec::with_capacity(width * depth);
+        for iz in 0..depth {
+            for ix in 0..width {
+                let (i, k) = (ix as isize, iz as isize);
+
+                let mut h = self.origin[1];
+                let mut j = top;
+                let mut da = self.density(self.point(i, j, k));
+                while j > 0 {
+                    let db = self.density(self.point(i, j - 1, k));
+                    if da <= 0.0 && db > 0.0 {
+                        h = self.point(i, j, k)[1] - self.cell_size * da / (da - db);
+                        break;
+                    }
+                    da = db;
+                    j -= 1;
+                }
+                heights.push(h);
+            }
+        }
+
+        Heightmap::from_heights(width,
+                                depth,
+                                self.horizontal_scale,
+                                self.vertical_scale,
+                                heights)
+    }
+}
@@ -0,0 +1,432 @@
+
+use std::f32::consts::PI;
+
+use gfx;
+use gfx::{Bundle, texture};
+use gfx::format::Rgba8;
+use gfx::traits::FactoryExt;
+use na::Matrix4;
+use noise::perlin4;
+use noise;
+
+use rendering::camera::Camera;
+use rendering::colors;
+use rendering::deferred::{AlbedoFormat, Depth, GFormat, TerrainVertex};
+use rendering::skybox::Skybox;
+use support::config::Config;
+
+gfx_defines!{
+    vertex WaterVertex {
+        pos_tex: [i8; 4] = "a_PosTexCoord",
+    }
+
+    constant ReflectLocals {
+        view_proj: [[f32; 4]; 4] = "u_ViewProj",
+        water_level: [f32; 4] = "u_WaterLevel",
+    }
+
+    constant WaterLocals {
+        inv_view_proj: [[f32; 4]; 4] = "u_InvViewProj",
+        reflect_view_proj: [[f32; 4]; 4] = "u_ReflectViewProj",
+        cam_pos: [f32; 4] = "u_CamPos",
+        water: [f32; 4] = "u_Water",
+        waves: [f32; 4] = "u_Waves",
+        color: [f32; 4] = "u_WaterColor",
+    }
+
+    pipeline reflect {
+        vbuf: gfx::VertexBuffer<TerrainVertex> = (),
+        locals: gfx::ConstantBuffer<ReflectLocals> = "ReflectLocals",
+        out_color: gfx::RenderTarget<AlbedoFormat> = "Target0",
+        out_depth: gfx::DepthTarget<Depth> =
+            gfx::preset::depth::LESS_EQUAL_WRITE,
+    }
+
+    pipeline water {
+        vbuf: gfx::VertexBuffer<WaterVertex> = (),
+        locals: gfx::ConstantBuffer<WaterLocals> = "WaterLocals",
+        tex_depth: gfx::TextureSampler<[f32; 4]> = "t_Depth",
+        tex_scene: gfx::TextureSampler<[f32; 4]> = "t_Scene",
+        tex_reflection: gfx::TextureSampler<[f32; 4]> = "t_Reflection",
+        tex_normals: gfx::TextureSampler<[f32; 4]> = "t_WaterNormals",
+        out: gfx::RenderTarget<GFormat> = "Target0",
+    }
+}
+
+// The reflection is lit by a fixed light from above, it only has to be
+// plausible once it is distorted by the waves.
+const REFLECT_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform ReflectLocals {
+        mat4 u_ViewProj;
+        vec4 u_WaterLevel;
+    };
+
+    in vec3 v_FragPos;
+    in vec3 v_Normal;
+    in vec3 v_Color;
+
+    out vec4 Target0;
+
+    void main() {
+        if (v_FragPos.y < u_WaterLevel.x) {
+            discard;
+        }
+
+        vec3 n = normalize(v_Normal);
+        float d = max(0.0, dot(n, normalize(vec3(0.3, 1.0, 0.2))));
+        Target0 = vec4(v_Color * (0.3 + 0.7 * d), 1.0);
+    }
+";
+
+const REFLECT_VERTEX_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform ReflectLocals {
+        mat4 u_ViewProj;
+        vec4 u_WaterLevel;
+    };
+
+    in vec3 a_Pos;
+    in vec3 a_Normal;
+    in vec3 a_Color;
+
+    out vec3 v_FragPos;
+    out vec3 v_Normal;
+    out vec3 v_Color;
+
+    void main() {
+        v_FragPos = a_Pos;
+        v_Normal = a_Normal;
+        v_Color = a_Color;
+        gl_Position = u_ViewProj * vec4(a_Pos, 1.0);
+    }
+";
+
+const WATER_VERTEX_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    in ivec4 a_PosTexCoord;
+
+    out vec2 v_TexCoord;
+
+    void main() {
+        v_TexCoord = a_PosTexCoord.zw;
+        gl_Position = vec4(a_PosTexCoord.xy, 0.0, 1.0);
+    }
+";
+
+// Intersects the view ray of every pixel with the water plane. If the plane
+// is hit in front of the geometry in the G-buffer the pixel is shaded as
+// water, otherwise the lit scene is passed through.
+const WATER_FRAGMENT_SHADER: &'static [u8] = b"
+    #version 150 core
+
+    layout(std140)
+    uniform WaterLocals {
+        mat4 u_InvViewProj;
+        mat4 u_ReflectViewProj;
+        vec4 u_CamPos;
+        // level, time, half extent, depth fade
+        vec4 u_Water;
+        // wave scale, wave speed, distortion, unused
+        vec4 u_Waves;
+        vec4 u_WaterColor;
+    };
+
+    uniform sampler2D t_Depth;
+    uniform sampler2D t_Scene;
+    uniform sampler2D t_Reflection;
+    uniform sampler2D t_WaterNormals;
+
+    in vec2 v_TexCoord;
+
+    out vec4 Target0;
+
+    vec3 wave_normal(vec2 p) {
+        float t = u_Water.y * u_Waves.y;
+        vec3 a = texture(t_WaterNormals, p * u_Waves.x + vec2(t, 0.5 * t)).xyz;
+        vec3 b = texture(t_WaterNormals, p * u_Waves.x * 1.7 - vec2(0.4 * t, t)).xyz;
+        vec3 n = (a + b) - 1.0;
+        // Stored with z up, the water plane has y up
+        return normalize(vec3(n.x, n.z, n.y));
+    }
+
+    void main() {
+        vec4 scene = texture(t_Scene, v_TexCoord);
+        float scene_depth = texelFetch(t_Depth, ivec2(gl_FragCoord.xy), 0).r;
+
+        vec4 far = u_InvViewProj * vec4(2.0 * v_TexCoord - 1.0, 1.0, 1.0);
+        vec3 dir = normalize(far.xyz / far.w - u_CamPos.xyz);
+        vec4 gpos = u_InvViewProj * vec4(2.0 * vec3(v_TexCoord, scene_depth) - 1.0, 1.0);
+
+        // Ray/plane intersection, only looking down at the surface
+        float t_water = (u_Water.x - u_CamPos.y) / dir.y;
+        // The depth is still cleared where no geometry was drawn
+        float t_scene = scene_depth >= 1.0 ? 1.0e9 : length(gpos.xyz / gpos.w - u_CamPos.xyz);
+
+        vec3 hit = u_CamPos.xyz + t_water * dir;
+        if (u_CamPos.y < u_Water.x || dir.y >= 0.0 || t_water > t_scene ||
+            max(abs(hit.x), abs(hit.z)) > u_Water.z) {
+            Target0 = scene;
+            return;
+        }
+
+        vec3 n = wave_normal(hit.xz);
+        vec3 to_cam = -dir;
+
+        // Fade in with depth so the shoreline blends into the terrain
+        float depth = min(t_scene - t_water, 1.0e3);
+        float shore = 1.0 - exp(-depth * u_Water.w);
+
+        vec2 offset = n.xz * u_Waves.z * shore;
+
+        vec4 r = u_ReflectViewProj * vec4(hit, 1.0);
+        vec2 reflect_uv = clamp(0.5 * r.xy / r.w + 0.5 + offset, 0.001, 0.999);
+        vec3 reflection = texture(t_Reflection, reflect_uv).rgb;
+
+        vec3 refraction = texture(t_Scene, clamp(v_TexCoord + offset, 0.001, 0.999)).rgb;
+        refraction = mix(refraction, u_WaterColor.rgb * 0.2, shore * u_WaterColor.a);
+
+        // Schlick approximation, F0 of water is about 0.02
+        float fresnel = 0.02 + 0.98 * pow(1.0 - max(0.0, dot(n, to_cam)), 5.0);
+
+        vec3 color = mix(refraction, reflection, fresnel);
+        Target0 = vec4(mix(scene.rgb, color, shore), 1.0);
+    }
+";
+
+const NORMAL_TEXTURE_SIZE: usize = 256;
+
+// Tiling normal map made from the gradient of 4D noise walked around a torus.
+fn wave_normals() -> Vec<u8> {
+    let seed = noise::PermutationTable::new(17);
+    let size = NORMAL_TEXTURE_SIZE;
+
+    let heights: Vec<f32> = (0..size * size)
+        .map(|i| {
+            let a = 2.0 * PI * (i % size) as f32 / size as f32;
+            let b = 2.0 * PI * (i / size) as f32 / size as f32;
+            let mut h = 0.0;
+            let mut amplitude = 1.0;
+            let mut radius = 1.0;
+            for _ in 0..3 {
+                h += amplitude *
+                     perlin4(&seed,
+                             &[radius * a.cos(), radius * a.sin(), radius * b.cos(), radius * b.sin()]);
+                amplitude *= 0.5;
+                radius *= 2.0;
+            }
+            h
+        })
+        .collect();
+
+    let at = |x: usize, y: usize| heights[(y % size) * size + (x % size)];
+    let mut data = Vec::with_capacity(size * size * 4);
+
+    for y in 0..size {
+        for x in 0..size {
+            let dx = at(x + 1, y) - at(x + size - 1, y);
+            let dy = at(x, y + 1) - at(x, y + size - 1);
+            let (nx, ny, nz) = (-8.0 * dx, -8.0 * dy, 1.0);
+            let len = (nx * nx + ny * ny + nz * nz).sqrt();
+            for c in &[nx / len, ny / len, nz / len] {
+                data.push(((c * 0.5 + 0.5) * 255.0) as u8);
+            }
+            data.push(255);
+        }
+    }
+
+    data
+}
+
+pub struct Water<R: gfx::Resources> {
+    enabled: bool,
+    level: f32,
+    extent: f32,
+    depth_fade: f32,
+    waves: [f32; 4],
+    color: [f32; 4],
+    reflect: Option<Bundle<R, reflect::Data<R>>>,
+    reflect_color: gfx::handle::RenderTargetView<R, AlbedoFormat>,
+    reflect_depth: gfx::handle::DepthStencilView<R, Depth>,
+    water: Bundle<R, water::Data<R>>,
+    output: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+}
+
+impl<R: gfx::Resources> Water<R> {
+    pub fn new<F: gfx::Factory<R>>(factory: &mut F,
+                                   config: &Config,
+                                   target_width: u16,
+                                   target_height: u16,
+                                   extent: f32,
+                                   depth: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+                                   scene: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+                                   terrain: Option<(gfx::handle::Buffer<R, TerrainVertex>,
+                                                    gfx::Slice<R>)>)
+                                   -> Self {
+        info!(target: "DAT205", "Loading water...");
+
+        let sampler = factory.create_sampler(texture::SamplerInfo::new(texture::FilterMethod::Bilinear,
+                                                      texture::WrapMode::Clamp));
+
+        // The reflection does not need to be sharp, render it at half size
+        let (reflect_w, reflect_h) = (target_width / 2, target_height / 2);
+        let (_, reflect_srv, reflect_rtv) =
+            factory.create_render_target::<AlbedoFormat>(reflect_w, reflect_h).unwrap();
+        let reflect_depth = factory.create_depth_stencil_view_only::<Depth>(reflect_w, reflect_h)
+            .unwrap();
+
+        let (_, out_srv, out_rtv) =
+            factory.create_render_target::<GFormat>(target_width, target_height).unwrap();
+
+        // Without a terrain mesh only the sky is reflected
+        let reflect = terrain.map(|(vbuf, slice)| {
+            let pso = factory.create_pipeline_simple(REFLECT_VERTEX_SHADER,
+                                        REFLECT_FRAGMENT_SHADER,
+                                        reflect::new())
+                .unwrap();
+
+            let data = reflect::Data {
+                vbuf: vbuf,
+                locals: factory.create_constant_buffer(1),
+                out_color: reflect_rtv.clone(),
+                out_depth: reflect_depth.clone(),
+            };
+
+            Bundle::new(slice, pso, data)
+        });
+
+        let water = {
+            let vertex_data = [WaterVertex { pos_tex: [-3, -1, -1, 0] },
+                               WaterVertex { pos_tex: [1, -1, 1, 0] },
+                               WaterVertex { pos_tex: [1, 3, 1, 2] }];
+
+            let (vbuf, slice) = factory.create_vertex_buffer_with_slice(&vertex_data, ());
+
+            let pso = factory.create_pipeline_simple(WATER_VERTEX_SHADER,
+                                        WATER_FRAGMENT_SHADER,
+                                        water::new())
+                .unwrap();
+
+            let size = NORMAL_TEXTURE_SIZE as texture::Size;
+            let (_, normals) = factory.create_texture_immutable_u8::<Rgba8>(
+                    texture::Kind::D2(size, size, texture::AaMode::Single),
+                    &[&wave_normals()])
+                .unwrap();
+            let normal_sampler =
+                factory.create_sampler(texture::SamplerInfo::new(texture::FilterMethod::Bilinear,
+                                                                 texture::WrapMode::Tile));
+
+            let data = water::Data {
+                vbuf: vbuf,
+                locals: factory.create_constant_buffer(1),
+                tex_depth: (depth, sampler.clone()),
+                tex_scene: (scene, sampler.clone()),
+                tex_reflection: (reflect_srv, sampler.clone()),
+                tex_normals: (normals, normal_sampler),
+                out: out_rtv,
+            };
+
+            Bundle::new(slice, pso, data)
+        };
+
+        let color = match config.get_floats("water.color") {
+            Some(ref c) if c.len() == 4 => [c[0], c[1], c[2], c[3]],
+            _ => colors::DARK_BLUE.into_with_a(),
+        };
+
+        info!(target: "DAT205", "Done!");
+
+        Water {
+            enabled: config.get("water.enabled", true),
+            level: config.get("water.level", -5.0),
+            extent: extent,
+            depth_fade: config.get("water.depth_fade", 0.3),
+            waves: [config.get("water.wave_scale", 0.02),
+                    config.get("water.wave_speed", 0.01),
+                    config.get("water.distortion", 0.02),
+                    0.0],
+            color: color,
+            reflect: reflect,
+            reflect_color: reflect_rtv,
+            reflect_depth: reflect_depth,
+            water: water,
+            output: out_srv,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+    }
+
+    pub fn set_level(&mut self, level: f32) {
+        self.level = level;
+    }
+
+    /// The lit scene with water composited on top.
+    pub fn output(&self) -> &gfx::handle::ShaderResourceView<R, [f32; 4]> {
+        &self.output
+    }
+
+    /// Draw the terrain mirrored in the water plane into the reflection
+    /// target. Has to run before `render`.
+    pub fn render_reflection<C: gfx::CommandBuffer<R>>(&mut self,
+                                                       encoder: &mut gfx::Encoder<R, C>,
+                                                       cam: &Camera,
+                                                       skybox: &mut Skybox<R>) {
+        if !self.enabled {
+            return;
+        }
+
+        let view = cam.get_mirrored_view_matrix(self.level);
+        let view_proj: [[f32; 4]; 4] = (cam.get_proj_matrix() * view).into();
+
+        encoder.clear(&self.reflect_color, [0.0, 0.0, 0.0, 1.0]);
+        encoder.clear_depth(&self.reflect_depth, 1.0);
+
+        if let Some(inv) = cam.get_proj_matrix().try_inverse() {
+            skybox.render_to(encoder,
+                             &self.reflect_color,
+                             inv.into(),
+                             view.into());
+        }
+
+        let locals = ReflectLocals {
+            view_proj: view_proj,
+            water_level: [self.level, 0.0, 0.0, 0.0],
+        };
+        if let Some(ref reflect) = self.reflect {
+            encoder.update_constant_buffer(&reflect.data.locals, &locals);
+            reflect.encode(encoder);
+        }
+    }
+
+    /// Composite the water over the lit scene.
+    pub fn render<C: gfx::CommandBuffer<R>>(&mut self,
+                                            encoder: &mut gfx::Encoder<R, C>,
+                                            cam: &Camera,
+                                            time: f32) {
+        let eye = cam.get_eye();
+        let reflect_view_proj: Matrix4<f32> = cam.get_proj_matrix() *
+                                              cam.get_mirrored_view_matrix(self.level);
+
+        let locals = WaterLocals {
+            inv_view_proj: cam.get_inv_view_proj().into(),
+            reflect_view_proj: reflect_view_proj.into(),
+            cam_pos: [eye.x, eye.y, eye.z, 1.0],
+            water: [self.level, time, self.extent, self.depth_fade],
+            waves: self.waves,
+            color: self.color,
+        };
+        encoder.update_constant_buffer(&self.water.data.locals, &locals);
+        self.water.encode(encoder);
+    }
+}
@@ -0,0 +1,76 @@
+use std::time::Instant;
+
+// Frames drawn before measuring each setup, and frames measured
+const WARMUP_FRAMES: u32 = 30;
+pub const MEASURED_FRAMES: u32 = 120;
+
+/// Times a list of setups, one after the other, for a number of frames
+/// each. Frames are timed on the CPU from one to the next, so this only
+/// means something with vsync off.
+pub struct FrameBenchmark<S: Copy> {
+    previous: S,
+    runs: Vec<S>,
+    run: usize,
+    frame: u32,
+    started: Option<Instant>,
+    extra_ms: f32,
+    // Setup, ms per frame and extra ms per frame
+    results: Vec<(S, f32, f32)>,
+}
+
+impl<S: Copy> FrameBenchmark<S> {
+    /// Measure each of `runs`. `previous` is the setup to go back to
+    /// afterwards.
+    pub fn new(runs: Vec<S>, previous: S) -> FrameBenchmark<S> {
+        FrameBenchmark {
+            previous: previous,
+            runs: runs,
+            run: 0,
+            frame: 0,
+            started: None,
+            extra_ms: 0.0,
+            results: Vec::new(),
+        }
+    }
+
+    /// Count a frame. Returns the setup to draw the next frame with, or None
+    /// once every setup has been measured. `extra_ms` is time spent on some
+    /// part of the frame, averaged along with the frame time.
+    pub fn next_frame(&mut self, extra_ms: f32) -> Option<S> {
+        if self.frame == WARMUP_FRAMES {
+            self.started = Some(Instant::now());
+        } else if self.frame > WARMUP_FRAMES {
+            self.extra_ms += extra_ms;
+        }
+
+        if self.frame == WARMUP_FRAMES + MEASURED_FRAMES {
+            if let Some(started) = self.started {
+                let t = started.elapsed();
+                let ms = t.as_secs() as f32 * 1000.0 + t.subsec_nanos() as f32 / 1.0e6;
+                self.results.push((self.runs[self.run],
+                                   ms / MEASURED_FRAMES as f32,
+                                   self.extra_ms / MEASURED_FRAMES as f32));
+            }
+
+            self.run += 1;
+            self.frame = 0;
+            self.started = None;
+            self.extra_ms = 0.0;
+        }
+
+        if self.run >= self.runs.len() {
+            return None;
+        }
+
+        self.frame += 1;
+        Some(self.runs[self.run])
+    }
+
+    pub fn previous(&self) -> S {
+        self.previous
+    }
+
+    pub fn results(&self) -> &[(S, f32, f32)] {
+        &self.results
+    }
+}
@@ -0,0 +1,92 @@
+
+use std::collections::HashMap;
+use std::collections::hash_map::Keys;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Settings read from a plain `key = value` file. Everything after a `#` on
+/// a line is treated as a comment.
+#[derive(Debug, Clone)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config { values: HashMap::new() }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, String> {
+        let disp = path.as_ref().display();
+
+        let mut file = match File::open(path.as_ref()) {
+            Err(reason) => return Err(format!("Could not open file {}: {}", disp, reason)),
+            Ok(file) => file,
+        };
+
+        let mut content = String::new();
+        if let Err(reason) = file.read_to_string(&mut content) {
+            return Err(format!("Could not read file {}: {}", disp, reason));
+        }
+
+        Ok(Config::parse(&content))
+    }
+
+    pub fn parse(content: &str) -> Config {
+        let mut values = HashMap::new();
+
+        for line in content.lines() {
+            let line = match line.find('#') {
+                Some(i) => &line[..i],
+                None => line,
+            };
+
+            let mut kv = line.splitn(2, '=');
+            if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+                let k = k.trim();
+                if !k.is_empty() {
+                    values.insert(k.to_owned(), v.trim().to_owned());
+                }
+            }
+        }
+
+        Config { values: values }
+    }
+
+    pub fn keys(&self) -> Keys<String, String> {
+        self.values.keys()
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|s| s.as_str())
+    }
+
+    /// Parse a whitespace or comma separated list of numbers.
+    pub fn get_floats(&self, key: &str) -> Option<Vec<f32>> {
+        self.values.get(key).and_then(|v| {
+            v.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse::<f32>().ok())
+                .collect()
+        })
+    }
+
+    /// Parse the value of `key`, falling back to `default` if the key is
+    /// missing or malformed.
+    pub fn get<T: FromStr>(&self, key: &str, default: T) -> T {
+        match self.values.get(key) {
+            Some(v) => {
+                match v.parse() {
+                    Ok(parsed) => parsed,
+                    Err(_) => {
+                        warn!(target: "DAT205", "Invalid value '{}' for setting {}", v, key);
+                        default
+                    }
+                }
+            }
+            None => default,
+        }
+    }
+}
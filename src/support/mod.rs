@@ -1,3 +1,5 @@
 
 pub mod logging;
 pub mod frame_clock;
+pub mod config;
+pub mod benchmark;
@@ -7,6 +7,9 @@ use alewife;
 
 use core::event;
 use rendering::colors;
+use rendering::sculpt::BrushTool;
+use rendering::clusters::LightingMode;
+use rendering::tonemap::ToneMapOperator;
 
 widget_ids!{
     pub struct ConsoleIds {
@@ -41,10 +44,21 @@ lazy_static! {
         m.insert("wireframe", (event::EventID::RenderEvent, event::Event::ToggleWireframe));
         m.insert("close", (event::EventID::UIEvent ,event::Event::ToggleConsole));
         m.insert("toggleFXAA", (event::EventID::RenderEvent, event::Event::ToggleFXAA));
+        m.insert("toggleWater", (event::EventID::RenderEvent, event::Event::ToggleWater));
+        m.insert("toggleBloom", (event::EventID::RenderEvent, event::Event::ToggleBloom));
+        m.insert("toggleAutoExposure", (event::EventID::RenderEvent, event::Event::ToggleAutoExposure));
+        m.insert("undo", (event::EventID::RenderEvent, event::Event::UndoSculpt));
+        m.insert("redo", (event::EventID::RenderEvent, event::Event::RedoSculpt));
+        m.insert("road_end", (event::EventID::RenderEvent, event::Event::EndRoad));
+        m.insert("road_clear", (event::EventID::RenderEvent, event::Event::ClearRoads));
+        m.insert("light_benchmark", (event::EventID::RenderEvent, event::Event::BenchmarkLights));
+        m.insert("terrain_benchmark", (event::EventID::RenderEvent, event::Event::BenchmarkTerrain));
         m.insert("debug_ShowLightBuffer", (event::EventID::RenderEvent, event::Event::DebugShowLightBuffer));
         m.insert("debug_ShowNormalBuffer", (event::EventID::RenderEvent, event::Event::DebugShowNormalBuffer));
         m.insert("debug_ShowDiffuseBuffer", (event::EventID::RenderEvent, event::Event::DebugShowDiffuseBuffer));
         m.insert("debug_ShowDepthBuffer", (event::EventID::RenderEvent, event::Event::DebugShowDepthBuffer));
+        m.insert("debug_ShowMaterialBuffer", (event::EventID::RenderEvent, event::Event::DebugShowMaterialBuffer));
+        m.insert("debug_ShowCascades", (event::EventID::RenderEvent, event::Event::DebugShowCascades));
         m.insert("debug_Off", (event::EventID::RenderEvent, event::Event::DebugOff));
        
        // m.insert("moveCam", (event::EventID::EntityEvent, event::Event::MoveCamera(0f32, 0f32))):
@@ -52,6 +66,209 @@ lazy_static! {
     };
 }
 
+// Commands that take arguments. Returns None if the command is unknown and
+// the usage string if the arguments could not be parsed.
+fn parse_command(name: &str,
+                 args: &[&str])
+                 -> Option<Result<(event::EventID, event::Event), String>> {
+    match name {
+        "terrain_export" => {
+            Some(match args.first() {
+                Some(path) => {
+                    Ok((event::EventID::RenderEvent, event::Event::ExportHeightmap(path.to_string())))
+                }
+                None => Err("Usage: terrain_export <file.png>".to_owned()),
+            })
+        }
+        "terrain_export_normals" => {
+            Some(match args.first() {
+                Some(path) => {
+                    Ok((event::EventID::RenderEvent, event::Event::ExportNormalMap(path.to_string())))
+                }
+                None => Err("Usage: terrain_export_normals <file.png>".to_owned()),
+            })
+        }
+        "terrain_regen" => {
+            Some(match args.first().map(|s| s.parse::<u32>()) {
+                Some(Ok(seed)) => {
+                    Ok((event::EventID::RenderEvent, event::Event::RegenerateTerrain(Some(seed))))
+                }
+                Some(Err(_)) => Err("Usage: terrain_regen [seed]".to_owned()),
+                None => Ok((event::EventID::RenderEvent, event::Event::RegenerateTerrain(None))),
+            })
+        }
+        "export_obj" => {
+            Some(match args.first() {
+                Some(path) => {
+                    Ok((event::EventID::RenderEvent, event::Event::ExportObj(path.to_string())))
+                }
+                None => Err("Usage: export_obj <file.obj>".to_owned()),
+            })
+        }
+        "export_gltf" => {
+            Some(match args.first() {
+                Some(path) => {
+                    Ok((event::EventID::RenderEvent, event::Event::ExportGltf(path.to_string())))
+                }
+                None => Err("Usage: export_gltf <file.gltf>".to_owned()),
+            })
+        }
+        "tree_export" => {
+            let seed = args.get(2).map(|s| s.parse::<u32>());
+            Some(match (args.get(0), args.get(1), seed) {
+                (Some(preset), Some(path), None) => {
+                    let evt = event::Event::ExportTree(preset.to_string(), path.to_string(), None);
+                    Ok((event::EventID::RenderEvent, evt))
+                }
+                (Some(preset), Some(path), Some(Ok(seed))) => {
+                    let evt =
+                        event::Event::ExportTree(preset.to_string(), path.to_string(), Some(seed));
+                    Ok((event::EventID::RenderEvent, evt))
+                }
+                _ => Err("Usage: tree_export <preset.cfg> <file.obj> [seed]".to_owned()),
+            })
+        }
+        "sun_dir" | "sun_color" => {
+            let values: Vec<f32> = args.iter().filter_map(|v| v.parse::<f32>().ok()).collect();
+            Some(if args.len() == 3 && values.len() == 3 {
+                let v = [values[0], values[1], values[2]];
+                let evt = if name == "sun_dir" {
+                    event::Event::SetSunDirection(v)
+                } else {
+                    event::Event::SetSunColor(v)
+                };
+                Ok((event::EventID::RenderEvent, evt))
+            } else if name == "sun_dir" {
+                Err("Usage: sun_dir <x> <y> <z>".to_owned())
+            } else {
+                Err("Usage: sun_color <r> <g> <b>".to_owned())
+            })
+        }
+        "sun_intensity" => {
+            Some(match args.first().and_then(|v| v.parse::<f32>().ok()) {
+                Some(v) => Ok((event::EventID::RenderEvent, event::Event::SetSunIntensity(v))),
+                None => Err("Usage: sun_intensity <value>".to_owned()),
+            })
+        }
+        "light_mode" => {
+            Some(match args.first().and_then(|name| LightingMode::from_name(name)) {
+                Some(mode) => Ok((event::EventID::RenderEvent, event::Event::SetLightingMode(mode))),
+                None => Err("Usage: light_mode <clustered|volumes>".to_owned()),
+            })
+        }
+        "light_count" => {
+            Some(match args.first().and_then(|v| v.parse::<usize>().ok()) {
+                Some(n) => Ok((event::EventID::RenderEvent, event::Event::SetLightCount(n))),
+                None => Err("Usage: light_count <count>".to_owned()),
+            })
+        }
+        "tonemap" => {
+            Some(match args.first().and_then(|name| ToneMapOperator::from_name(name)) {
+                Some(op) => Ok((event::EventID::RenderEvent, event::Event::SetToneMapOperator(op))),
+                None => Err("Usage: tonemap <reinhard|aces|uncharted2>".to_owned()),
+            })
+        }
+        "bloom_intensity" | "bloom_threshold" => {
+            Some(match args.first().and_then(|v| v.parse::<f32>().ok()) {
+                Some(v) => {
+                    let evt = if name == "bloom_intensity" {
+                        event::Event::SetBloomIntensity(v)
+                    } else {
+                        event::Event::SetBloomThreshold(v)
+                    };
+                    Ok((event::EventID::RenderEvent, evt))
+                }
+                None => Err(format!("Usage: {} <value>", name)),
+            })
+        }
+        "exposure" | "exposure_key" | "adaptation_speed" => {
+            Some(match args.first().and_then(|v| v.parse::<f32>().ok()) {
+                Some(v) => {
+                    let evt = match name {
+                        "exposure" => event::Event::SetExposure(v),
+                        "exposure_key" => event::Event::SetExposureKey(v),
+                        _ => event::Event::SetAdaptationSpeed(v),
+                    };
+                    Ok((event::EventID::RenderEvent, evt))
+                }
+                None => Err(format!("Usage: {} <value>", name)),
+            })
+        }
+        "road" => {
+            Some(match args.first().map(|s| s.parse::<f32>()) {
+                Some(Ok(width)) => {
+                    Ok((event::EventID::RenderEvent, event::Event::BeginRoad(Some(width))))
+                }
+                Some(Err(_)) => Err("Usage: road [width]".to_owned()),
+                None => Ok((event::EventID::RenderEvent, event::Event::BeginRoad(None))),
+            })
+        }
+        "terrain_erode" => {
+            let overrides: Result<Vec<_>, _> = args.iter()
+                .map(|arg| {
+                    let mut kv = arg.splitn(2, '=');
+                    match (kv.next(), kv.next().and_then(|v| v.parse::<f32>().ok())) {
+                        (Some(k), Some(v)) => Ok((k.to_owned(), v)),
+                        _ => Err(()),
+                    }
+                })
+                .collect();
+
+            Some(match overrides {
+                Ok(o) => Ok((event::EventID::RenderEvent, event::Event::ErodeTerrain(o))),
+                Err(_) => Err("Usage: terrain_erode [param=value ...]".to_owned()),
+            })
+        }
+        "water_level" => {
+            Some(match args.first().and_then(|v| v.parse::<f32>().ok()) {
+                Some(h) => Ok((event::EventID::RenderEvent, event::Event::SetWaterLevel(h))),
+                None => Err("Usage: water_level <height>".to_owned()),
+            })
+        }
+        "brush" => {
+            Some(match args.first() {
+                Some(&"off") => Ok((event::EventID::RenderEvent, event::Event::SetBrushTool(None))),
+                Some(name) => {
+                    match BrushTool::from_name(name) {
+                        Some(tool) => {
+                            Ok((event::EventID::RenderEvent, event::Event::SetBrushTool(Some(tool))))
+                        }
+                        None => Err("Usage: brush <raise|lower|smooth|flatten|noise|off>".to_owned()),
+                    }
+                }
+                None => Err("Usage: brush <raise|lower|smooth|flatten|noise|off>".to_owned()),
+            })
+        }
+        "brush_radius" | "brush_strength" | "brush_falloff" => {
+            Some(match args.first().and_then(|v| v.parse::<f32>().ok()) {
+                Some(v) => {
+                    let evt = match name {
+                        "brush_radius" => event::Event::SetBrushRadius(v),
+                        "brush_strength" => event::Event::SetBrushStrength(v),
+                        _ => event::Event::SetBrushFalloff(v),
+                    };
+                    Ok((event::EventID::RenderEvent, evt))
+                }
+                None => Err(format!("Usage: {} <value>", name)),
+            })
+        }
+        "terrain_save_edits" | "terrain_load_edits" => {
+            Some(match args.first() {
+                Some(path) => {
+                    let evt = if name == "terrain_save_edits" {
+                        event::Event::SaveTerrainEdits(path.to_string())
+                    } else {
+                        event::Event::LoadTerrainEdits(path.to_string())
+                    };
+                    Ok((event::EventID::RenderEvent, evt))
+                }
+                None => Err(format!("Usage: {} <file>", name)),
+            })
+        }
+        _ => None,
+    }
+}
+
 #[derive(Debug)]
 pub struct ConsoleEntry {
     text: String,
@@ -105,10 +322,23 @@ impl Console {
     }
 
     fn process_command(&mut self, cmd: &str) {
-        match BULTIN_COMMANDS.get(cmd) {
-            Some(&(id, ref evt)) => self.publisher.publish(id, evt.clone()),
+        let mut tokens = cmd.split_whitespace();
+        let name = match tokens.next() {
+            Some(name) => name,
+            None => return,
+        };
+        let args: Vec<&str> = tokens.collect();
+
+        if let Some(&(id, ref evt)) = BULTIN_COMMANDS.get(name) {
+            self.publisher.publish(id, evt.clone());
+            return;
+        }
+
+        match parse_command(name, &args) {
+            Some(Ok((id, evt))) => self.publisher.publish(id, evt),
+            Some(Err(usage)) => self.add_entry(usage, ConsoleLogLevel::WARNING),
             None => {
-                self.add_entry("Command not found: ".to_owned() + cmd,
+                self.add_entry("Command not found: ".to_owned() + name,
                                ConsoleLogLevel::WARNING)
             }
         }
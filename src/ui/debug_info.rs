@@ -27,7 +27,8 @@ impl DebugInfo {
                   ids: &DebugIds,
                   fps: u64,
                   ms: u64,
-                  cam_pos: Point3<f32>) {
+                  cam_pos: Point3<f32>,
+                  terrain_seed: Option<u32>) {
 
         use conrod;
         use conrod::widget;
@@ -40,16 +41,22 @@ impl DebugInfo {
             return;
         }
 
-        Rectangle::fill_with([100.0, 40.0], conrod::Color::Rgba(0.0, 0.0, 0.0, 1.0))
+        let seed = match terrain_seed {
+            Some(seed) => seed.to_string(),
+            None => "none".to_owned(),
+        };
+
+        Rectangle::fill_with([130.0, 56.0], conrod::Color::Rgba(0.0, 0.0, 0.0, 1.0))
             .top_left_of(ui.window)
             .set(ids.bg, ui);
 
-        widget::Text::new(format!("{} fps ({} ms)\nx: {:.2} y: {:.2} z: {:.2}",
+        widget::Text::new(format!("{} fps ({} ms)\nx: {:.2} y: {:.2} z: {:.2}\nseed: {}",
                                   fps,
                                   ms,
                                   cam_pos.x,
                                   cam_pos.y,
-                                  cam_pos.z)
+                                  cam_pos.z,
+                                  seed)
                 .as_str())
             .top_left_with_margins_on(ids.bg, 2.0, 4.0)
             .color(conrod::color::WHITE)